@@ -0,0 +1,479 @@
+use std::path::Path;
+
+use lewton::inside_ogg::OggStreamReader;
+
+use crate::chart::SongStreams;
+
+/// Decoded audio, as interleaved 16-bit samples
+pub struct Pcm {
+    pub channels: usize,
+    pub sample_rate: u32,
+    pub samples: Vec<i16>,
+}
+
+impl Pcm {
+    pub fn frames(self: &Self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    pub fn frames_to_ms(self: &Self, frames: f64) -> f32 {
+        (frames * 1000.0 / self.sample_rate as f64) as f32
+    }
+
+    pub fn ms_to_frames(self: &Self, time_ms: f32) -> f64 {
+        time_ms as f64 * self.sample_rate as f64 / 1000.0
+    }
+}
+
+/// Frames in every chunk of a PcmChunks but the last
+const CHUNK_FRAMES: usize = 4096;
+
+/// Decoded audio kept in chunks of CHUNK_FRAMES frames, so it can grow while it's being played
+/// without ever being moved
+pub struct PcmChunks {
+    pub channels: usize,
+    pub sample_rate: u32,
+    chunks: Vec<Vec<i16>>,
+    frames: usize,
+    /// Every chunk has been added
+    pub complete: bool,
+}
+
+impl PcmChunks {
+    pub fn new(channels: usize, sample_rate: u32) -> PcmChunks {
+        PcmChunks {
+            channels: channels,
+            sample_rate: sample_rate,
+            chunks: Vec::new(),
+            frames: 0,
+            complete: false,
+        }
+    }
+
+    /// Frames decoded so far
+    pub fn frames(self: &Self) -> usize {
+        self.frames
+    }
+
+    pub fn sample(self: &Self, frame: usize, channel: usize) -> i16 {
+        self.chunks[frame / CHUNK_FRAMES][(frame % CHUNK_FRAMES) * self.channels + channel]
+    }
+
+    /// Adds the next chunk. Only the last chunk can be shorter than CHUNK_FRAMES.
+    pub fn push(self: &mut Self, samples: Vec<i16>) {
+        self.frames += samples.len() / self.channels;
+        self.chunks.push(samples);
+    }
+
+    pub fn frames_to_ms(self: &Self, frames: f64) -> f32 {
+        (frames * 1000.0 / self.sample_rate as f64) as f32
+    }
+
+    pub fn ms_to_frames(self: &Self, time_ms: f32) -> f64 {
+        time_ms as f64 * self.sample_rate as f64 / 1000.0
+    }
+}
+
+impl std::convert::From<Pcm> for PcmChunks {
+    fn from(pcm: Pcm) -> PcmChunks {
+        let mut chunks = PcmChunks::new(pcm.channels, pcm.sample_rate);
+        for chunk in pcm.samples.chunks(CHUNK_FRAMES * pcm.channels) {
+            chunks.push(chunk.to_vec());
+        }
+        chunks.complete = true;
+        chunks
+    }
+}
+
+/// Adds samples into mixed, clipping
+fn mix_into(mixed: &mut [i16], samples: &[i16]) {
+    for (mixed_sample, sample) in mixed.iter_mut().zip(samples) {
+        *mixed_sample = (*mixed_sample as i32 + *sample as i32).max(std::i16::MIN as i32).min(std::i16::MAX as i32) as i16;
+    }
+}
+
+struct OggStream {
+    reader: OggStreamReader<std::fs::File>,
+    path: String,
+    /// Decoded samples that haven't been mixed yet
+    pending: Vec<i16>,
+    ended: bool,
+}
+
+impl OggStream {
+    fn open(path: &Path) -> Result<OggStream, String> {
+        let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let reader = OggStreamReader::new(file).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(OggStream { reader: reader, path: path.display().to_string(), pending: Vec::new(), ended: false })
+    }
+
+    /// Decodes until at least len samples are pending, or the stream ends
+    fn decode(self: &mut Self, len: usize) -> Result<(), String> {
+        while !self.ended && self.pending.len() < len {
+            match self.reader.read_dec_packet_itl().map_err(|e| format!("{}: {}", self.path, e))? {
+                Some(packet) => self.pending.extend(packet),
+                None => self.ended = true,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a song's streams on another thread, mixing them into chunks that are handed over as they're ready,
+/// so the song can start playing before it's all decoded
+pub struct SongDecoder {
+    pub channels: usize,
+    pub sample_rate: u32,
+    chunks: std::sync::mpsc::Receiver<Result<Vec<i16>, String>>,
+}
+
+impl SongDecoder {
+    /// Audio to be filled by receive
+    pub fn pcm(self: &Self) -> PcmChunks {
+        PcmChunks::new(self.channels, self.sample_rate)
+    }
+
+    /// Adds the chunks decoded since the last call, without waiting for more.
+    /// Once decoding has finished, or failed, pcm is complete.
+    pub fn receive(self: &Self, pcm: &mut PcmChunks) -> Result<(), String> {
+        loop {
+            match self.chunks.try_recv() {
+                Ok(Ok(chunk)) => pcm.push(chunk),
+                Ok(Err(e)) => return Err(e),
+                Err(std::sync::mpsc::TryRecvError::Empty) => return Ok(()),
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    pcm.complete = true;
+                    return Ok(());
+                },
+            }
+        }
+    }
+}
+
+/// Starts decoding the song's streams from the song's directory. A song that doesn't name any streams
+/// is a single song.ogg. The streams are opened here, so missing files and mismatched formats are errors
+/// before anything plays.
+pub fn decode_song(dir: &Path, streams: &SongStreams) -> Result<SongDecoder, String> {
+    let mut files: Vec<&str> = [&streams.music, &streams.guitar, &streams.bass, &streams.rhythm, &streams.drum].iter()
+        .filter_map(|stream| stream.as_ref().map(|file| file.as_ref()))
+        .collect();
+    if files.is_empty() {
+        files.push("song.ogg");
+    }
+
+    let mut streams = files.iter()
+        .map(|file| OggStream::open(&dir.join(file)))
+        .collect::<Result<Vec<OggStream>, String>>()?;
+    let channels = streams[0].reader.ident_hdr.audio_channels as usize;
+    let sample_rate = streams[0].reader.ident_hdr.audio_sample_rate;
+    if streams.iter().any(|stream| stream.reader.ident_hdr.audio_channels as usize != channels || stream.reader.ident_hdr.audio_sample_rate != sample_rate) {
+        return Err(String::from("can't mix streams of different formats"));
+    }
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let chunk_len = CHUNK_FRAMES * channels;
+        loop {
+            if let Err(e) = streams.iter_mut().try_for_each(|stream| stream.decode(chunk_len)) {
+                let _ = sender.send(Err(e));
+                return;
+            }
+
+            // every stream that hasn't ended has a whole chunk pending, so only the last chunk is short
+            let len = streams.iter().map(|stream| std::cmp::min(stream.pending.len(), chunk_len)).max().unwrap_or(0);
+            if len == 0 {
+                return;
+            }
+            let mut chunk = vec![0; len];
+            for stream in streams.iter_mut() {
+                let mixed = std::cmp::min(stream.pending.len(), len);
+                mix_into(&mut chunk, &stream.pending[..mixed]);
+                stream.pending.drain(..mixed);
+            }
+
+            // the song stopped playing
+            if sender.send(Ok(chunk)).is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(SongDecoder {
+        channels: channels,
+        sample_rate: sample_rate,
+        chunks: receiver,
+    })
+}
+
+/// Length of the frames the audio is cut into
+const FRAME_MS: f32 = 40.0;
+/// How far a frame may be taken from its nominal position to line up with the audio before it
+const TOLERANCE_MS: f32 = 10.0;
+// lining up only needs every other sample, which halves the search twice over
+const CORRELATION_STRIDE: usize = 2;
+
+/// Plays audio slower or faster than recorded without changing its pitch, using WSOLA
+/// (waveform similarity overlap-add).
+///
+/// The source is cut into overlapping Hann-windowed frames. Output frames are a fixed hop apart,
+/// but frames are taken from the source `rate` hops apart, so the source goes by at `rate` times
+/// real time. Each frame is moved (within a tolerance) to where it best lines up with how the previous
+/// frame would have continued, so the overlapping waveforms add up without phase cancellation.
+/// At a rate of 1 every frame lines up where it is, and the source is played back unchanged.
+///
+/// The source can still be decoding: until it has decoded far enough, silence is played in the meantime.
+/// Playing never allocates, as it runs on the audio thread.
+pub struct TimeStretch {
+    pub source: PcmChunks,
+    rate: f32,
+    frame_len: usize, // in frames, even, so the windows add up to 1 at half overlap
+    hop: usize,
+    tolerance: usize,
+    window: Vec<f32>,
+    /// Nominal source frame of the next frame to be taken
+    analysis_pos: f64,
+    /// Source frame that continues the last frame taken, which the next one is lined up with
+    continuation: Option<usize>,
+    /// Second half of the last windowed frame, waiting to be overlapped with the next one
+    overlap: Vec<f32>,
+    /// Output ready to be played: the first block_len samples, of which block_read have been
+    block: Vec<f32>,
+    block_len: usize,
+    block_read: usize,
+    /// Source frame the block's first frame stands for
+    block_pos: f64,
+    finished: bool,
+}
+
+impl TimeStretch {
+    pub fn new(source: PcmChunks) -> TimeStretch {
+        let frame_len = (source.ms_to_frames(FRAME_MS) as usize / 2 * 2).max(2);
+        let hop = frame_len / 2;
+        let window = (0..frame_len)
+            .map(|i| 0.5 - 0.5 * f32::cos(2.0 * std::f32::consts::PI * i as f32 / frame_len as f32))
+            .collect();
+
+        TimeStretch {
+            rate: 1.0,
+            frame_len: frame_len,
+            hop: hop,
+            tolerance: source.ms_to_frames(TOLERANCE_MS) as usize,
+            window: window,
+            analysis_pos: 0.0,
+            continuation: None,
+            overlap: vec![0.0; hop * source.channels],
+            block: vec![0.0; hop * source.channels],
+            block_len: 0,
+            block_read: 0,
+            block_pos: 0.0,
+            finished: source.complete && source.frames() == 0,
+            source: source,
+        }
+    }
+
+    /// How fast the source goes by, e.g. 0.5 for half speed
+    pub fn set_rate(self: &mut Self, rate: f32) {
+        self.rate = rate;
+    }
+
+    /// Position in the source of the next sample to be played
+    pub fn position_ms(self: &Self) -> f32 {
+        let played = (self.block_read / self.source.channels) as f64;
+        self.source.frames_to_ms(self.block_pos + played * self.rate as f64)
+    }
+
+    pub fn seek_ms(self: &mut Self, time_ms: f32) {
+        let frame = f64::max(self.source.ms_to_frames(time_ms), 0.0);
+        self.analysis_pos = frame;
+        self.continuation = None;
+        self.overlap.iter_mut().for_each(|sample| *sample = 0.0);
+        self.block_len = 0;
+        self.block_read = 0;
+        self.block_pos = frame;
+        self.finished = self.source.complete && frame >= self.source.frames() as f64;
+    }
+
+    /// The whole source has been played
+    pub fn is_finished(self: &Self) -> bool {
+        self.finished && self.block_read >= self.block_len
+    }
+
+    /// Fills interleaved output samples, with silence once the source is finished
+    /// or while it hasn't been decoded far enough
+    pub fn fill(self: &mut Self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            if self.block_read >= self.block_len && !self.finished {
+                self.next_block();
+            }
+            *sample = if self.block_read < self.block_len {
+                self.block_read += 1;
+                self.block[self.block_read - 1]
+            } else {
+                0.0
+            };
+        }
+    }
+
+    /// Makes the next block, unless the source hasn't been decoded far enough for it
+    fn next_block(self: &mut Self) {
+        let channels = self.source.channels;
+        let nominal = self.analysis_pos.round() as usize;
+
+        if nominal + self.frame_len > self.source.frames() {
+            if !self.source.complete {
+                return;
+            }
+
+            // the last frame only has its second half left to play
+            self.block.copy_from_slice(&self.overlap);
+            self.overlap.iter_mut().for_each(|sample| *sample = 0.0);
+            self.block_len = self.block.len();
+            self.block_read = 0;
+            self.block_pos = self.analysis_pos;
+            self.finished = true;
+            return;
+        }
+
+        let offset = match self.continuation {
+            Some(continuation) if continuation != nominal => self.best_offset(nominal, continuation),
+            _ => nominal,
+        };
+
+        // the first half overlaps the last frame's second half, which is only replaced once it's been read
+        for i in 0..self.frame_len {
+            for channel in 0..channels {
+                let sample = self.source.sample(offset + i, channel) as f32 / 32768.0 * self.window[i];
+                if i < self.hop {
+                    self.block[i * channels + channel] = self.overlap[i * channels + channel] + sample;
+                } else {
+                    self.overlap[(i - self.hop) * channels + channel] = sample;
+                }
+            }
+        }
+
+        // the block crossfades from the last frame's continuation into this frame,
+        // which on average sits this far into the source
+        self.block_pos = nominal as f64 + self.hop as f64 * (1.0 - self.rate as f64);
+        self.block_len = self.block.len();
+        self.block_read = 0;
+        self.continuation = Some(offset + self.hop);
+        self.analysis_pos += self.hop as f64 * self.rate as f64;
+    }
+
+    /// The source frame near nominal whose start best matches the audio at continuation
+    fn best_offset(self: &Self, nominal: usize, continuation: usize) -> usize {
+        let frames = self.source.frames();
+        if continuation + self.hop > frames {
+            return nominal;
+        }
+
+        let mono = |frame: usize| -> i64 {
+            (0..self.source.channels).map(|channel| self.source.sample(frame, channel) as i64).sum()
+        };
+
+        let first = nominal.saturating_sub(self.tolerance);
+        let last = std::cmp::min(nominal + self.tolerance, frames - self.frame_len);
+        (first..=last).step_by(CORRELATION_STRIDE)
+            .max_by_key(|candidate| {
+                (0..self.hop).step_by(CORRELATION_STRIDE)
+                    .map(|i| mono(candidate + i) * mono(continuation + i))
+                    .sum::<i64>()
+            })
+            .unwrap_or(nominal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    // a second of stereo sine, a different pitch on each channel
+    fn sine() -> Pcm {
+        let samples = (0..SAMPLE_RATE as usize).flat_map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            vec![(f32::sin(2.0 * std::f32::consts::PI * 440.0 * t) * 10000.0) as i16, (f32::sin(2.0 * std::f32::consts::PI * 660.0 * t) * 10000.0) as i16]
+        }).collect();
+        Pcm { channels: 2, sample_rate: SAMPLE_RATE, samples: samples }
+    }
+
+    // output until the stretch is finished, in device-sized buffers
+    fn play(stretch: &mut TimeStretch) -> Vec<f32> {
+        let mut out = Vec::new();
+        let mut buffer = vec![0f32; 512];
+        while !stretch.is_finished() {
+            stretch.fill(&mut buffer);
+            out.extend_from_slice(&buffer);
+            assert!(out.len() < 20 * SAMPLE_RATE as usize);
+        }
+        out
+    }
+
+    #[test]
+    fn rate_of_one_plays_the_source_unchanged() {
+        let source = sine();
+        let mut stretch = TimeStretch::new(PcmChunks::from(sine()));
+        let out = play(&mut stretch);
+
+        // the first and last half frames fade in and out
+        let hop_samples = stretch.hop * 2;
+        assert!(out.len() >= source.samples.len() - 2 * hop_samples);
+        for i in hop_samples..source.samples.len() - 2 * hop_samples {
+            assert!((out[i] - source.samples[i] as f32 / 32768.0).abs() < 1e-4, "sample {}", i);
+        }
+    }
+
+    #[test]
+    fn position_follows_the_rate() {
+        for &rate in &[0.5f32, 1.0, 2.0] {
+            let mut stretch = TimeStretch::new(PcmChunks::from(sine()));
+            stretch.set_rate(rate);
+            stretch.seek_ms(100.0);
+            assert_eq!(stretch.position_ms(), 100.0);
+
+            // 400 ms of output
+            stretch.fill(&mut vec![0f32; SAMPLE_RATE as usize * 2 * 2 / 5]);
+            let expected_ms = 100.0 + 400.0 * rate;
+            assert!((stretch.position_ms() - expected_ms).abs() < FRAME_MS, "at rate {}: {} ms", rate, stretch.position_ms());
+        }
+    }
+
+    #[test]
+    fn slower_rate_plays_longer() {
+        let mut stretch = TimeStretch::new(PcmChunks::from(sine()));
+        stretch.set_rate(0.5);
+        let seconds = play(&mut stretch).len() as f32 / 2.0 / SAMPLE_RATE as f32;
+        assert!((seconds - 2.0).abs() < 0.1, "{} s", seconds);
+    }
+
+    #[test]
+    fn waits_for_the_source_to_be_decoded() {
+        let source = sine();
+        let mut pcm = PcmChunks::new(source.channels, source.sample_rate);
+        pcm.push(source.samples[..CHUNK_FRAMES * 2].to_vec());
+        let mut stretch = TimeStretch::new(pcm);
+
+        // plays what's there, then silence without moving on
+        let mut out = vec![0f32; SAMPLE_RATE as usize * 2];
+        stretch.fill(&mut out);
+        let position_ms = stretch.position_ms();
+        assert!(position_ms < stretch.source.frames_to_ms(CHUNK_FRAMES as f64));
+        assert!(!stretch.is_finished());
+        assert!(out[out.len() - 100..].iter().all(|sample| *sample == 0.0));
+
+        stretch.source.push(source.samples[CHUNK_FRAMES * 2..].to_vec());
+        stretch.source.complete = true;
+        stretch.fill(&mut out[..2]);
+        assert!(stretch.position_ms() > position_ms);
+        play(&mut stretch);
+        assert!(stretch.is_finished());
+    }
+
+    #[test]
+    fn mixing_clips() {
+        let mut mixed = vec![30000, 1, -30000];
+        mix_into(&mut mixed, &[30000, 1, -30000]);
+        assert_eq!(mixed, vec![std::i16::MAX, 2, std::i16::MIN]);
+    }
+}
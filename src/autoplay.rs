@@ -0,0 +1,192 @@
+use crate::guitarplaythrough::*;
+use crate::playthrough::{SP_ACTIVATION_METER, SP_METER_BEATS, SP_PHRASE_METER};
+use crate::replay::Replay;
+
+// the bar has to keep moving to count as whammying
+const WHAMMY_STEP_MS: f32 = WHAMMY_HOLD_MS / 2.0;
+
+// how often the autoplay replay updates the song clock
+const FRAME_MS: f32 = 1000.0 / 120.0;
+
+/// The inputs that hit every note of the chart exactly on time, sorted by time.
+///
+/// Every note gets its frets held exactly (no anchoring) at the note's time.
+/// It is strummed unless the fret change itself hits it: a tap, or a HOPO after the first note,
+/// whose frets differ from the note before it.
+/// Star power sustains are whammied for their whole length, and star power is activated
+/// at the first note where the meter is certain to be at least half full.
+/// The meter is estimated from completed phrases alone, so whammy only makes it last longer.
+pub fn inputs(chart: &GuitarChart) -> Vec<(f32, GuitarInputAction)> {
+    let mut inputs: Vec<(f32, GuitarInputAction)> = Vec::new();
+    let mut held = [false; MAX_LANES];
+    let mut sp_meter = 0f32;
+    let mut sp_active_until_beat: Option<f32> = None;
+
+    for (index, note) in chart.notes.iter().enumerate() {
+        let time_ms = chart.ticks_to_ms(note.ticks);
+        let beat = chart.ms_to_beats(time_ms);
+
+        if sp_active_until_beat.map_or(false, |until_beat| beat >= until_beat) {
+            sp_active_until_beat = None;
+        }
+        if sp_active_until_beat.is_none() && sp_meter >= SP_ACTIVATION_METER {
+            inputs.push((time_ms, GuitarInputAction::StarPower));
+            sp_active_until_beat = Some(beat + sp_meter * SP_METER_BEATS);
+            sp_meter = 0.0;
+        }
+
+        let frets_changed = held != note.chord;
+        for fret in chart.layout.frets().iter().filter(|fret| held[fret.lane()] && !note.chord[fret.lane()]) {
+            inputs.push((time_ms, GuitarInputAction::FretUp(*fret)));
+        }
+        for fret in chart.layout.frets().iter().filter(|fret| !held[fret.lane()] && note.chord[fret.lane()]) {
+            inputs.push((time_ms, GuitarInputAction::FretDown(*fret)));
+        }
+        held = note.chord;
+
+        let hit_by_frets = frets_changed && match note.strum_type {
+            GuitarNoteStrumType::Strum => false,
+            GuitarNoteStrumType::Hopo => index > 0,
+            GuitarNoteStrumType::Tap => true,
+        };
+        if !hit_by_frets {
+            inputs.push((time_ms, GuitarInputAction::Strum));
+        }
+
+        if note.duration > 0 && note.sp_phrase.is_some() {
+            let end_ms = chart.ticks_to_ms(note.ticks + note.duration);
+            let mut whammy_ms = time_ms + WHAMMY_STEP_MS;
+            let mut pressed = true;
+            while whammy_ms < end_ms {
+                inputs.push((whammy_ms, GuitarInputAction::Whammy(if pressed { 1.0 } else { 0.0 })));
+                pressed = !pressed;
+                whammy_ms += WHAMMY_STEP_MS;
+            }
+            inputs.push((end_ms, GuitarInputAction::Whammy(0.0)));
+        }
+
+        let phrase_ended = note.sp_phrase.is_some()
+            && chart.notes.get(index + 1).map_or(true, |next| next.sp_phrase != note.sp_phrase);
+        if phrase_ended {
+            match sp_active_until_beat {
+                Some(until_beat) => sp_active_until_beat = Some(f32::min(until_beat + SP_PHRASE_METER * SP_METER_BEATS, beat + SP_METER_BEATS)),
+                None => sp_meter = f32::min(sp_meter + SP_PHRASE_METER, 1.0),
+            }
+        }
+    }
+
+    // whammy inputs of a sustain can run past the next notes; the sort is stable
+    inputs.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    inputs
+}
+
+/// A replay of the autoplay bot playing the playthrough's part, updating the song clock every frame.
+/// Watching it gives a demo of the song; playing it headless gives the best run the engine allows.
+pub fn replay(playthrough: &GuitarPlaythrough, chart_hash: u64) -> Replay {
+    let (chart, settings) = (&playthrough.chart, playthrough.settings);
+    let mut replay = Replay::new(chart_hash, playthrough.instrument, playthrough.difficulty, settings);
+    // the bot plays to the notes as they are heard, like a player would
+    let mut pending = inputs(chart).into_iter()
+        .map(|(input_ms, action)| (input_ms + settings.audio_latency_ms, action))
+        .peekable();
+
+    let end_ms = chart.end_ms() + settings.audio_latency_ms + playthrough.hit_window().late_ms + FRAME_MS;
+    let mut time_ms = -settings.lead_in_ms;
+
+    while time_ms <= end_ms || pending.peek().is_some() {
+        while let Some((input_ms, action)) = pending.peek().map(|input| *input).filter(|(input_ms, _)| *input_ms <= time_ms) {
+            replay.record_input(action, input_ms);
+            pending.next();
+        }
+        replay.record_time(time_ms);
+        time_ms += FRAME_MS;
+    }
+
+    replay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart;
+
+    // 120 BPM at 192 ticks per beat
+    fn chart_file(notes: &str) -> String {
+        format!("[Song]\n{{\n  Resolution = 192\n}}\n[SyncTrack]\n{{\n  0 = B 120000\n}}\n[ExpertSingle]\n{{\n{}}}\n", notes)
+    }
+
+    /// Plays the autoplay replay of the chart into a fresh playthrough, checking it never overstrummed
+    fn autoplay(chart_file: &str) -> GuitarPlaythrough {
+        let difficulty = chart::Difficulty::Expert;
+        let new_playthrough = || GuitarPlaythrough::new(chart::read(chart_file).unwrap(), chart::Instrument::Guitar, difficulty, GuitarSettings::for_difficulty(&difficulty)).unwrap();
+        let replay = replay(&new_playthrough(), 0);
+        let mut playthrough = new_playthrough();
+        let effects = replay.play_all(&mut playthrough);
+        assert!(!effects.contains(&GuitarGameEffect::Overstrum));
+        playthrough
+    }
+
+    fn assert_every_note_hit(playthrough: &GuitarPlaythrough) {
+        assert_eq!(playthrough.state.notes_missed, 0);
+        assert_eq!(playthrough.state.notes_hit, playthrough.chart.notes.len() as u64);
+        assert_eq!(playthrough.state.best_streak, playthrough.chart.notes.len() as u64);
+    }
+
+    /// The score of hitting every note without star power: 50 points a gem, times the streak multiplier
+    fn max_score_without_sp(chart: &GuitarChart) -> u64 {
+        chart.notes.iter().enumerate().map(|(streak, note)| {
+            let gems = std::cmp::max(note.chord.iter().filter(|c| **c).count() as u64, 1);
+            50 * gems * std::cmp::min(1 + streak as u64 / 10, 4)
+        }).sum()
+    }
+
+    #[test]
+    fn strums_and_chords_past_the_max_multiplier() {
+        let notes: String = (0..40u64).map(|i| match i % 3 {
+            0 => format!("  {} = N {} 0\n", 192 + i * 96, i % 5),
+            1 => format!("  {} = N 0 0\n  {} = N 2 0\n", 192 + i * 96, 192 + i * 96),
+            _ => format!("  {} = N 1 0\n  {} = N 3 0\n  {} = N 4 0\n", 192 + i * 96, 192 + i * 96, 192 + i * 96),
+        }).collect();
+        let playthrough = autoplay(&chart_file(&notes));
+
+        assert_every_note_hit(&playthrough);
+        assert_eq!(playthrough.state.score, max_score_without_sp(&playthrough.chart));
+        assert_eq!(playthrough.state.timing.total_offset_ms, 0.0);
+    }
+
+    #[test]
+    fn hopos_taps_and_open_notes() {
+        // a HOPO on the same fret as the note before has to be strummed, and so does the open note
+        let playthrough = autoplay(&chart_file(concat!(
+            "  192 = N 0 0\n",
+            "  288 = N 1 0\n  288 = N 5 0\n",
+            "  384 = N 2 0\n  384 = N 5 0\n",
+            "  480 = N 2 0\n  480 = N 5 0\n",
+            "  576 = N 3 0\n  576 = N 6 0\n",
+            "  672 = N 0 0\n  672 = N 6 0\n",
+            "  768 = N 7 0\n",
+            "  864 = N 0 0\n  864 = N 1 0\n  864 = N 5 0\n",
+        )));
+
+        assert_every_note_hit(&playthrough);
+        assert_eq!(playthrough.state.score, max_score_without_sp(&playthrough.chart));
+    }
+
+    #[test]
+    fn sustains_and_star_power() {
+        // a note every beat; two phrases fill half the meter by the fifth note, which activates star power
+        // for 16 beats, past the end of the chart
+        let notes: String = (0..12u64).map(|i| {
+            let ticks = 192 + i * 192;
+            let sustain = if i % 2 == 1 { 96 } else { 0 };
+            let phrase = if i < 4 && i % 2 == 0 { format!("  {} = S 2 {}\n", ticks, 192 + 96) } else { String::new() };
+            format!("  {} = N {} {}\n{}", ticks, i % 5, sustain, phrase)
+        }).collect();
+        let playthrough = autoplay(&chart_file(&notes));
+
+        assert_every_note_hit(&playthrough);
+        assert_eq!(playthrough.state.sp_phrases, 2);
+        // four notes at 1x, then star power doubles six at 1x and two at 2x past a streak of ten
+        assert_eq!(playthrough.state.score, 4 * 50 + 6 * 100 + 2 * 200);
+    }
+}
@@ -0,0 +1,600 @@
+use crate::drumplaythrough::*;
+use crate::guitarplaythrough::*;
+use crate::playthrough::{Playthrough, PlaythroughEffect, PlaythroughState, SP_PHRASE_METER};
+
+/// One player's engine, whatever their instrument
+pub enum PlayerPlaythrough {
+    Guitar(GuitarPlaythrough),
+    Drums(DrumPlaythrough),
+}
+
+#[derive(Copy, Clone)]
+pub enum PlayerInputAction {
+    Guitar(GuitarInputAction),
+    Drums(DrumInputAction),
+}
+
+/// One player's final statistics, whatever their instrument
+#[derive(Clone, PartialEq, Debug)]
+pub enum PlayerResults {
+    Guitar(GuitarResults),
+    Drums(DrumResults),
+}
+
+pub enum PlayerEffect {
+    Guitar(GuitarGameEffect),
+    Drums(DrumGameEffect),
+}
+
+impl PlayerEffect {
+    /// The star power phrase the effect completed, if it completed one
+    fn completed_phrase(self: &Self) -> Option<usize> {
+        match self {
+            PlayerEffect::Guitar(GuitarGameEffect::StarPowerPhraseComplete { phrase }) => Some(*phrase),
+            PlayerEffect::Drums(DrumGameEffect::StarPowerPhraseComplete { phrase }) => Some(*phrase),
+            _ => None,
+        }
+    }
+
+    fn activated_star_power(self: &Self) -> bool {
+        match self {
+            PlayerEffect::Guitar(GuitarGameEffect::StarPowerActivated) => true,
+            PlayerEffect::Drums(DrumGameEffect::StarPowerActivated) => true,
+            _ => false,
+        }
+    }
+}
+
+impl PlaythroughEffect for PlayerEffect {
+    type Results = PlayerResults;
+
+    fn results(self: &Self) -> Option<PlayerResults> {
+        match self {
+            PlayerEffect::Guitar(effect) => effect.results().map(PlayerResults::Guitar),
+            PlayerEffect::Drums(effect) => effect.results().map(PlayerResults::Drums),
+        }
+    }
+}
+
+impl PlayerPlaythrough {
+    fn sp_phrases(self: &Self) -> &Vec<StarPowerPhrase> {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => &playthrough.chart.sp_phrases,
+            PlayerPlaythrough::Drums(playthrough) => &playthrough.chart.sp_phrases,
+        }
+    }
+
+    fn no_fail(self: &Self) -> bool {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.settings.no_fail,
+            PlayerPlaythrough::Drums(playthrough) => playthrough.settings.no_fail,
+        }
+    }
+
+    fn set_no_fail(self: &mut Self, no_fail: bool) {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.settings.no_fail = no_fail,
+            PlayerPlaythrough::Drums(playthrough) => playthrough.settings.no_fail = no_fail,
+        }
+    }
+
+    pub fn state(self: &Self) -> &PlaythroughState {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => &playthrough.state,
+            PlayerPlaythrough::Drums(playthrough) => &playthrough.state,
+        }
+    }
+
+    fn state_mut(self: &mut Self) -> &mut PlaythroughState {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => &mut playthrough.state,
+            PlayerPlaythrough::Drums(playthrough) => &mut playthrough.state,
+        }
+    }
+
+    fn set_rock_meter(self: &mut Self, rock_meter: f32) {
+        self.state_mut().rock_meter = rock_meter;
+    }
+
+    fn add_sp_meter(self: &mut Self, meter: f32) {
+        let state = self.state_mut();
+        state.sp_meter = f32::min(state.sp_meter + meter, 1.0);
+    }
+
+    fn fail(self: &mut Self) -> Vec<PlayerEffect> {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.fail().into_iter().map(PlayerEffect::Guitar).collect(),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.fail().into_iter().map(PlayerEffect::Drums).collect(),
+        }
+    }
+}
+
+impl Playthrough for PlayerPlaythrough {
+    type InputAction = PlayerInputAction;
+    type Results = PlayerResults;
+    type Effect = PlayerEffect;
+
+    /// Actions for another instrument than the player's are ignored
+    fn apply(self: &mut Self, action: &PlayerInputAction, time_ms: f32) -> Vec<PlayerEffect> {
+        match (self, action) {
+            (PlayerPlaythrough::Guitar(playthrough), PlayerInputAction::Guitar(action)) =>
+                playthrough.apply(action, time_ms).into_iter().map(PlayerEffect::Guitar).collect(),
+            (PlayerPlaythrough::Drums(playthrough), PlayerInputAction::Drums(action)) =>
+                playthrough.apply(action, time_ms).into_iter().map(PlayerEffect::Drums).collect(),
+            _ => vec![],
+        }
+    }
+
+    fn update_time(self: &mut Self, time_ms: f32) -> Vec<PlayerEffect> {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.update_time(time_ms).into_iter().map(PlayerEffect::Guitar).collect(),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.update_time(time_ms).into_iter().map(PlayerEffect::Drums).collect(),
+        }
+    }
+
+    fn time_ms(self: &Self) -> f32 {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.time_ms(),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.time_ms(),
+        }
+    }
+
+    fn start_time_ms(self: &Self) -> f32 {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.start_time_ms(),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.start_time_ms(),
+        }
+    }
+
+    fn audio_offset_ms(self: &Self) -> f32 {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.chart.audio_offset_ms,
+            PlayerPlaythrough::Drums(playthrough) => playthrough.chart.audio_offset_ms,
+        }
+    }
+
+    fn seek(self: &mut Self, time_ms: f32) {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.seek(time_ms),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.seek(time_ms),
+        }
+    }
+
+    fn pause(self: &mut Self) {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.pause(),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.pause(),
+        }
+    }
+
+    fn resume(self: &mut Self) -> f32 {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.resume(),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.resume(),
+        }
+    }
+
+    fn countdown_ms(self: &Self) -> Option<f32> {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.countdown_ms(),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.countdown_ms(),
+        }
+    }
+
+    fn end_audio(self: &mut Self) {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => playthrough.end_audio(),
+            PlayerPlaythrough::Drums(playthrough) => playthrough.end_audio(),
+        }
+    }
+
+    fn score(self: &Self) -> u64 {
+        self.state().score
+    }
+
+    fn streak(self: &Self) -> u64 {
+        self.state().streak
+    }
+
+    fn multiplier(self: &Self) -> u64 {
+        self.state().multiplier()
+    }
+
+    fn sp_meter(self: &Self) -> f32 {
+        self.state().sp_meter
+    }
+
+    fn sp_active(self: &Self) -> bool {
+        self.state().sp_active
+    }
+
+    fn rock_meter(self: &Self) -> f32 {
+        self.state().rock_meter
+    }
+
+    fn failed(self: &Self) -> bool {
+        self.state().failed
+    }
+
+    fn results(self: &Self) -> PlayerResults {
+        match self {
+            PlayerPlaythrough::Guitar(playthrough) => PlayerResults::Guitar(playthrough.results()),
+            PlayerPlaythrough::Drums(playthrough) => PlayerResults::Drums(playthrough.results()),
+        }
+    }
+}
+
+pub struct BandPlayer {
+    pub playthrough: PlayerPlaythrough,
+    /// Set once the player's song has ended, by completing or failing it
+    pub results: Option<PlayerResults>,
+    /// Song time when the player's rock meter ran out, while they wait for another player to save them
+    pub out_since_ms: Option<f32>,
+    /// The player can't run out of rock meter, whether the band is playing or not
+    no_fail: bool,
+    /// The player's base score the band last counted
+    counted_score: u64,
+}
+
+/// Star power phrases at the same tick in every player's part. Completing all of them
+/// awards every player bonus meter.
+pub struct UnisonPhrase {
+    pub ticks: u64,
+    /// Index into each player's sp_phrases of their phrase
+    pub phrases: Vec<usize>,
+    /// Whether each player has completed their phrase
+    pub completed: Vec<bool>,
+}
+
+/// Effects of the band on top of its players' own effects
+pub enum BandEffect {
+    Player(usize, PlayerEffect),
+    /// Every player completed a unison phrase
+    UnisonBonus,
+    /// A player's rock meter ran out, and another player activating star power saves them
+    PlayerOut(usize),
+    PlayerSaved(usize),
+    /// Every player was out at once, or a player wasn't saved in time
+    Failed,
+}
+
+/// Local players sharing one song clock. Every player has their own engine, and the game loop
+/// drives them all through the band so that they pause, resume and end together.
+///
+/// With more than one player, the band keeps its own score: every player's points without their own
+/// star power doubling, times the band multiplier. A player whose rock meter runs out doesn't fail the song: they're out until
+/// another player activates star power, which saves them.
+pub struct Band {
+    pub players: Vec<BandPlayer>,
+    pub unison_phrases: Vec<UnisonPhrase>,
+    pub score: u64,
+    pub failed: bool,
+}
+
+pub const MAX_PLAYERS: usize = 4;
+/// Star power meter every player gets for completing a unison phrase, on top of the phrase's own
+pub const UNISON_BONUS_METER: f32 = SP_PHRASE_METER;
+/// Rock meter a saved player comes back with
+pub const SAVED_ROCK_METER: f32 = 0.5;
+/// How long a player can be out before the band fails
+pub const SAVE_WINDOW_MS: f32 = 20000.0;
+
+impl Band {
+    pub fn new(playthroughs: Vec<PlayerPlaythrough>) -> Result<Band, &'static str> {
+        if playthroughs.is_empty() || playthroughs.len() > MAX_PLAYERS {
+            return Err("a band has one to four players");
+        }
+
+        let unison_phrases = if playthroughs.len() > 1 {
+            playthroughs[0].sp_phrases().iter().filter_map(|phrase| {
+                let phrases: Option<Vec<usize>> = playthroughs.iter()
+                    .map(|playthrough| playthrough.sp_phrases().iter().position(|other| other.ticks == phrase.ticks))
+                    .collect();
+                phrases.map(|phrases| UnisonPhrase {
+                    ticks: phrase.ticks,
+                    completed: vec![false; phrases.len()],
+                    phrases: phrases,
+                })
+            }).collect()
+        } else {
+            vec![]
+        };
+
+        let band_size = playthroughs.len();
+        let players = playthroughs.into_iter().map(|mut playthrough| {
+            let no_fail = playthrough.no_fail();
+            // with more than one player, the band decides when a player's rock meter running out fails the song
+            if band_size > 1 {
+                playthrough.set_no_fail(true);
+            }
+
+            BandPlayer {
+                playthrough: playthrough,
+                results: None,
+                out_since_ms: None,
+                no_fail: no_fail,
+                counted_score: 0,
+            }
+        }).collect();
+
+        Ok(Band {
+            players: players,
+            unison_phrases: unison_phrases,
+            score: 0,
+            failed: false,
+        })
+    }
+
+    /// Applies the band's rules to effects of a player's engine, returning them along with the band's own effects.
+    /// The band does this itself for the engine calls it makes, so it's only needed for calls made
+    /// to a player's engine directly, like replayed events.
+    pub fn handle_effects(self: &mut Self, player: usize, effects: Vec<PlayerEffect>) -> Vec<BandEffect> {
+        let mut band_effects = Vec::new();
+
+        if let Some(results) = effects.iter().filter_map(|effect| effect.results()).last() {
+            self.players[player].results = Some(results);
+        }
+
+        // points only count for the band while the player is in
+        let score = self.players[player].playthrough.state().base_score;
+        if self.players[player].out_since_ms.is_none() {
+            self.score += score.saturating_sub(self.players[player].counted_score) * self.multiplier();
+        }
+        self.players[player].counted_score = score;
+
+        for phrase in effects.iter().filter_map(PlayerEffect::completed_phrase) {
+            band_effects.extend(self.complete_phrase(player, phrase));
+        }
+
+        if self.players[player].out_since_ms.is_none() && effects.iter().any(PlayerEffect::activated_star_power) {
+            band_effects.extend(self.save_players());
+        }
+
+        if self.players.len() > 1 && !self.players[player].no_fail && self.players[player].out_since_ms.is_none()
+            && self.players[player].playthrough.rock_meter() <= 0.0 {
+            self.players[player].out_since_ms = Some(self.players[player].playthrough.time_ms());
+            band_effects.push(BandEffect::PlayerOut(player));
+
+            if self.players.iter().all(|player| player.out_since_ms.is_some() || player.results.is_some()) {
+                band_effects.extend(self.fail());
+            }
+        }
+
+        effects.into_iter().map(|effect| BandEffect::Player(player, effect)).chain(band_effects).collect()
+    }
+
+    fn complete_phrase(self: &mut Self, player: usize, phrase: usize) -> Vec<BandEffect> {
+        let unison = match self.unison_phrases.iter_mut().find(|unison| unison.phrases[player] == phrase) {
+            Some(unison) => unison,
+            None => return vec![],
+        };
+
+        unison.completed[player] = true;
+        if !unison.completed.iter().all(|completed| *completed) {
+            return vec![];
+        }
+
+        for player in &mut self.players {
+            player.playthrough.add_sp_meter(UNISON_BONUS_METER);
+        }
+        vec![BandEffect::UnisonBonus]
+    }
+
+    fn save_players(self: &mut Self) -> Vec<BandEffect> {
+        let mut effects = Vec::new();
+
+        for (index, player) in self.players.iter_mut().enumerate() {
+            if player.out_since_ms.is_some() && player.results.is_none() {
+                player.out_since_ms = None;
+                player.counted_score = player.playthrough.state().base_score;
+                player.playthrough.set_rock_meter(SAVED_ROCK_METER);
+                effects.push(BandEffect::PlayerSaved(index));
+            }
+        }
+
+        effects
+    }
+
+    fn fail(self: &mut Self) -> Vec<BandEffect> {
+        if self.failed {
+            return vec![];
+        }
+        self.failed = true;
+
+        let mut effects = Vec::new();
+        for player in 0..self.players.len() {
+            if self.players[player].results.is_some() {
+                continue;
+            }
+            let player_effects = self.players[player].playthrough.fail();
+            if let Some(results) = player_effects.iter().filter_map(|effect| effect.results()).last() {
+                self.players[player].results = Some(results);
+            }
+            effects.extend(player_effects.into_iter().map(|effect| BandEffect::Player(player, effect)));
+        }
+        effects.push(BandEffect::Failed);
+        effects
+    }
+
+    pub fn apply(self: &mut Self, player: usize, action: &PlayerInputAction, time_ms: f32) -> Vec<BandEffect> {
+        let effects = self.players[player].playthrough.apply(action, time_ms);
+        self.handle_effects(player, effects)
+    }
+
+    /// Moves every player's engine to time_ms
+    pub fn update_time(self: &mut Self, time_ms: f32) -> Vec<BandEffect> {
+        let mut band_effects = Vec::new();
+
+        for player in 0..self.players.len() {
+            let effects = self.players[player].playthrough.update_time(time_ms);
+            band_effects.extend(self.handle_effects(player, effects));
+        }
+
+        let unsaved = self.players.iter()
+            .any(|player| player.out_since_ms.map_or(false, |out_since_ms| time_ms - out_since_ms > SAVE_WINDOW_MS));
+        if unsaved {
+            band_effects.extend(self.fail());
+        }
+
+        band_effects
+    }
+
+    /// The band multiplier: every player in star power adds two to it, so one player in star power
+    /// doubles the band's points as it does their own
+    pub fn multiplier(self: &Self) -> u64 {
+        std::cmp::max(2 * self.players.iter().filter(|player| player.playthrough.sp_active()).count() as u64, 1)
+    }
+
+    /// The song clock starts at the earliest of the players' starts
+    pub fn start_time_ms(self: &Self) -> f32 {
+        self.players.iter().map(|player| player.playthrough.start_time_ms()).fold(0f32, f32::min)
+    }
+
+    pub fn audio_offset_ms(self: &Self) -> f32 {
+        self.players[0].playthrough.audio_offset_ms()
+    }
+
+    pub fn pause(self: &mut Self) {
+        self.players.iter_mut().for_each(|player| player.playthrough.pause());
+    }
+
+    /// Resumes every player, returning the time the song clock should restart from:
+    /// the earliest any of them rewound to
+    pub fn resume(self: &mut Self) -> f32 {
+        self.players.iter_mut().map(|player| player.playthrough.resume()).fold(std::f32::INFINITY, f32::min)
+    }
+
+    pub fn countdown_ms(self: &Self) -> Option<f32> {
+        self.players.iter().filter_map(|player| player.playthrough.countdown_ms()).fold(None, |max, countdown_ms| {
+            Some(max.map_or(countdown_ms, |max: f32| max.max(countdown_ms)))
+        })
+    }
+
+    pub fn end_audio(self: &mut Self) {
+        self.players.iter_mut().for_each(|player| player.playthrough.end_audio());
+    }
+
+    /// Every player's song has ended
+    pub fn is_over(self: &Self) -> bool {
+        self.players.iter().all(|player| player.results.is_some())
+    }
+
+    /// Every player has failed, so the music can stop
+    pub fn failed(self: &Self) -> bool {
+        self.failed || self.players.iter().all(|player| player.playthrough.failed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart;
+
+    // 120 BPM at 192 ticks per beat, so tick 192 is at 500 ms. Both parts have a star power phrase
+    // on their first note, which makes it a unison phrase.
+    const CHART: &str = "[Song]\n{\n  Resolution = 192\n}\n[SyncTrack]\n{\n  0 = B 120000\n}\n\
+        [ExpertSingle]\n{\n  192 = N 0 0\n  192 = S 2 1\n  384 = N 0 0\n  576 = N 0 0\n  9600 = N 0 0\n}\n\
+        [ExpertDrums]\n{\n  192 = N 1 0\n  192 = S 2 1\n  384 = N 1 0\n  576 = N 1 0\n  9600 = N 1 0\n}\n";
+
+    fn band() -> Band {
+        let difficulty = chart::Difficulty::Expert;
+        let guitar = GuitarPlaythrough::new(chart::read(CHART).unwrap(), chart::Instrument::Guitar, difficulty, GuitarSettings::for_difficulty(&difficulty)).unwrap();
+        let drums = DrumPlaythrough::new(chart::read(CHART).unwrap(), difficulty, DrumSettings::for_difficulty(&difficulty)).unwrap();
+        Band::new(vec![PlayerPlaythrough::Guitar(guitar), PlayerPlaythrough::Drums(drums)]).unwrap()
+    }
+
+    fn strum(band: &mut Band, time_ms: f32) -> Vec<BandEffect> {
+        band.update_time(time_ms);
+        band.apply(0, &PlayerInputAction::Guitar(GuitarInputAction::FretDown(Fret::G)), time_ms);
+        band.apply(0, &PlayerInputAction::Guitar(GuitarInputAction::Strum), time_ms)
+    }
+
+    fn hit(band: &mut Band, time_ms: f32) -> Vec<BandEffect> {
+        band.update_time(time_ms);
+        band.apply(1, &PlayerInputAction::Drums(DrumInputAction::Hit(Pad::Red)), time_ms)
+    }
+
+    fn has(effects: &[BandEffect], matches: fn(&BandEffect) -> bool) -> bool {
+        effects.iter().any(matches)
+    }
+
+    #[test]
+    fn unison_bonus_needs_every_player_to_complete_the_phrase() {
+        let mut band = band();
+        assert_eq!(band.unison_phrases.len(), 1);
+
+        let effects = strum(&mut band, 500.0);
+        assert!(!has(&effects, |effect| match effect { BandEffect::UnisonBonus => true, _ => false }));
+        assert_eq!(band.players[0].playthrough.sp_meter(), SP_PHRASE_METER);
+
+        let effects = hit(&mut band, 500.0);
+        assert!(has(&effects, |effect| match effect { BandEffect::UnisonBonus => true, _ => false }));
+        assert_eq!(band.players[0].playthrough.sp_meter(), SP_PHRASE_METER + UNISON_BONUS_METER);
+        assert_eq!(band.players[1].playthrough.sp_meter(), SP_PHRASE_METER + UNISON_BONUS_METER);
+    }
+
+    #[test]
+    fn band_score_multiplies_base_points_by_the_band_multiplier() {
+        let mut band = band();
+        strum(&mut band, 500.0);
+        hit(&mut band, 500.0);
+        assert_eq!(band.score, band.players[0].playthrough.score() + band.players[1].playthrough.score());
+
+        band.apply(0, &PlayerInputAction::Guitar(GuitarInputAction::StarPower), 600.0);
+        band.apply(1, &PlayerInputAction::Drums(DrumInputAction::StarPower), 600.0);
+        assert_eq!(band.multiplier(), 4);
+
+        // the guitarist's own score doubles in star power, but the band's points are multiplied once
+        let (score, guitar_score) = (band.score, band.players[0].playthrough.score());
+        strum(&mut band, 1000.0);
+        let guitar_points = band.players[0].playthrough.score() - guitar_score;
+        assert_eq!(guitar_points, 2 * 50);
+        assert_eq!(band.score - score, 4 * 50);
+    }
+
+    #[test]
+    fn player_out_is_saved_by_star_power_within_the_window() {
+        let mut band = band();
+        strum(&mut band, 500.0);
+        hit(&mut band, 500.0);
+
+        band.players[1].playthrough.set_rock_meter(0.01);
+        let effects = band.update_time(1200.0);
+        assert!(has(&effects, |effect| match effect { BandEffect::PlayerOut(1) => true, _ => false }));
+        assert!(!band.players[1].playthrough.failed());
+
+        // the points of a player who is out don't count for the band
+        let score = band.score;
+        hit(&mut band, 1500.0);
+        assert_eq!(band.score, score);
+
+        let effects = band.apply(0, &PlayerInputAction::Guitar(GuitarInputAction::StarPower), 1500.0 + SAVE_WINDOW_MS - 1.0);
+        assert!(has(&effects, |effect| match effect { BandEffect::PlayerSaved(1) => true, _ => false }));
+        assert_eq!(band.players[1].playthrough.rock_meter(), SAVED_ROCK_METER);
+        assert!(!band.failed());
+    }
+
+    #[test]
+    fn player_not_saved_in_time_fails_the_band() {
+        let mut band = band();
+        band.players[1].playthrough.set_rock_meter(0.01);
+        band.update_time(1200.0);
+        assert!(band.players[1].out_since_ms.is_some());
+
+        assert!(!has(&band.update_time(1200.0 + SAVE_WINDOW_MS), |effect| match effect { BandEffect::Failed => true, _ => false }));
+        let effects = band.update_time(1201.0 + SAVE_WINDOW_MS);
+        assert!(has(&effects, |effect| match effect { BandEffect::Failed => true, _ => false }));
+        assert!(band.failed() && band.is_over());
+    }
+
+    #[test]
+    fn every_player_out_at_once_fails_the_band() {
+        let mut band = band();
+        band.players[0].playthrough.set_rock_meter(0.01);
+        band.players[1].playthrough.set_rock_meter(0.01);
+        let effects = band.update_time(1200.0);
+
+        assert!(has(&effects, |effect| match effect { BandEffect::Failed => true, _ => false }));
+        assert!(band.failed() && band.is_over());
+    }
+}
@@ -0,0 +1,298 @@
+extern crate bumpit;
+
+use bumpit::chart;
+use bumpit::guitarplaythrough::*;
+use bumpit::replay;
+use bumpit::replay::Replay;
+
+const USAGE: &str = "usage: bumpit-verify <chart file> <replay file> [--score <claimed score>] [--json]";
+
+// exit codes
+const MISMATCH: i32 = 1;
+const ERROR: i32 = 2;
+const UNRANKED: i32 = 3;
+const UNCLAIMED: i32 = 4;
+
+/// What the re-simulation found, from worst to best
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Status {
+    /// Recorded on a different chart, or the score doesn't match the claim
+    Mismatch,
+    /// Played with settings a player can't choose, like wider hit windows or no-fail
+    Unranked,
+    /// Nothing to check the score against
+    Unclaimed,
+    Verified,
+}
+
+impl Status {
+    fn name(self: &Self) -> &'static str {
+        match self {
+            Status::Mismatch => "MISMATCH",
+            Status::Unranked => "UNRANKED",
+            Status::Unclaimed => "UNCLAIMED",
+            Status::Verified => "VERIFIED",
+        }
+    }
+
+    fn exit_code(self: &Self) -> i32 {
+        match self {
+            Status::Mismatch => MISMATCH,
+            Status::Unranked => UNRANKED,
+            Status::Unclaimed => UNCLAIMED,
+            Status::Verified => 0,
+        }
+    }
+}
+
+struct Verification {
+    results: GuitarResults,
+    claimed_score: Option<u64>,
+    chart_matches: bool,
+    /// The replay's settings are the ones a player can play with
+    ranked: bool,
+    status: Status,
+}
+
+struct Args {
+    chart_path: String,
+    replay_path: String,
+    claimed_score: Option<u64>,
+    json: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut paths: Vec<String> = Vec::new();
+    let mut claimed_score = None;
+    let mut json = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--json" => json = true,
+            "--score" => claimed_score = Some(args.next()
+                .ok_or_else(|| String::from("--score needs a value"))?
+                .parse::<u64>()
+                .map_err(|e| e.to_string())?),
+            _ => paths.push(arg),
+        }
+    }
+
+    match paths.as_slice() {
+        [chart_path, replay_path] => Ok(Args {
+            chart_path: chart_path.clone(),
+            replay_path: replay_path.clone(),
+            claimed_score: claimed_score,
+            json: json,
+        }),
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let escaped: String = s.chars().map(|c| match c {
+        '"' => String::from("\\\""),
+        '\\' => String::from("\\\\"),
+        c if (c as u32) < 0x20 => format!("\\u{:04x}", c as u32),
+        c => c.to_string(),
+    }).collect();
+    format!("\"{}\"", escaped)
+}
+
+fn json_option(value: Option<u64>) -> String {
+    value.map_or_else(|| String::from("null"), |v| v.to_string())
+}
+
+fn print_json(verification: &Verification) {
+    let results = &verification.results;
+    let sections: Vec<String> = results.sections.iter().zip(&results.section_overstrums).map(|(section, overstrums)| format!(
+        "{{\"name\":{},\"notes_hit\":{},\"notes_missed\":{},\"notes_total\":{},\"overstrums\":{},\"accuracy\":{},\"score\":{}}}",
+        json_string(&section.name), section.notes_hit, section.notes_missed, section.notes_total,
+        overstrums, section.accuracy(), section.score)).collect();
+    let modifiers: Vec<String> = results.modifiers.names().iter().map(|name| json_string(name)).collect();
+
+    println!(
+        "{{\"status\":{},\"verified\":{},\"chart_matches\":{},\"ranked\":{},\"claimed_score\":{},\"score\":{},\"notes_hit\":{},\"notes_missed\":{},\"notes_total\":{},\"accuracy\":{},\"best_streak\":{},\"sp_phrases\":{},\"failed\":{},\"modifiers\":[{}],\"mean_offset_ms\":{},\"sections\":[{}]}}",
+        json_string(verification.status.name()), verification.status == Status::Verified, verification.chart_matches,
+        verification.ranked, json_option(verification.claimed_score), results.score, results.notes_hit, results.notes_missed,
+        results.notes_total, results.accuracy(), results.best_streak, results.sp_phrases, results.failed,
+        modifiers.join(","), results.timing.mean_offset_ms(), sections.join(","));
+}
+
+fn print_text(verification: &Verification) {
+    let results = &verification.results;
+    if !verification.chart_matches {
+        println!("The replay was recorded on a different chart");
+    }
+    if !verification.ranked {
+        println!("The replay was played with settings other than the difficulty's, so it was scored with the difficulty's");
+    }
+    match verification.claimed_score {
+        Some(claimed) => println!("Score: {} (claimed {})", results.score, claimed),
+        None => println!("Score: {} (nothing claimed)", results.score),
+    }
+    println!("Notes: {} / {} ({:.2}%)", results.notes_hit, results.notes_total, results.accuracy() * 100f32);
+    println!("Best streak: {}", results.best_streak);
+    println!("Star power phrases: {}", results.sp_phrases);
+    if !results.modifiers.names().is_empty() {
+        println!("Modifiers: {}", results.modifiers.names().join(", "));
+    }
+    println!("Average offset: {:+.1} ms", results.timing.mean_offset_ms());
+    if results.failed {
+        println!("Failed");
+    }
+    for (section, overstrums) in results.sections.iter().zip(&results.section_overstrums) {
+        println!("  {}: {} / {} ({:.2}%), {} overstrums, {} points",
+            section.name, section.notes_hit, section.notes_total, section.accuracy() * 100f32, overstrums, section.score);
+    }
+    println!("{}", verification.status.name());
+}
+
+/// The settings the replay is scored with: the difficulty's, with the ones a player chooses taken from the replay,
+/// and whether the replay had any others
+fn ranked_settings(replay: &Replay) -> (GuitarSettings, bool) {
+    let settings = GuitarSettings {
+        modifiers: replay.settings.modifiers,
+        audio_latency_ms: replay.settings.audio_latency_ms,
+        ..GuitarSettings::for_difficulty(&replay.difficulty)
+    };
+    (settings, settings == replay.settings)
+}
+
+/// Re-simulates the replay on the chart. A claimed score overrides the one the replay claims.
+fn verify(chart_file: &str, replay_file: &str, claimed_score: Option<u64>) -> Result<Verification, String> {
+    let replay = replay::read(replay_file).map_err(|e| format!("couldn't parse replay: {:?}", e))?;
+    let chart = chart::read(chart_file).map_err(|e| format!("couldn't parse chart: {:?}", e))?;
+
+    let (settings, ranked) = ranked_settings(&replay);
+    let mut playthrough = GuitarPlaythrough::new(chart, replay.instrument, replay.difficulty, settings).map_err(|s| String::from(s))?;
+    replay.play_all(&mut playthrough);
+    let results = playthrough.results();
+
+    let chart_matches = replay.chart_hash == replay::chart_hash(chart_file);
+    let claimed_score = claimed_score.or(replay.claimed_score);
+    let status = match claimed_score {
+        _ if !chart_matches => Status::Mismatch,
+        Some(claimed) if claimed != results.score => Status::Mismatch,
+        _ if !ranked => Status::Unranked,
+        None => Status::Unclaimed,
+        Some(_) => Status::Verified,
+    };
+
+    Ok(Verification {
+        results: results,
+        claimed_score: claimed_score,
+        chart_matches: chart_matches,
+        ranked: ranked,
+        status: status,
+    })
+}
+
+/// Re-simulates a recorded replay through GuitarPlaythrough, without a window or audio,
+/// and checks that it reproduces the claimed score with settings a player can choose.
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(ERROR);
+    });
+
+    let run = || -> Result<Verification, String> {
+        let chart_file = std::fs::read_to_string(&args.chart_path).map_err(|e| e.to_string())?;
+        let replay_file = std::fs::read_to_string(&args.replay_path).map_err(|e| e.to_string())?;
+        verify(&chart_file, &replay_file, args.claimed_score)
+    };
+
+    let verification = run().unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(ERROR);
+    });
+
+    if args.json {
+        print_json(&verification);
+    } else {
+        print_text(&verification);
+    }
+
+    if verification.status != Status::Verified {
+        std::process::exit(verification.status.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bumpit::autoplay;
+
+    // 120 BPM at 192 ticks per beat
+    const CHART: &str = "[Song]\n{\n  Resolution = 192\n}\n[SyncTrack]\n{\n  0 = B 120000\n}\n[ExpertSingle]\n{\n  192 = N 0 0\n  384 = N 1 0\n  576 = N 2 0\n}\n";
+
+    // a bot's replay of the chart, claiming the score it gets
+    fn replay(settings: GuitarSettings) -> Replay {
+        let difficulty = chart::Difficulty::Expert;
+        let mut playthrough = GuitarPlaythrough::new(chart::read(CHART).unwrap(), chart::Instrument::Guitar, difficulty, settings).unwrap();
+        let mut replay = autoplay::replay(&playthrough, replay::chart_hash(CHART));
+        replay.play_all(&mut playthrough);
+        replay.claimed_score = Some(playthrough.state.score);
+        replay
+    }
+
+    fn default_settings() -> GuitarSettings {
+        GuitarSettings::for_difficulty(&chart::Difficulty::Expert)
+    }
+
+    #[test]
+    fn matching_replay_is_verified() {
+        let verification = verify(CHART, &replay(default_settings()).write(), None).unwrap();
+        assert_eq!(verification.status, Status::Verified);
+        assert_eq!(verification.results.score, 150);
+        assert!(verification.chart_matches && verification.ranked);
+    }
+
+    #[test]
+    fn player_chosen_settings_stay_ranked() {
+        let mut settings = default_settings();
+        settings.modifiers.precision = true;
+        settings.audio_latency_ms = 30.0;
+        assert_eq!(verify(CHART, &replay(settings).write(), None).unwrap().status, Status::Verified);
+    }
+
+    #[test]
+    fn wrong_claimed_score_is_a_mismatch() {
+        let mut replay = replay(default_settings());
+        replay.claimed_score = Some(151);
+        assert_eq!(verify(CHART, &replay.write(), None).unwrap().status, Status::Mismatch);
+        // a score passed on the command line overrides the replay's
+        assert_eq!(verify(CHART, &replay.write(), Some(150)).unwrap().status, Status::Verified);
+    }
+
+    #[test]
+    fn missing_claim_is_unclaimed() {
+        let mut replay = replay(default_settings());
+        replay.claimed_score = None;
+        let verification = verify(CHART, &replay.write(), None).unwrap();
+        assert_eq!(verification.status, Status::Unclaimed);
+        assert_ne!(verification.status.exit_code(), 0);
+    }
+
+    #[test]
+    fn changed_chart_is_a_mismatch() {
+        let changed = CHART.replace("576 = N 2 0", "576 = N 3 0");
+        let verification = verify(&changed, &replay(default_settings()).write(), None).unwrap();
+        assert!(!verification.chart_matches);
+        assert_eq!(verification.status, Status::Mismatch);
+    }
+
+    #[test]
+    fn tampered_settings_are_unranked() {
+        let mut wide_windows = default_settings();
+        wide_windows.hit_window.late_ms = 1000.0;
+        let mut no_fail = default_settings();
+        no_fail.no_fail = true;
+
+        for settings in &[wide_windows, no_fail] {
+            let verification = verify(CHART, &replay(*settings).write(), None).unwrap();
+            assert!(!verification.ranked);
+            assert_eq!(verification.status, Status::Unranked);
+        }
+    }
+}
@@ -0,0 +1,588 @@
+use crate::drumplaythrough::Pad;
+
+/// Where the input bindings are kept between runs
+pub const BINDINGS_PATH: &str = "Settings/bindings.txt";
+
+/// The part a device's bindings play. A device maps its controls differently for each.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BindingKind {
+    Guitar,
+    SixFret,
+    Drums,
+}
+
+impl BindingKind {
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            BindingKind::Guitar => "guitar",
+            BindingKind::SixFret => "six_fret",
+            BindingKind::Drums => "drums",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BindingKind> {
+        match name {
+            "guitar" => Some(BindingKind::Guitar),
+            "six_fret" => Some(BindingKind::SixFret),
+            "drums" => Some(BindingKind::Drums),
+            _ => None,
+        }
+    }
+
+    /// Every action of the part, in the order rebinding asks for them
+    pub fn actions(self: &Self) -> Vec<Action> {
+        match self {
+            BindingKind::Guitar => vec![
+                Action::Green, Action::Red, Action::Yellow, Action::Blue, Action::Orange,
+                Action::Strum, Action::StarPower, Action::Whammy, Action::Tilt,
+            ],
+            BindingKind::SixFret => vec![
+                Action::White1, Action::White2, Action::White3, Action::Black1, Action::Black2, Action::Black3,
+                Action::Strum, Action::StarPower, Action::Whammy, Action::Tilt,
+            ],
+            BindingKind::Drums => vec![
+                Action::Pad(Pad::Red), Action::Pad(Pad::YellowTom), Action::Pad(Pad::BlueTom), Action::Pad(Pad::GreenTom),
+                Action::Pad(Pad::YellowCymbal), Action::Pad(Pad::BlueCymbal), Action::Pad(Pad::GreenCymbal),
+                Action::Pad(Pad::Kick), Action::StarPower,
+            ],
+        }
+    }
+}
+
+/// Whose bindings they are. A joystick uses the bindings of its GUID if there are any,
+/// then those of its name, then those of Joystick.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Device {
+    Keyboard,
+    /// Any joystick without bindings of its own
+    Joystick,
+    Guid(String),
+    Name(String),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HatDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl HatDirection {
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            HatDirection::Up => "up",
+            HatDirection::Down => "down",
+            HatDirection::Left => "left",
+            HatDirection::Right => "right",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HatDirection> {
+        match name {
+            "up" => Some(HatDirection::Up),
+            "down" => Some(HatDirection::Down),
+            "left" => Some(HatDirection::Left),
+            "right" => Some(HatDirection::Right),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AxisCalibration {
+    pub axis_idx: u8,
+    /// Raw value when untouched
+    pub rest: i16,
+    /// Raw value when fully engaged
+    pub full: i16,
+    /// Fraction of travel from rest that reads as 0
+    pub dead_zone: f32,
+}
+
+// the whammy bar rarely comes back all the way to rest, while tilt should only read once the guitar is raised
+const WHAMMY_DEAD_ZONE: f32 = 0.1;
+const TILT_DEAD_ZONE: f32 = 0.05;
+
+impl AxisCalibration {
+    /// Calibrates an axis moving from rest to full for the action it is bound to
+    pub fn new(action: Action, axis_idx: u8, rest: i16, full: i16) -> AxisCalibration {
+        let dead_zone = match action {
+            Action::Tilt => TILT_DEAD_ZONE,
+            _ => WHAMMY_DEAD_ZONE,
+        };
+        AxisCalibration { axis_idx: axis_idx, rest: rest, full: full, dead_zone: dead_zone }
+    }
+
+    /// Maps a raw axis value to 0 (rest) through 1 (full)
+    pub fn normalize(self: &Self, value: i16) -> f32 {
+        let travel = (value as f32 - self.rest as f32) / (self.full as f32 - self.rest as f32);
+        let travel = travel.max(0f32).min(1f32);
+        if travel <= self.dead_zone {
+            0f32
+        } else {
+            (travel - self.dead_zone) / (1f32 - self.dead_zone)
+        }
+    }
+}
+
+/// A control on a device. Keys are named as SDL names them, e.g. "Z", "Space" or "Left Shift".
+/// Hats only press, so they suit strumming better than frets.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Control {
+    Key(String),
+    Button(u8),
+    Hat(u8, HatDirection),
+    Axis(AxisCalibration),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Action {
+    Green,
+    Red,
+    Yellow,
+    Blue,
+    Orange,
+    White1,
+    White2,
+    White3,
+    Black1,
+    Black2,
+    Black3,
+    Strum,
+    StarPower,
+    Whammy,
+    Tilt,
+    Pad(Pad),
+}
+
+impl Action {
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            Action::Green => "green",
+            Action::Red => "red",
+            Action::Yellow => "yellow",
+            Action::Blue => "blue",
+            Action::Orange => "orange",
+            Action::White1 => "white1",
+            Action::White2 => "white2",
+            Action::White3 => "white3",
+            Action::Black1 => "black1",
+            Action::Black2 => "black2",
+            Action::Black3 => "black3",
+            Action::Strum => "strum",
+            Action::StarPower => "star_power",
+            Action::Whammy => "whammy",
+            Action::Tilt => "tilt",
+            Action::Pad(Pad::Kick) => "kick",
+            Action::Pad(Pad::Red) => "red_pad",
+            Action::Pad(Pad::YellowTom) => "yellow_tom",
+            Action::Pad(Pad::YellowCymbal) => "yellow_cymbal",
+            Action::Pad(Pad::BlueTom) => "blue_tom",
+            Action::Pad(Pad::BlueCymbal) => "blue_cymbal",
+            Action::Pad(Pad::GreenTom) => "green_tom",
+            Action::Pad(Pad::GreenCymbal) => "green_cymbal",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        [
+            Action::Green, Action::Red, Action::Yellow, Action::Blue, Action::Orange,
+            Action::White1, Action::White2, Action::White3, Action::Black1, Action::Black2, Action::Black3,
+            Action::Strum, Action::StarPower, Action::Whammy, Action::Tilt,
+            Action::Pad(Pad::Kick), Action::Pad(Pad::Red), Action::Pad(Pad::YellowTom), Action::Pad(Pad::YellowCymbal),
+            Action::Pad(Pad::BlueTom), Action::Pad(Pad::BlueCymbal), Action::Pad(Pad::GreenTom), Action::Pad(Pad::GreenCymbal),
+        ].iter().find(|action| action.name() == name).map(|action| *action)
+    }
+
+    /// How rebinding names the action to the player
+    pub fn label(self: &Self) -> &'static str {
+        match self {
+            Action::Green => "Green",
+            Action::Red => "Red",
+            Action::Yellow => "Yellow",
+            Action::Blue => "Blue",
+            Action::Orange => "Orange",
+            Action::White1 => "White 1",
+            Action::White2 => "White 2",
+            Action::White3 => "White 3",
+            Action::Black1 => "Black 1",
+            Action::Black2 => "Black 2",
+            Action::Black3 => "Black 3",
+            Action::Strum => "Strum",
+            Action::StarPower => "Star Power",
+            Action::Whammy => "the whammy bar",
+            Action::Tilt => "tilt",
+            Action::Pad(Pad::Kick) => "the kick pedal",
+            Action::Pad(Pad::Red) => "the red pad",
+            Action::Pad(Pad::YellowTom) => "the yellow tom",
+            Action::Pad(Pad::YellowCymbal) => "the yellow cymbal",
+            Action::Pad(Pad::BlueTom) => "the blue tom",
+            Action::Pad(Pad::BlueCymbal) => "the blue cymbal",
+            Action::Pad(Pad::GreenTom) => "the green tom",
+            Action::Pad(Pad::GreenCymbal) => "the green cymbal",
+        }
+    }
+
+    /// Whammy and tilt are positions along an axis rather than presses
+    pub fn is_analog(self: &Self) -> bool {
+        match self {
+            Action::Whammy | Action::Tilt => true,
+            _ => false,
+        }
+    }
+}
+
+/// Something a device did, as far as bindings go
+#[derive(Clone, PartialEq, Debug)]
+pub enum Input {
+    KeyDown(String),
+    KeyUp(String),
+    ButtonDown(u8),
+    ButtonUp(u8),
+    Hat(u8, HatDirection),
+    Axis(u8, i16),
+}
+
+/// What an input does to the action it is bound to
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Press {
+    Down,
+    Up,
+    /// An axis moved to this position, from 0 (rest) to 1 (full)
+    Position(f32),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Binding {
+    pub control: Control,
+    pub action: Action,
+}
+
+impl Binding {
+    fn press(self: &Self, input: &Input) -> Option<Press> {
+        match (&self.control, input) {
+            (Control::Key(key), Input::KeyDown(name)) if key == name => Some(Press::Down),
+            (Control::Key(key), Input::KeyUp(name)) if key == name => Some(Press::Up),
+            (Control::Button(button), Input::ButtonDown(button_idx)) if button == button_idx => Some(Press::Down),
+            (Control::Button(button), Input::ButtonUp(button_idx)) if button == button_idx => Some(Press::Up),
+            (Control::Hat(hat, direction), Input::Hat(hat_idx, moved)) if hat == hat_idx && direction == moved => Some(Press::Down),
+            (Control::Axis(calibration), Input::Axis(axis_idx, value)) if calibration.axis_idx == *axis_idx => Some(Press::Position(calibration.normalize(*value))),
+            _ => None,
+        }
+    }
+}
+
+/// One device's bindings for one part
+#[derive(Clone, PartialEq, Debug)]
+pub struct DeviceBindings {
+    pub kind: BindingKind,
+    pub device: Device,
+    pub bindings: Vec<Binding>,
+}
+
+pub struct Bindings {
+    pub devices: Vec<DeviceBindings>,
+}
+
+#[derive(Debug, Clone)]
+pub enum BindingsParseError {
+    ParseIntError(std::num::ParseIntError),
+    ParseFloatError(std::num::ParseFloatError),
+    UnknownKind(String),
+    UnknownDevice(String),
+    UnknownAction(String),
+    UnknownControl(String),
+    /// An axis whose rest and full are the same, or whose dead zone isn't at least 0 and under 1
+    InvalidAxis(u8),
+    BindingOutsideDevice,
+    MissingField,
+}
+
+impl std::convert::From<std::num::ParseIntError> for BindingsParseError {
+    fn from(err: std::num::ParseIntError) -> BindingsParseError {
+        BindingsParseError::ParseIntError(err)
+    }
+}
+
+impl std::convert::From<std::num::ParseFloatError> for BindingsParseError {
+    fn from(err: std::num::ParseFloatError) -> BindingsParseError {
+        BindingsParseError::ParseFloatError(err)
+    }
+}
+
+fn key(name: &str, action: Action) -> Binding {
+    Binding { control: Control::Key(String::from(name)), action: action }
+}
+
+fn button(button_idx: u8, action: Action) -> Binding {
+    Binding { control: Control::Button(button_idx), action: action }
+}
+
+impl Default for Bindings {
+    /// The bindings every device starts with: the keyboard, and Xbox 360 guitars and drum kits.
+    fn default() -> Bindings {
+        // Xbox 360 guitars report the whammy bar on the right stick X axis, tilt on the right stick Y axis,
+        // and the strum bar on the d-pad
+        let guitar_joystick = [
+            button(6, Action::StarPower),
+            Binding { control: Control::Hat(0, HatDirection::Up), action: Action::Strum },
+            Binding { control: Control::Hat(0, HatDirection::Down), action: Action::Strum },
+            Binding { control: Control::Axis(AxisCalibration::new(Action::Whammy, 3, -32768, 32767)), action: Action::Whammy },
+            Binding { control: Control::Axis(AxisCalibration::new(Action::Tilt, 4, 0, 32767)), action: Action::Tilt },
+        ];
+
+        Bindings {
+            devices: vec![
+                DeviceBindings {
+                    kind: BindingKind::Guitar,
+                    device: Device::Keyboard,
+                    bindings: vec![
+                        key("Z", Action::Green), key("X", Action::Red), key("C", Action::Yellow), key("V", Action::Blue), key("B", Action::Orange),
+                        key("Space", Action::Strum), key("Return", Action::StarPower),
+                    ],
+                },
+                DeviceBindings {
+                    kind: BindingKind::Guitar,
+                    device: Device::Joystick,
+                    bindings: vec![
+                        button(0, Action::Green), button(1, Action::Red), button(3, Action::Yellow), button(2, Action::Blue), button(4, Action::Orange),
+                    ].into_iter().chain(guitar_joystick.iter().cloned()).collect(),
+                },
+                // six-fret frets are on Z X C (white) and A S D (black) on the keyboard.
+                // Xbox 360 GHL guitars report Black 1-3 as A, B and Y, and White 1-3 as X, LB and RB.
+                DeviceBindings {
+                    kind: BindingKind::SixFret,
+                    device: Device::Keyboard,
+                    bindings: vec![
+                        key("Z", Action::White1), key("X", Action::White2), key("C", Action::White3),
+                        key("A", Action::Black1), key("S", Action::Black2), key("D", Action::Black3),
+                        key("Space", Action::Strum), key("Return", Action::StarPower),
+                    ],
+                },
+                DeviceBindings {
+                    kind: BindingKind::SixFret,
+                    device: Device::Joystick,
+                    bindings: vec![
+                        button(0, Action::Black1), button(1, Action::Black2), button(3, Action::Black3),
+                        button(2, Action::White1), button(4, Action::White2), button(5, Action::White3),
+                    ].into_iter().chain(guitar_joystick.iter().cloned()).collect(),
+                },
+                // drum pads are on Z X C V (red, yellow, blue, green toms), S D F (yellow, blue, green cymbals)
+                // and Space (kick) on the keyboard.
+                // Xbox 360 drum kits report the pads as B, Y, X and A, and the kick pedal as LB.
+                DeviceBindings {
+                    kind: BindingKind::Drums,
+                    device: Device::Keyboard,
+                    bindings: vec![
+                        key("Z", Action::Pad(Pad::Red)), key("X", Action::Pad(Pad::YellowTom)),
+                        key("C", Action::Pad(Pad::BlueTom)), key("V", Action::Pad(Pad::GreenTom)),
+                        key("S", Action::Pad(Pad::YellowCymbal)), key("D", Action::Pad(Pad::BlueCymbal)), key("F", Action::Pad(Pad::GreenCymbal)),
+                        key("Space", Action::Pad(Pad::Kick)), key("Return", Action::StarPower),
+                    ],
+                },
+                DeviceBindings {
+                    kind: BindingKind::Drums,
+                    device: Device::Joystick,
+                    bindings: vec![
+                        button(1, Action::Pad(Pad::Red)), button(3, Action::Pad(Pad::YellowTom)),
+                        button(2, Action::Pad(Pad::BlueTom)), button(0, Action::Pad(Pad::GreenTom)),
+                        button(4, Action::Pad(Pad::Kick)), button(6, Action::StarPower),
+                    ],
+                },
+            ],
+        }
+    }
+}
+
+impl Bindings {
+    /// Replaces the device's bindings for the part, or adds them
+    pub fn set(self: &mut Self, device_bindings: DeviceBindings) {
+        match self.devices.iter_mut().find(|existing| existing.kind == device_bindings.kind && existing.device == device_bindings.device) {
+            Some(existing) => *existing = device_bindings,
+            None => self.devices.push(device_bindings),
+        }
+    }
+
+    /// The action an input plays, using the bindings of the first of devices that has any for the part.
+    /// A joystick's devices are its GUID, its name, and Device::Joystick.
+    pub fn lookup(self: &Self, kind: BindingKind, devices: &[Device], input: &Input) -> Option<(Action, Press)> {
+        let device_bindings = devices.iter()
+            .filter_map(|device| self.devices.iter().find(|existing| existing.kind == kind && existing.device == *device))
+            .next()?;
+
+        device_bindings.bindings.iter()
+            .filter_map(|binding| binding.press(input).map(|press| (binding.action, press)))
+            .next()
+    }
+
+    pub fn write(self: &Self) -> String {
+        let mut lines = Vec::new();
+        for device_bindings in &self.devices {
+            let kind = device_bindings.kind.name();
+            lines.push(match &device_bindings.device {
+                Device::Keyboard => format!("device {} keyboard", kind),
+                Device::Joystick => format!("device {} joystick", kind),
+                Device::Guid(guid) => format!("device {} guid {}", kind, guid),
+                Device::Name(name) => format!("device {} name {}", kind, name),
+            });
+            lines.extend(device_bindings.bindings.iter().map(|binding| {
+                let action = binding.action.name();
+                match &binding.control {
+                    Control::Key(name) => format!("{} key {}", action, name),
+                    Control::Button(button_idx) => format!("{} button {}", action, button_idx),
+                    Control::Hat(hat_idx, direction) => format!("{} hat {} {}", action, hat_idx, direction.name()),
+                    Control::Axis(calibration) => format!("{} axis {} {} {} {}",
+                        action, calibration.axis_idx, calibration.rest, calibration.full, calibration.dead_zone),
+                }
+            }));
+            lines.push(String::new());
+        }
+        lines.join("\n")
+    }
+}
+
+/// Reads bindings written by Bindings::write.
+/// The devices and parts the file has bindings for replace the defaults; the rest keep them.
+pub fn read(contents: &str) -> Result<Bindings, BindingsParseError> {
+    let mut bindings = Bindings::default();
+    let mut current: Option<DeviceBindings> = None;
+
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let parts: Vec<&str> = line.split(' ').collect();
+        let field = |i: usize| parts.get(i).map(|s| *s).ok_or_else(|| BindingsParseError::MissingField);
+        // names can have spaces in them, so they run to the end of the line
+        let rest = |i: usize| if parts.len() > i { Ok(parts[i..].join(" ")) } else { Err(BindingsParseError::MissingField) };
+
+        if parts[0] == "device" {
+            let kind = BindingKind::from_name(field(1)?)
+                .ok_or_else(|| BindingsParseError::UnknownKind(parts[1].to_string()))?;
+            let device = match field(2)? {
+                "keyboard" => Device::Keyboard,
+                "joystick" => Device::Joystick,
+                "guid" => Device::Guid(field(3)?.to_string()),
+                "name" => Device::Name(rest(3)?),
+                other => return Err(BindingsParseError::UnknownDevice(other.to_string())),
+            };
+            if let Some(finished) = current.take() {
+                bindings.set(finished);
+            }
+            current = Some(DeviceBindings { kind: kind, device: device, bindings: Vec::new() });
+            continue;
+        }
+
+        let action = Action::from_name(parts[0])
+            .ok_or_else(|| BindingsParseError::UnknownAction(parts[0].to_string()))?;
+        let control = match field(1)? {
+            "key" => Control::Key(rest(2)?),
+            "button" => Control::Button(field(2)?.parse()?),
+            "hat" => Control::Hat(field(2)?.parse()?, HatDirection::from_name(field(3)?)
+                .ok_or_else(|| BindingsParseError::UnknownControl(parts[3].to_string()))?),
+            "axis" => {
+                let calibration = AxisCalibration {
+                    axis_idx: field(2)?.parse()?,
+                    rest: field(3)?.parse()?,
+                    full: field(4)?.parse()?,
+                    dead_zone: field(5)?.parse()?,
+                };
+                // normalize divides by the travel, and by what's left of it past the dead zone
+                if calibration.rest == calibration.full || !(calibration.dead_zone >= 0f32 && calibration.dead_zone < 1f32) {
+                    return Err(BindingsParseError::InvalidAxis(calibration.axis_idx));
+                }
+                Control::Axis(calibration)
+            },
+            other => return Err(BindingsParseError::UnknownControl(other.to_string())),
+        };
+        current.as_mut()
+            .ok_or_else(|| BindingsParseError::BindingOutsideDevice)?
+            .bindings.push(Binding { control: control, action: action });
+    }
+
+    if let Some(finished) = current.take() {
+        bindings.set(finished);
+    }
+    Ok(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_round_trip() {
+        let defaults = Bindings::default();
+        assert_eq!(read(&defaults.write()).unwrap().devices, defaults.devices);
+    }
+
+    #[test]
+    fn file_replaces_only_the_devices_it_has() {
+        let contents = "device guitar name Guitar Hero Controller\n\
+            green button 7\n\
+            strum hat 0 down\n\
+            whammy axis 2 -32768 32767 0.25\n\
+            \n\
+            device guitar keyboard\n\
+            green key Left Shift\n";
+        let bindings = read(contents).unwrap();
+
+        let named = bindings.devices.iter().find(|device| device.device == Device::Name(String::from("Guitar Hero Controller"))).unwrap();
+        assert_eq!(named.bindings, vec![
+            button(7, Action::Green),
+            Binding { control: Control::Hat(0, HatDirection::Down), action: Action::Strum },
+            Binding { control: Control::Axis(AxisCalibration { axis_idx: 2, rest: -32768, full: 32767, dead_zone: 0.25 }), action: Action::Whammy },
+        ]);
+        assert_eq!(bindings.lookup(BindingKind::Guitar, &[Device::Keyboard], &Input::KeyDown(String::from("Left Shift"))), Some((Action::Green, Press::Down)));
+        assert_eq!(bindings.lookup(BindingKind::Guitar, &[Device::Keyboard], &Input::KeyDown(String::from("Z"))), None);
+        // the other parts keep their defaults
+        assert_eq!(bindings.lookup(BindingKind::Drums, &[Device::Keyboard], &Input::KeyDown(String::from("Z"))), Some((Action::Pad(Pad::Red), Press::Down)));
+        assert_eq!(read(&bindings.write()).unwrap().devices, bindings.devices);
+    }
+
+    #[test]
+    fn joystick_uses_its_own_bindings_first() {
+        let bindings = read("device guitar guid 0300abcd\ngreen button 9\n").unwrap();
+        let devices = [Device::Guid(String::from("0300abcd")), Device::Name(String::from("Guitar")), Device::Joystick];
+        assert_eq!(bindings.lookup(BindingKind::Guitar, &devices, &Input::ButtonDown(9)), Some((Action::Green, Press::Down)));
+        assert_eq!(bindings.lookup(BindingKind::Guitar, &devices, &Input::ButtonDown(0)), None);
+        assert_eq!(bindings.lookup(BindingKind::Guitar, &devices[1..], &Input::ButtonDown(0)), Some((Action::Green, Press::Down)));
+    }
+
+    #[test]
+    fn axis_normalizes_past_the_dead_zone() {
+        let calibration = AxisCalibration { axis_idx: 0, rest: 0, full: -1000, dead_zone: 0.5 };
+        assert_eq!(calibration.normalize(-200), 0.0);
+        assert_eq!(calibration.normalize(-750), 0.5);
+        assert_eq!(calibration.normalize(-2000), 1.0);
+    }
+
+    #[test]
+    fn tilt_reads_sooner_than_the_whammy_bar() {
+        let whammy = AxisCalibration::new(Action::Whammy, 3, 0, 10000);
+        let tilt = AxisCalibration::new(Action::Tilt, 3, 0, 10000);
+        assert_eq!(whammy.normalize(800), 0.0);
+        assert!(tilt.normalize(800) > 0.0);
+    }
+
+    #[test]
+    fn invalid_axes_are_rejected() {
+        for axis in &["whammy axis 3 100 100 0.1", "whammy axis 3 0 32767 1", "whammy axis 3 0 32767 -0.1", "whammy axis 3 0 32767 NaN"] {
+            match read(&format!("device guitar joystick\n{}\n", axis)) {
+                Err(BindingsParseError::InvalidAxis(3)) => (),
+                other => panic!("{}: {:?}", axis, other.map(|bindings| bindings.devices)),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |contents: &str| read(contents).err().map(|e| format!("{:?}", e)).unwrap_or_default();
+        assert!(error("device bass keyboard\n").starts_with("UnknownKind"));
+        assert!(error("device guitar mouse\n").starts_with("UnknownDevice"));
+        assert!(error("device guitar keyboard\npurple key Z\n").starts_with("UnknownAction"));
+        assert!(error("device guitar keyboard\ngreen pedal 1\n").starts_with("UnknownControl"));
+        assert!(error("device guitar joystick\nstrum hat 0 sideways\n").starts_with("UnknownControl"));
+        assert!(error("device guitar joystick\ngreen button x\n").starts_with("ParseIntError"));
+        assert!(error("device guitar joystick\ngreen button\n").starts_with("MissingField"));
+        assert!(error("green key Z\n").starts_with("BindingOutsideDevice"));
+    }
+}
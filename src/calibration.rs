@@ -0,0 +1,178 @@
+use crate::audio::Pcm;
+use crate::latency::Latency;
+
+/// Tempo of the metronome, slow enough to tap along to comfortably
+pub const CALIBRATION_BPM: f32 = 100.0;
+/// Beats to settle into the tempo before taps are counted
+pub const COUNT_IN_BEATS: usize = 4;
+/// Beats whose taps are counted
+pub const MEASURED_BEATS: usize = 16;
+/// A phase with fewer counted taps than this doesn't measure anything
+pub const MIN_TAPS: usize = 8;
+/// Taps further than this from every beat aren't tapping along
+pub const MAX_TAP_OFFSET_MS: f32 = 200.0;
+
+const CLICK_MS: f32 = 25.0;
+const CLICK_HZ: f32 = 1500.0;
+
+pub fn beat_ms() -> f32 {
+    60000.0 / CALIBRATION_BPM
+}
+
+/// When a phase is over: the last beat, and long enough after it for a late tap
+pub fn end_ms() -> f32 {
+    (COUNT_IN_BEATS + MEASURED_BEATS - 1) as f32 * beat_ms() + MAX_TAP_OFFSET_MS
+}
+
+/// A metronome click on every beat of a phase, the first at 0 ms
+pub fn click_track(sample_rate: u32, channels: usize) -> Pcm {
+    let mut track = Pcm { channels: channels, sample_rate: sample_rate, samples: Vec::new() };
+    let frames = track.ms_to_frames(end_ms()) as usize;
+    let click_frames = track.ms_to_frames(CLICK_MS) as usize;
+    track.samples = vec![0; frames * channels];
+
+    for beat in 0..COUNT_IN_BEATS + MEASURED_BEATS {
+        let start = track.ms_to_frames(beat as f32 * beat_ms()) as usize;
+        for i in 0..click_frames {
+            // a sine burst that fades out, so it has a sharp start to tap to
+            let t = i as f32 / sample_rate as f32;
+            let fade = 1.0 - i as f32 / click_frames as f32;
+            let sample = (f32::sin(2.0 * std::f32::consts::PI * CLICK_HZ * t) * fade * 0.5 * std::i16::MAX as f32) as i16;
+            for channel in 0..channels {
+                track.samples[(start + i) * channels + channel] = sample;
+            }
+        }
+    }
+
+    track
+}
+
+/// The player's taps along to one phase's beats, as offsets from the beats they were tapping to.
+/// A late tap has a positive offset.
+pub struct TapCalibration {
+    pub offsets_ms: Vec<f32>,
+    tapped_beats: Vec<usize>,
+}
+
+impl TapCalibration {
+    pub fn new() -> TapCalibration {
+        TapCalibration {
+            offsets_ms: Vec::new(),
+            tapped_beats: Vec::new(),
+        }
+    }
+
+    /// Counts a tap at time_ms, where the phase's first beat is at 0 ms, returning its offset.
+    /// Taps during the count-in, far from a beat, or on a beat that was already tapped aren't counted.
+    pub fn tap(self: &mut Self, time_ms: f32) -> Option<f32> {
+        let beat = (time_ms / beat_ms()).round();
+        if beat < COUNT_IN_BEATS as f32 || beat >= (COUNT_IN_BEATS + MEASURED_BEATS) as f32 {
+            return None;
+        }
+        let (beat, offset_ms) = (beat as usize, time_ms - beat * beat_ms());
+        if offset_ms.abs() > MAX_TAP_OFFSET_MS || self.tapped_beats.contains(&beat) {
+            return None;
+        }
+
+        self.tapped_beats.push(beat);
+        self.offsets_ms.push(offset_ms);
+        Some(offset_ms)
+    }
+
+    pub fn mean_ms(self: &Self) -> Option<f32> {
+        if self.offsets_ms.is_empty() {
+            return None;
+        }
+        Some(self.offsets_ms.iter().sum::<f32>() / self.offsets_ms.len() as f32)
+    }
+
+    /// Standard deviation of the offsets: how steadily the player tapped
+    pub fn deviation_ms(self: &Self) -> Option<f32> {
+        self.mean_ms().map(|mean_ms| {
+            let variance = self.offsets_ms.iter().map(|offset_ms| (offset_ms - mean_ms).powi(2)).sum::<f32>() / self.offsets_ms.len() as f32;
+            variance.sqrt()
+        })
+    }
+
+    /// The mean offset, if enough beats were tapped for it to mean something
+    pub fn measured_ms(self: &Self) -> Option<f32> {
+        if self.offsets_ms.len() < MIN_TAPS { None } else { self.mean_ms() }
+    }
+}
+
+impl Default for TapCalibration {
+    fn default() -> TapCalibration {
+        TapCalibration::new()
+    }
+}
+
+/// The latency measured by tapping to clicks (audio) and then to flashes (video).
+/// A phase that wasn't tapped along to keeps its previous latency.
+///
+/// Both phases include the controller's own lag. It's left in both: judgement then takes it into account,
+/// and the drawing offset is the difference between the two.
+pub fn measured_latency(audio: &TapCalibration, video: &TapCalibration, previous: Latency) -> Latency {
+    Latency {
+        audio_ms: audio.measured_ms().unwrap_or(previous.audio_ms),
+        video_ms: video.measured_ms().unwrap_or(previous.video_ms),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // taps every measured beat from the first, offset by the given amounts in turn
+    fn tapped(offsets_ms: &[f32], taps: usize) -> TapCalibration {
+        let mut calibration = TapCalibration::new();
+        for i in 0..taps {
+            let beat = COUNT_IN_BEATS + i;
+            calibration.tap(beat as f32 * beat_ms() + offsets_ms[i % offsets_ms.len()]).unwrap();
+        }
+        calibration
+    }
+
+    #[test]
+    fn taps_count_once_per_measured_beat() {
+        // the first measured beat is at 2400 ms, and the last at 11400 ms
+        let mut calibration = TapCalibration::default();
+        assert_eq!(calibration.tap(600.0), None);
+        assert_eq!(calibration.tap(2430.0), Some(30.0));
+        assert_eq!(calibration.tap(2450.0), None);
+        assert_eq!(calibration.tap(3250.0), None);
+        assert_eq!(calibration.tap(12000.0), None);
+        assert_eq!(calibration.offsets_ms, vec![30.0]);
+    }
+
+    #[test]
+    fn early_taps_count_for_the_beat_after_them() {
+        let mut calibration = TapCalibration::new();
+        // just before the first measured beat, and the count-in's last beat
+        assert_eq!(calibration.tap(2360.0), Some(-40.0));
+        assert_eq!(calibration.tap(1900.0), None);
+        // just before a later beat, rather than long after the one before it
+        assert_eq!(calibration.tap(2990.0), Some(-10.0));
+        assert_eq!(calibration.mean_ms(), Some(-25.0));
+    }
+
+    #[test]
+    fn mean_and_deviation_of_the_offsets() {
+        assert_eq!(TapCalibration::new().mean_ms(), None);
+        assert_eq!(TapCalibration::new().deviation_ms(), None);
+
+        let calibration = tapped(&[-10.0, 10.0, 30.0, 50.0], 4);
+        assert_eq!(calibration.mean_ms(), Some(20.0));
+        assert!((calibration.deviation_ms().unwrap() - 500f32.sqrt()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn too_few_taps_keep_the_previous_latency() {
+        let previous = Latency { audio_ms: 5.0, video_ms: 15.0 };
+
+        let latency = measured_latency(&tapped(&[40.0], MIN_TAPS), &tapped(&[70.0], MIN_TAPS - 1), previous);
+        assert_eq!((latency.audio_ms, latency.video_ms), (40.0, 15.0));
+
+        let latency = measured_latency(&TapCalibration::new(), &tapped(&[0.0, 40.0], MEASURED_BEATS), previous);
+        assert_eq!((latency.audio_ms, latency.video_ms), (5.0, 20.0));
+    }
+}
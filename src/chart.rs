@@ -2,6 +2,7 @@ extern crate regex;
 use std::collections::HashMap;
 use regex::Regex;
 
+#[derive(Clone)]
 pub struct SongStreams {
     pub music: Option<String>,
     pub guitar: Option<String>,
@@ -10,18 +11,29 @@ pub struct SongStreams {
     pub drum: Option<String>,
 }
 
+#[derive(Clone)]
 pub enum SongPlayer2 {
     Bass,
     Rhythm,
 }
 
+impl SongPlayer2 {
+    pub fn instrument(self: &Self) -> Instrument {
+        match self {
+            SongPlayer2::Bass => Instrument::Bass,
+            SongPlayer2::Rhythm => Instrument::Rhythm,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Song {
     pub name: Option<String>,
     pub artist: Option<String>,
     pub charter: Option<String>,
     pub album: Option<String>,
     pub year: Option<String>,
-    pub offset: Option<u64>,
+    pub offset: Option<f32>, // seconds into the audio at which the chart starts
     pub resolution: u64,
     pub player2: Option<SongPlayer2>,
     pub difficulty: Option<u64>,
@@ -32,13 +44,16 @@ pub struct Song {
     pub streams: SongStreams,
 }
 
+#[derive(Clone)]
 pub enum SyncTrack {
     TimeSignature { ticks: u64, upper: u64, lower: u64 },
     BeatsPerMinute { ticks: u64, bpm1000: u64 },
 }
 
+#[derive(Clone)]
 pub enum Event {
     Section { ticks: u64, name: String },
+    End { ticks: u64 },
     // TODO: support additional event types
     // Rationale for not passing strings:
     // The consumer of this chart library is the game.
@@ -51,17 +66,22 @@ pub enum Event {
     // i.e. specific version with set list of features
 }
 
+#[derive(Clone)]
 pub enum SpecialEvent {
     // type 2: boost / star power / overdrive
     StarPower { ticks: u64, duration: u64 },
+    // type 64: drum fill / star power activation
+    DrumFill { ticks: u64, duration: u64 },
 }
 
+#[derive(Clone)]
 pub struct Note {
     pub ticks: u64,
     pub note: u64,
     pub duration: u64,
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Instrument {
     Guitar,
     GuitarCoop,
@@ -80,6 +100,7 @@ pub enum Instrument {
     // Harmony3,
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Difficulty {
     Easy,
     Medium,
@@ -87,6 +108,71 @@ pub enum Difficulty {
     Expert,
 }
 
+impl Instrument {
+    /// Whether the instrument is played with the five frets of a guitar controller.
+    pub fn is_five_fret(self: &Self) -> bool {
+        match self {
+            Instrument::Guitar | Instrument::GuitarCoop | Instrument::Bass | Instrument::Rhythm | Instrument::Keyboard => true,
+            _ => false,
+        }
+    }
+
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            Instrument::Guitar => "guitar",
+            Instrument::GuitarCoop => "coop",
+            Instrument::Bass => "bass",
+            Instrument::Rhythm => "rhythm",
+            Instrument::GHLGuitar => "ghl_guitar",
+            Instrument::GHLBass => "ghl_bass",
+            Instrument::Drums => "drums",
+            Instrument::Keyboard => "keys",
+            Instrument::RealBass => "real_bass",
+            Instrument::RealGuitar => "real_guitar",
+            Instrument::RealKeys => "real_keys",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Instrument> {
+        match name {
+            "guitar" => Some(Instrument::Guitar),
+            "coop" => Some(Instrument::GuitarCoop),
+            "bass" => Some(Instrument::Bass),
+            "rhythm" => Some(Instrument::Rhythm),
+            "ghl_guitar" => Some(Instrument::GHLGuitar),
+            "ghl_bass" => Some(Instrument::GHLBass),
+            "drums" => Some(Instrument::Drums),
+            "keys" => Some(Instrument::Keyboard),
+            "real_bass" => Some(Instrument::RealBass),
+            "real_guitar" => Some(Instrument::RealGuitar),
+            "real_keys" => Some(Instrument::RealKeys),
+            _ => None,
+        }
+    }
+}
+
+impl Difficulty {
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+            Difficulty::Expert => "expert",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Difficulty> {
+        match name {
+            "easy" => Some(Difficulty::Easy),
+            "medium" => Some(Difficulty::Medium),
+            "hard" => Some(Difficulty::Hard),
+            "expert" => Some(Difficulty::Expert),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Part {
     pub instrument: Instrument,
     pub difficulty: Difficulty,
@@ -94,6 +180,7 @@ pub struct Part {
     pub special_events: Vec<SpecialEvent>,
 }
 
+#[derive(Clone)]
 pub struct Chart {
     pub song: Song,
     pub sync_track: Vec<SyncTrack>,
@@ -101,6 +188,33 @@ pub struct Chart {
     pub parts: Vec<Part>,
 }
 
+impl Chart {
+    /// Tick of the chart's end event, if it has one
+    pub fn end_ticks(self: &Self) -> Option<u64> {
+        self.events.iter().filter_map(|event| {
+            match event {
+                Event::End { ticks } => Some(*ticks),
+                Event::Section { .. } => None,
+            }
+        }).nth(0)
+    }
+
+    pub fn has_part(self: &Self, instrument: Instrument, difficulty: Difficulty) -> bool {
+        self.parts.iter().any(|part| part.instrument == instrument && part.difficulty == difficulty)
+    }
+
+    /// The part player 2 plays in co-op, while player 1 plays lead guitar: the song's `player2` part
+    /// (bass or rhythm), or the co-op guitar part if the song names none or the chart doesn't have it.
+    /// None if the chart has neither.
+    pub fn coop_instrument(self: &Self, difficulty: Difficulty) -> Option<Instrument> {
+        match self.song.player2.as_ref().map(|player2| player2.instrument()) {
+            Some(instrument) if self.has_part(instrument, difficulty) => Some(instrument),
+            _ if self.has_part(Instrument::GuitarCoop, difficulty) => Some(Instrument::GuitarCoop),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum SongError {
     ParseIntError(std::num::ParseIntError),
@@ -151,6 +265,8 @@ pub enum PartError {
     ParseIntError(std::num::ParseIntError),
     NMissingNote,
     NMissingDuration,
+    SMissingType,
+    SMissingDuration,
 }
 
 impl std::convert::From<std::num::ParseIntError> for PartError {
@@ -230,7 +346,7 @@ fn build_song<'a>(song_entries: impl Iterator<Item = (&'a str, &'a str)>) -> Res
         charter:       take_quoted(&mut fields, "charter"),
         album:         take_quoted(&mut fields, "album"),
         year:          take_quoted(&mut fields, "year").map(cleaned_year),
-        offset:           take_int(&mut fields, "offset")?,
+        offset:         take_float(&mut fields, "offset")?,
         resolution:       take_int(&mut fields, "resolution")?.ok_or_else(|| SongError::MissingResolution)?,
         player2:              take(&mut fields, "player2").and_then(parse_player_2),
         difficulty:       take_int(&mut fields, "difficulty")?,
@@ -297,6 +413,9 @@ fn build_events<'a>(entries: impl Iterator<Item = (&'a str, &'a str)>) -> Result
                     ticks: key.parse::<u64>()?,
                     name: event_param.map(|n| n.to_string()).ok_or_else(|| EventError::ESectionMissingSectionName)?,
                 })),
+                (Some("E"), Some("end")) => Ok(Some(Event::End {
+                    ticks: key.parse::<u64>()?,
+                })),
                 // Ignore unknown event types
                 (_, _) => Ok(None),
             }
@@ -305,16 +424,24 @@ fn build_events<'a>(entries: impl Iterator<Item = (&'a str, &'a str)>) -> Result
         .collect();
 }
 
-fn build_part<'a>(name: &'a str, entries: impl Iterator<Item = (&'a str, &'a str)>) -> Result<Part, ChartParseError> {
+fn build_part<'a>(name: &'a str, entries: impl Iterator<Item = (&'a str, &'a str)>) -> Result<Part, PartError> {
     let (instrument, difficulty) = match name {
         "ExpertSingle" => (Instrument::Guitar, Difficulty::Expert),
         "HardSingle" => (Instrument::Guitar, Difficulty::Hard),
         "MediumSingle" => (Instrument::Guitar, Difficulty::Medium),
         "EasySingle" => (Instrument::Guitar, Difficulty::Easy),
+        "ExpertDoubleGuitar" => (Instrument::GuitarCoop, Difficulty::Expert),
+        "HardDoubleGuitar" => (Instrument::GuitarCoop, Difficulty::Hard),
+        "MediumDoubleGuitar" => (Instrument::GuitarCoop, Difficulty::Medium),
+        "EasyDoubleGuitar" => (Instrument::GuitarCoop, Difficulty::Easy),
         "ExpertDoubleBass" => (Instrument::Bass, Difficulty::Expert),
         "HardDoubleBass" => (Instrument::Bass, Difficulty::Hard),
         "MediumDoubleBass" => (Instrument::Bass, Difficulty::Medium),
         "EasyDoubleBass" => (Instrument::Bass, Difficulty::Easy),
+        "ExpertDoubleRhythm" => (Instrument::Rhythm, Difficulty::Expert),
+        "HardDoubleRhythm" => (Instrument::Rhythm, Difficulty::Hard),
+        "MediumDoubleRhythm" => (Instrument::Rhythm, Difficulty::Medium),
+        "EasyDoubleRhythm" => (Instrument::Rhythm, Difficulty::Easy),
         "ExpertKeyboard" => (Instrument::Keyboard, Difficulty::Expert),
         "HardKeyboard" => (Instrument::Keyboard, Difficulty::Hard),
         "MediumKeyboard" => (Instrument::Keyboard, Difficulty::Medium),
@@ -323,6 +450,14 @@ fn build_part<'a>(name: &'a str, entries: impl Iterator<Item = (&'a str, &'a str
         "HardDrums" => (Instrument::Drums, Difficulty::Hard),
         "MediumDrums" => (Instrument::Drums, Difficulty::Medium),
         "EasyDrums" => (Instrument::Drums, Difficulty::Easy),
+        "ExpertGHLGuitar" => (Instrument::GHLGuitar, Difficulty::Expert),
+        "HardGHLGuitar" => (Instrument::GHLGuitar, Difficulty::Hard),
+        "MediumGHLGuitar" => (Instrument::GHLGuitar, Difficulty::Medium),
+        "EasyGHLGuitar" => (Instrument::GHLGuitar, Difficulty::Easy),
+        "ExpertGHLBass" => (Instrument::GHLBass, Difficulty::Expert),
+        "HardGHLBass" => (Instrument::GHLBass, Difficulty::Hard),
+        "MediumGHLBass" => (Instrument::GHLBass, Difficulty::Medium),
+        "EasyGHLBass" => (Instrument::GHLBass, Difficulty::Easy),
         "PART REAL_GUITAR" => (Instrument::RealGuitar, Difficulty::Expert),
         "PART REAL_BASS" => (Instrument::RealBass, Difficulty::Expert),
         "PART REAL_KEYS_X" => (Instrument::RealKeys, Difficulty::Expert),
@@ -333,31 +468,42 @@ fn build_part<'a>(name: &'a str, entries: impl Iterator<Item = (&'a str, &'a str
         _ => (Instrument::Guitar, Difficulty::Expert),
     };
 
-    let notes: Result<Vec<Note>, PartError> = entries
-        .map(|(key, value)| -> Result<Option<Note>, PartError> {
-            let parts: Vec<&str> = value.split(' ').collect();
-            match parts.first().map(|s| s.as_ref()) {
-                Some("N") => Ok(Some(Note {
+    let mut notes: Vec<Note> = Vec::new();
+    let mut special_events: Vec<SpecialEvent> = Vec::new();
+
+    for (key, value) in entries {
+        let parts: Vec<&str> = value.split(' ').collect();
+        match parts.first().map(|s| s.as_ref()) {
+            Some("N") => notes.push(Note {
+                ticks: key.parse::<u64>()?,
+                note: parts.get(1).ok_or_else(|| PartError::NMissingNote)?.parse::<u64>()?,
+                duration: parts.get(2).ok_or_else(|| PartError::NMissingDuration)?.parse::<u64>()?,
+            }),
+            // Feedback .chart files have several types of special event,
+            // https://github.com/FeedBackDevs/feedback/blob/534d90f266/src/db/chart/event.d#L29
+            // while Moonscraper .chart files only have star power (type 2) and drum fills (type 64).
+            Some("S") => match parts.get(1).ok_or_else(|| PartError::SMissingType)?.parse::<u64>()? {
+                2 => special_events.push(SpecialEvent::StarPower {
                     ticks: key.parse::<u64>()?,
-                    note: parts.get(1).ok_or_else(|| PartError::NMissingNote)?.parse::<u64>()?,
-                    duration: parts.get(2).ok_or_else(|| PartError::NMissingDuration)?.parse::<u64>()?,
-                })),
-                // TODO: handle star power
-                // Ignore unknown event types
-                _ => Ok(None),
-            }
-        })
-        .filter_map(|s| s.map_or_else(|e| Some(Err(e)), |s| s.map(Ok)))
-        .collect();
+                    duration: parts.get(2).ok_or_else(|| PartError::SMissingDuration)?.parse::<u64>()?,
+                }),
+                64 => special_events.push(SpecialEvent::DrumFill {
+                    ticks: key.parse::<u64>()?,
+                    duration: parts.get(2).ok_or_else(|| PartError::SMissingDuration)?.parse::<u64>()?,
+                }),
+                // Ignore unknown special event types
+                _ => (),
+            },
+            // Ignore unknown event types
+            _ => (),
+        }
+    }
 
-    // Feedback .chart files have several types of special event,
-    // https://github.com/FeedBackDevs/feedback/blob/534d90f266/src/db/chart/event.d#L29
-    // while Moonscraper .chart files only have star power (type 2).
     Ok(Part {
         instrument: instrument,
         difficulty: difficulty,
-        notes: notes?,
-        special_events: vec![], // TODO
+        notes: notes,
+        special_events: special_events,
     })
 }
 
@@ -410,3 +556,43 @@ pub fn read(contents: &str) -> Result<Chart, ChartParseError> {
         parts: parts,
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chart_with(player2: &str, parts: &[&str]) -> Chart {
+        let parts: String = parts.iter().map(|part| format!("[{}]\n{{\n  192 = N 0 0\n}}\n", part)).collect();
+        read(&format!("[Song]\n{{\n  Resolution = 192\n{}}}\n[SyncTrack]\n{{\n  0 = B 120000\n}}\n{}", player2, parts)).unwrap()
+    }
+
+    #[test]
+    fn coop_plays_the_songs_player2_part() {
+        let chart = chart_with("  Player2 = bass\n", &["ExpertSingle", "ExpertDoubleBass", "ExpertDoubleGuitar"]);
+        assert_eq!(chart.coop_instrument(Difficulty::Expert), Some(Instrument::Bass));
+    }
+
+    #[test]
+    fn coop_falls_back_to_coop_guitar() {
+        let chart = chart_with("  Player2 = rhythm\n", &["ExpertSingle", "ExpertDoubleGuitar"]);
+        assert_eq!(chart.coop_instrument(Difficulty::Expert), Some(Instrument::GuitarCoop));
+
+        let chart = chart_with("", &["ExpertSingle", "ExpertDoubleGuitar"]);
+        assert_eq!(chart.coop_instrument(Difficulty::Expert), Some(Instrument::GuitarCoop));
+    }
+
+    #[test]
+    fn six_fret_parts_keep_their_black_3_notes() {
+        let chart = read("[Song]\n{\n  Resolution = 192\n}\n[SyncTrack]\n{\n  0 = B 120000\n}\n[ExpertGHLGuitar]\n{\n  192 = N 8 0\n  384 = N 4 96\n}\n").unwrap();
+        let part = &chart.parts[0];
+        assert_eq!((part.instrument, part.difficulty), (Instrument::GHLGuitar, Difficulty::Expert));
+        let notes: Vec<(u64, u64, u64)> = part.notes.iter().map(|note| (note.ticks, note.note, note.duration)).collect();
+        assert_eq!(notes, vec![(192, 8, 0), (384, 4, 96)]);
+    }
+
+    #[test]
+    fn coop_needs_a_second_part() {
+        let chart = chart_with("  Player2 = bass\n", &["ExpertSingle", "HardDoubleBass"]);
+        assert_eq!(chart.coop_instrument(Difficulty::Expert), None);
+    }
+}
@@ -0,0 +1,893 @@
+use crate::chart;
+use crate::guitarplaythrough::{HitWindow, RockMeterSettings, Section, SectionStats, StarPowerPhrase, TempoMap, TimingGrade, TimingStats};
+use crate::playthrough::{NoteJudgement, Playthrough, PlaythroughEffect, PlaythroughState};
+
+/* Yellow, blue and green have a tom and a cymbal, which are only told apart in pro mode */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Pad {
+	Kick,
+	Red,
+	YellowTom,
+	YellowCymbal,
+	BlueTom,
+	BlueCymbal,
+	GreenTom,
+	GreenCymbal,
+}
+
+impl Pad {
+	/* What the pad counts as outside of pro mode */
+	pub fn tom(self: &Self) -> Pad {
+		match self {
+			Pad::YellowCymbal => Pad::YellowTom,
+			Pad::BlueCymbal => Pad::BlueTom,
+			Pad::GreenCymbal => Pad::GreenTom,
+			pad => *pad,
+		}
+	}
+
+	pub fn is_cymbal(self: &Self) -> bool {
+		*self != self.tom()
+	}
+}
+
+/* One gem. The gems of a chord are separate notes at the same tick, each hit by its own pad. */
+pub struct DrumNote {
+	pub ticks: u64,
+	pub pad: Pad,
+	/* Index into DrumChart.sp_phrases of the phrase containing this note */
+	pub sp_phrase: Option<usize>,
+	/* Index into DrumChart.fills of the fill containing this note */
+	pub fill: Option<usize>,
+	/* Index into DrumChart.sections of the section containing this note */
+	pub section: Option<usize>,
+}
+
+/* A drum fill. While star power can be activated, its notes are played freestyle
+ * and hitting its last note activates star power. */
+pub struct DrumFill {
+	pub ticks: u64,
+	pub duration: u64,
+	/* Tick of the fill's last note, if it has any */
+	pub finale_ticks: Option<u64>,
+}
+
+impl DrumFill {
+	pub fn contains(self: &Self, ticks: u64) -> bool {
+		ticks == self.ticks || (ticks > self.ticks && ticks < self.ticks + self.duration)
+	}
+}
+
+pub struct DrumChart {
+	/* Position in the audio of time 0 in the chart */
+	pub audio_offset_ms: f32,
+	pub tempo_map: TempoMap,
+	/* Vector of notes sorted by their tick */
+	pub notes: std::vec::Vec<DrumNote>,
+	/* Vector of star power phrases sorted by their tick */
+	pub sp_phrases: std::vec::Vec<StarPowerPhrase>,
+	/* Vector of drum fills sorted by their tick */
+	pub fills: std::vec::Vec<DrumFill>,
+	/* Vector of sections sorted by their tick */
+	pub sections: std::vec::Vec<Section>,
+	/* Tick of the chart's end event, if it has one */
+	pub end_ticks: Option<u64>,
+}
+
+impl DrumChart {
+	pub fn ticks_to_ms(self: &Self, ticks: u64) -> f32 {
+		self.tempo_map.ticks_to_ms(ticks)
+	}
+
+	pub fn ms_to_beats(self: &Self, time_ms: f32) -> f32 {
+		self.tempo_map.ms_to_beats(time_ms)
+	}
+
+	/* Time at which the chart is over: its end event, or else its last note */
+	pub fn end_ms(self: &Self) -> f32 {
+		match self.end_ticks {
+			Some(ticks) => self.ticks_to_ms(ticks),
+			None => self.notes.last().map_or(0f32, |note| self.ticks_to_ms(note.ticks)),
+		}
+	}
+
+	/* Index of the last section starting at or before the given tick */
+	pub fn section_at_ticks(self: &Self, ticks: u64) -> Option<usize> {
+		self.sections.iter().rposition(|section| section.ticks <= ticks)
+	}
+
+	pub fn section_at_ms(self: &Self, time_ms: f32) -> Option<usize> {
+		let beats = self.ms_to_beats(time_ms);
+		if beats < 0.0 {
+			return None;
+		}
+		self.section_at_ticks(self.tempo_map.beats_to_ticks(beats))
+	}
+
+	fn is_fill_finale(self: &Self, note: &DrumNote) -> bool {
+		note.fill.map_or(false, |fill| self.fills[fill].finale_ticks == Some(note.ticks))
+	}
+}
+
+#[derive(Copy, Clone)]
+pub struct DrumSettings {
+	pub hit_window: HitWindow,
+	/* Pro drums: cymbals and toms of the same color are different pads */
+	pub pro: bool,
+	/* Play the second kick pedal notes of Expert+ charts */
+	pub double_kick: bool,
+	/* How far the song goes back when resuming from pause */
+	pub pause_rewind_ms: f32,
+	/* How long before the paused point a countdown is shown when resuming */
+	pub resume_countdown_ms: f32,
+	/* How long the song clock runs before the chart starts, so notes at tick 0 can scroll in */
+	pub lead_in_ms: f32,
+	/* overstrum_loss is lost per overhit */
+	pub rock_meter: RockMeterSettings,
+	/* The rock meter still moves, but the song can't be failed */
+	pub no_fail: bool,
+	/* How long the audio takes to be heard once it is played. Inputs are judged this much earlier,
+	 * against the notes the player heard rather than the ones being played. */
+	pub audio_latency_ms: f32,
+}
+
+impl DrumSettings {
+	pub fn for_difficulty(difficulty: &chart::Difficulty) -> DrumSettings {
+		DrumSettings {
+			hit_window: HitWindow::for_difficulty(difficulty),
+			pro: false,
+			double_kick: false,
+			pause_rewind_ms: 3000.0,
+			resume_countdown_ms: 3000.0,
+			lead_in_ms: 2000.0,
+			rock_meter: RockMeterSettings::default(),
+			no_fail: false,
+			audio_latency_ms: 0.0,
+		}
+	}
+}
+
+/* Final statistics of a drum playthrough */
+#[derive(Clone, PartialEq, Debug)]
+pub struct DrumResults {
+	pub score: u64,
+	pub notes_hit: u64,
+	pub notes_missed: u64,
+	/* Notes that were hit or missed, leaving out the ones played freestyle */
+	pub notes_total: u64,
+	pub notes_freestyle: u64,
+	pub best_streak: u64,
+	pub sp_phrases: u64,
+	pub failed: bool,
+	pub timing: TimingStats,
+	pub sections: Vec<SectionStats>,
+	/* Pads hit on no note */
+	pub overhits: u64,
+	/* Overhits, one entry per section */
+	pub section_overhits: Vec<u64>,
+}
+
+impl DrumResults {
+	pub fn accuracy(self: &Self) -> f32 {
+		if self.notes_total == 0 { 1.0 } else { self.notes_hit as f32 / self.notes_total as f32 }
+	}
+}
+
+pub struct DrumPlaythrough {
+	pub difficulty: chart::Difficulty,
+	pub chart: DrumChart,
+	pub settings: DrumSettings,
+	/* Score, statistics, clock and meters. Inputs before the clock is judging are ignored. */
+	pub state: PlaythroughState,
+	/* Notes of fills that were played freestyle, which are neither hit nor missed */
+	pub notes_freestyle: u64,
+	/* Pads hit on no note */
+	pub overhits: u64,
+	/* One entry per DrumChart.sections */
+	pub section_overhits: Vec<u64>,
+
+	// state
+	/* Index into DrumChart.fills of the fill being played freestyle */
+	pub active_fill: Option<usize>,
+	resolved: Vec<bool>, // one per note: hit, missed, played freestyle or skipped by a seek
+	first_unresolved_index: usize,
+	next_fill_index: usize, // the first fill that hasn't started yet
+	sp_phrase_notes: Vec<u64>, // one per phrase: how many notes it has
+	sp_phrase_hits: Vec<u64>, // one per phrase
+	sp_phrase_broken: Vec<bool>, // one per phrase
+}
+
+// Notes in .chart drum parts
+//  - 0 is the kick, 1 to 4 are red, yellow, blue and green (4-lane) or orange (5-lane)
+//  - 5 is green in 5-lane charts; they are played on four lanes, so orange and green are both green
+//  - 32 is the second kick of Expert+ charts
+//  - 66, 67 and 68 mark the yellow, blue and green note at the same tick as a cymbal
+fn drum_notes(part: &chart::Part, settings: &DrumSettings) -> Vec<DrumNote> {
+	let cymbal_markers: std::collections::HashSet<(u64, u64)> = part.notes.iter()
+		.filter(|note| note.note >= 66 && note.note <= 68)
+		.map(|note| (note.ticks, note.note))
+		.collect();
+	let is_cymbal = |ticks: u64, marker: u64| cymbal_markers.contains(&(ticks, marker));
+
+	let mut notes: Vec<DrumNote> = part.notes.iter().filter_map(|note| {
+		let pad = match note.note {
+			0 => Some(Pad::Kick),
+			32 if settings.double_kick => Some(Pad::Kick),
+			1 => Some(Pad::Red),
+			2 => Some(if is_cymbal(note.ticks, 66) { Pad::YellowCymbal } else { Pad::YellowTom }),
+			3 => Some(if is_cymbal(note.ticks, 67) { Pad::BlueCymbal } else { Pad::BlueTom }),
+			4 | 5 => Some(if is_cymbal(note.ticks, 68) { Pad::GreenCymbal } else { Pad::GreenTom }),
+			_ => None, // TODO: accents and ghost notes
+		};
+
+		pad.map(|pad| DrumNote {
+			ticks: note.ticks,
+			pad: pad,
+			sp_phrase: None,
+			fill: None,
+			section: None,
+		})
+	}).collect();
+
+	notes.sort_by_key(|note| (note.ticks, note.pad as usize));
+	notes.dedup_by(|a, b| a.ticks == b.ticks && a.pad == b.pad);
+	notes
+}
+
+impl DrumPlaythrough {
+	pub fn new(chart: chart::Chart, difficulty: chart::Difficulty, settings: DrumSettings) -> Result<DrumPlaythrough, &'static str> {
+		let tempo_map = TempoMap::new(&chart)?;
+
+		let part = chart.parts
+			.iter()
+			.filter(|part| part.instrument == chart::Instrument::Drums && part.difficulty == difficulty)
+			.nth(0)
+			.ok_or_else(|| "part not found in chart")?;
+
+		let mut fills: Vec<DrumFill> = part.special_events.iter().filter_map(|event| {
+			match event {
+				chart::SpecialEvent::DrumFill { ticks, duration } => Some(DrumFill { ticks: *ticks, duration: *duration, finale_ticks: None }),
+				chart::SpecialEvent::StarPower { .. } => None,
+			}
+		}).collect();
+		fills.sort_by_key(|fill| fill.ticks);
+
+		let mut drum_chart = DrumChart {
+			audio_offset_ms: chart.song.offset.unwrap_or(0.0) * 1000f32,
+			tempo_map: tempo_map,
+			notes: drum_notes(part, &settings),
+			sp_phrases: StarPowerPhrase::from_part(part),
+			fills: fills,
+			sections: Section::from_chart(&chart),
+			end_ticks: chart.end_ticks(),
+		};
+
+		for index in 0..drum_chart.notes.len() {
+			let ticks = drum_chart.notes[index].ticks;
+			drum_chart.notes[index].sp_phrase = drum_chart.sp_phrases.iter().position(|phrase| phrase.contains(ticks));
+			drum_chart.notes[index].fill = drum_chart.fills.iter().position(|fill| fill.contains(ticks));
+			drum_chart.notes[index].section = drum_chart.section_at_ticks(ticks);
+		}
+
+		for index in 0..drum_chart.fills.len() {
+			drum_chart.fills[index].finale_ticks = drum_chart.notes.iter()
+				.filter(|note| note.fill == Some(index))
+				.map(|note| note.ticks)
+				.last();
+		}
+
+		let section_stats = drum_chart.sections.iter().enumerate().map(|(index, section)| SectionStats {
+			name: section.name.clone(),
+			notes_hit: 0,
+			notes_missed: 0,
+			notes_total: drum_chart.notes.iter().filter(|note| note.section == Some(index)).count() as u64,
+			score: 0,
+		}).collect();
+		let state = PlaythroughState::new(drum_chart.notes.len(), section_stats, -settings.lead_in_ms, settings.rock_meter.start);
+
+		let mut sp_phrase_notes = vec![0; drum_chart.sp_phrases.len()];
+		for phrase in drum_chart.notes.iter().filter_map(|note| note.sp_phrase) {
+			sp_phrase_notes[phrase] += 1;
+		}
+
+		let notes_count = drum_chart.notes.len();
+		let phrases_count = drum_chart.sp_phrases.len();
+		let sections_count = drum_chart.sections.len();
+
+		return Ok(DrumPlaythrough {
+			difficulty: difficulty,
+			chart: drum_chart,
+			settings: settings,
+			state: state,
+			notes_freestyle: 0,
+			overhits: 0,
+			section_overhits: vec![0; sections_count],
+			active_fill: None,
+			resolved: vec![false; notes_count],
+			first_unresolved_index: 0,
+			next_fill_index: 0,
+			sp_phrase_notes: sp_phrase_notes,
+			sp_phrase_hits: vec![0; phrases_count],
+			sp_phrase_broken: vec![false; phrases_count],
+		})
+	}
+}
+
+#[derive(Copy, Clone)]
+pub enum DrumInputAction {
+	/* A pad or the kick pedal was hit. Outside of pro mode, cymbals count as toms. */
+	Hit(Pad),
+	/* Activates star power, on charts that have no drum fills to activate it with */
+	StarPower,
+}
+
+// when a pad is hit on a note:
+//    update score, streak
+//    unmute track if muted
+// when a pad is hit on no note:
+//    play the pad's sound
+// when a note exits the hit window:
+//    mute track
+//    if you have a streak, shake the screen, play miss sound
+// when every note of a star power phrase is hit:
+//    fill the star power meter, play the phrase sound
+// when a fill starts (the meter can activate star power):
+//    show the freestyle lanes until its last note
+// when star power is activated / runs out:
+//    light up / restore the highway
+// when every note is resolved and the chart or its audio has ended:
+//    show the results
+// when the rock meter runs out (unless no-fail is on):
+//    stop the song, show the results
+pub enum DrumGameEffect {
+	Hit { offset_ms: f32, grade: TimingGrade }, // offset is negative when early
+	Overhit,
+	MissStreak,
+	MissNoStreak,
+	StarPowerPhraseComplete { phrase: usize }, // index into the chart's sp_phrases
+	FillStarted,
+	FillMissed,
+	StarPowerActivated,
+	StarPowerDeactivated,
+	SongComplete(DrumResults),
+	Failed(DrumResults),
+}
+
+// every gem of a chord scores on its own, so gems score half of a guitar note.
+// The multiplier climbs as fast in notes as on guitar, which is faster in time since chords count per gem.
+const NOTE_SCORE: u64 = 25;
+
+impl DrumPlaythrough {
+	pub fn results(self: &Self) -> DrumResults {
+		DrumResults {
+			score: self.state.score,
+			notes_hit: self.state.notes_hit,
+			notes_missed: self.state.notes_missed,
+			notes_total: self.chart.notes.len() as u64 - self.notes_freestyle,
+			notes_freestyle: self.notes_freestyle,
+			best_streak: self.state.best_streak,
+			sp_phrases: self.state.sp_phrases,
+			failed: self.state.failed,
+			timing: self.state.timing.clone(),
+			sections: self.state.section_stats.clone(),
+			overhits: self.overhits,
+			section_overhits: self.section_overhits.clone(),
+		}
+	}
+
+	/* Tells the playthrough that the song's audio has finished playing */
+	pub fn end_audio(self: &mut Self) {
+		self.state.end_audio();
+	}
+
+	fn adjust_rock_meter(self: &mut Self, delta: f32) -> Vec<DrumGameEffect> {
+		if self.state.adjust_rock_meter(delta, self.settings.no_fail) { self.fail() } else { vec![] }
+	}
+
+	/* Ends the song as failed, as the rock meter running out does */
+	pub fn fail(self: &mut Self) -> Vec<DrumGameEffect> {
+		if self.state.fail() { vec![DrumGameEffect::Failed(self.results())] } else { vec![] }
+	}
+
+	/* Whether the note is played freestyle, as part of the fill being played */
+	fn is_freestyle(self: &Self, note: &DrumNote) -> bool {
+		self.active_fill.is_some() && note.fill == self.active_fill && !self.chart.is_fill_finale(note)
+	}
+
+	fn pad_matches(self: &Self, note_pad: Pad, hit_pad: Pad) -> bool {
+		if self.settings.pro { note_pad == hit_pad } else { note_pad.tom() == hit_pad.tom() }
+	}
+
+	fn advance_first_unresolved(self: &mut Self) {
+		while self.resolved.get(self.first_unresolved_index).map_or(false, |resolved| *resolved) {
+			self.first_unresolved_index += 1;
+		}
+	}
+
+	// Unlike on guitar, a hit only contests the notes of its own pad, and never misses the notes
+	// of other pads: every gem is judged on its own, so chords need no strum and can be hit
+	// one pad at a time. Of the unresolved notes of the pad whose hit window contains the hit,
+	// the earliest is hit. Freestyle notes of a fill are not contested.
+	fn find_hit(self: &Self, pad: Pad, time_ms: f32) -> Option<(usize, f32)> {
+		let hit_window = self.settings.hit_window;
+
+		self.chart.notes.iter()
+			.enumerate()
+			.skip(self.first_unresolved_index)
+			.map(|(index, note)| (index, note, time_ms - self.chart.ticks_to_ms(note.ticks)))
+			.skip_while(|(_index, _note, offset_ms)| *offset_ms > hit_window.late_ms)
+			.take_while(|(_index, _note, offset_ms)| *offset_ms >= -hit_window.early_ms)
+			.filter(|(index, note, _offset_ms)| !self.resolved[*index] && !self.is_freestyle(note))
+			.find(|(_index, note, _offset_ms)| self.pad_matches(note.pad, pad))
+			.map(|(index, _note, offset_ms)| (index, offset_ms))
+	}
+
+	fn hit(self: &mut Self, note_index: usize, offset_ms: f32, time_ms: f32) -> Vec<DrumGameEffect> {
+		let grade = self.settings.hit_window.grade(offset_ms);
+
+		self.resolved[note_index] = true;
+		self.advance_first_unresolved();
+
+		let note = &self.chart.notes[note_index];
+		let phrase = note.sp_phrase;
+		let section = note.section;
+		let finale = self.active_fill.is_some() && note.fill == self.active_fill;
+
+		let completed_phrase = phrase.map_or(false, |phrase| {
+			self.sp_phrase_hits[phrase] += 1;
+			self.sp_phrase_hits[phrase] == self.sp_phrase_notes[phrase] && !self.sp_phrase_broken[phrase]
+		});
+
+		let points = NOTE_SCORE * self.state.multiplier();
+		self.state.record_hit(note_index, section, points, offset_ms, grade, completed_phrase);
+
+		let mut effects = vec![DrumGameEffect::Hit { offset_ms: offset_ms, grade: grade }];
+		effects.extend(self.adjust_rock_meter(self.state.rock_meter_hit_gain(&self.settings.rock_meter)));
+		if let (Some(phrase), true) = (phrase, completed_phrase) {
+			effects.push(DrumGameEffect::StarPowerPhraseComplete { phrase: phrase });
+		}
+
+		if finale {
+			self.active_fill = None;
+			effects.extend(self.activate_star_power(time_ms));
+		}
+
+		effects
+	}
+
+	fn take_back_judgement(self: &mut Self, note_index: usize) {
+		let section = self.chart.notes[note_index].section;
+		if let Some(NoteJudgement::Freestyle) = self.state.take_back_judgement(note_index, section) {
+			self.notes_freestyle -= 1;
+		}
+	}
+
+	/* Resolves every unresolved note that has left the hit window, missing or playing it freestyle */
+	fn miss_notes_before(self: &mut Self, time_ms: f32) -> Vec<DrumGameEffect> {
+		let late_ms = self.settings.hit_window.late_ms;
+		let mut missed = 0;
+
+		for index in self.first_unresolved_index..self.chart.notes.len() {
+			let note = &self.chart.notes[index];
+			if self.chart.ticks_to_ms(note.ticks) >= time_ms - late_ms {
+				break;
+			}
+			if self.resolved[index] {
+				continue;
+			}
+
+			self.resolved[index] = true;
+			let (section, phrase) = (note.section, note.sp_phrase);
+			if self.is_freestyle(note) {
+				self.state.record_freestyle(index, section);
+				self.notes_freestyle += 1;
+				continue;
+			}
+
+			self.state.record_miss(index, section);
+			missed += 1;
+			if let Some(phrase) = phrase {
+				self.sp_phrase_broken[phrase] = true;
+			}
+		}
+		self.advance_first_unresolved();
+
+		if missed == 0 {
+			return vec![];
+		}
+
+		let effect = if self.state.break_streak() { DrumGameEffect::MissStreak } else { DrumGameEffect::MissNoStreak };
+
+		let mut effects = vec![effect];
+		effects.extend(self.adjust_rock_meter(-self.settings.rock_meter.miss_loss * missed as f32));
+		effects
+	}
+
+	fn activate_star_power(self: &mut Self, time_ms: f32) -> Vec<DrumGameEffect> {
+		if !self.state.activate_sp(self.chart.ms_to_beats(time_ms)) {
+			return vec![];
+		}
+
+		let mut effects = vec![DrumGameEffect::StarPowerActivated];
+		effects.extend(self.adjust_rock_meter(self.settings.rock_meter.sp_activation_gain));
+		effects
+	}
+
+	// Drum fills
+	//  - a fill starts when the song reaches it while star power could be activated:
+	//    the meter is at least half full, star power is not active and no other fill is playing.
+	//    A fill the song reaches at any other time is played like any other notes.
+	//  - the notes of a started fill are played freestyle: hitting them does nothing,
+	//    and letting them pass is not a miss
+	//  - hitting the fill's last note activates star power. If it is missed the fill ends,
+	//    and the meter waits for the next fill.
+	fn update_fills(self: &mut Self, time_ms: f32) -> Vec<DrumGameEffect> {
+		let mut effects = Vec::new();
+
+		while let Some(fill) = self.chart.fills.get(self.next_fill_index) {
+			if self.chart.ticks_to_ms(fill.ticks) > time_ms {
+				break;
+			}
+
+			let can_activate = self.state.can_activate_sp() && self.active_fill.is_none();
+			if can_activate && fill.finale_ticks.is_some() {
+				self.active_fill = Some(self.next_fill_index);
+				effects.push(DrumGameEffect::FillStarted);
+			}
+			self.next_fill_index += 1;
+		}
+
+		let late_ms = self.settings.hit_window.late_ms;
+		if let Some(fill) = self.active_fill.map(|fill| &self.chart.fills[fill]) {
+			let finale_passed = fill.finale_ticks.map_or(true, |ticks| self.chart.ticks_to_ms(ticks) < time_ms - late_ms);
+			if finale_passed {
+				self.active_fill = None;
+				effects.push(DrumGameEffect::FillMissed);
+			}
+		}
+
+		effects
+	}
+
+	pub fn apply(self: &mut Self, action: &DrumInputAction, time_ms: f32) -> Vec<DrumGameEffect> {
+		if self.state.failed || !self.state.judging(time_ms) {
+			return vec![];
+		}
+		let time_ms = time_ms - self.settings.audio_latency_ms;
+
+		match action {
+			DrumInputAction::Hit(pad) => {
+				match self.find_hit(*pad, time_ms) {
+					Some((note_index, offset_ms)) => self.hit(note_index, offset_ms, time_ms),
+					// anything goes during a fill
+					None if self.active_fill.is_some() => vec![],
+					None => {
+						self.overhits += 1;
+						if let Some(section) = self.chart.section_at_ms(time_ms) {
+							self.section_overhits[section] += 1;
+						}
+
+						let mut effects = vec![DrumGameEffect::Overhit];
+						effects.extend(self.adjust_rock_meter(-self.settings.rock_meter.overstrum_loss));
+						effects
+					},
+				}
+			},
+			DrumInputAction::StarPower if self.chart.fills.is_empty() => self.activate_star_power(time_ms),
+			DrumInputAction::StarPower => vec![],
+		}
+	}
+
+	/* Moves the playthrough to time_ms, forwards or backwards, without judging the notes in between.
+	 * Notes from time_ms on can be hit again: however they were judged before is taken back out of
+	 * the score and statistics, so no note is ever counted twice. Streaks, star power and the rock meter
+	 * are kept as they are. */
+	pub fn seek(self: &mut Self, time_ms: f32) {
+		let judged_ms = time_ms - self.settings.audio_latency_ms;
+		let late_ms = self.settings.hit_window.late_ms;
+		for index in 0..self.chart.notes.len() {
+			self.resolved[index] = self.chart.ticks_to_ms(self.chart.notes[index].ticks) < judged_ms - late_ms;
+			if !self.resolved[index] {
+				self.take_back_judgement(index);
+			}
+		}
+		self.first_unresolved_index = 0;
+		self.advance_first_unresolved();
+
+		// a phrase can only be completed if the seek landed before its first note
+		let mut phrase_seen = vec![false; self.chart.sp_phrases.len()];
+		for (index, note) in self.chart.notes.iter().enumerate() {
+			if let Some(phrase) = note.sp_phrase {
+				if !phrase_seen[phrase] {
+					phrase_seen[phrase] = true;
+					self.sp_phrase_hits[phrase] = 0;
+					self.sp_phrase_broken[phrase] = self.resolved[index];
+				}
+			}
+		}
+
+		self.next_fill_index = self.chart.fills.iter()
+			.position(|fill| self.chart.ticks_to_ms(fill.ticks) >= judged_ms)
+			.unwrap_or(self.chart.fills.len());
+		self.active_fill = None;
+		self.state.seek(time_ms, self.chart.ms_to_beats(judged_ms));
+	}
+
+	pub fn time_ms(self: &Self) -> f32 {
+		self.state.time_ms()
+	}
+
+	/* The song clock starts here, before the chart does */
+	pub fn start_time_ms(self: &Self) -> f32 {
+		-self.settings.lead_in_ms
+	}
+
+	pub fn pause(self: &mut Self) {
+		// nothing is held on drums
+	}
+
+	/* Rewinds from the paused point and returns the time the song clock should restart from.
+	 * The rewound notes are already judged, so nothing is judged again until the paused point. */
+	pub fn resume(self: &mut Self) -> f32 {
+		let start_time_ms = self.start_time_ms();
+		self.state.resume(self.settings.pause_rewind_ms, start_time_ms)
+	}
+
+	/* Time left on the resume countdown, if it is showing */
+	pub fn countdown_ms(self: &Self) -> Option<f32> {
+		self.state.countdown_ms(self.settings.resume_countdown_ms)
+	}
+
+	pub fn update_time(self: &mut Self, time_ms: f32) -> Vec<DrumGameEffect> {
+		self.state.set_time_ms(time_ms);
+
+		if self.state.failed {
+			return vec![];
+		}
+		let time_ms = time_ms - self.settings.audio_latency_ms;
+
+		let mut effects = Vec::new();
+
+		if self.state.drain_sp(self.chart.ms_to_beats(time_ms)) {
+			effects.push(DrumGameEffect::StarPowerDeactivated);
+		}
+
+		effects.extend(self.miss_notes_before(time_ms));
+		effects.extend(self.update_fills(time_ms));
+
+		// the song is over at the chart's end event, or without one, once the audio has ended
+		let ended = self.state.audio_ended() || self.chart.end_ticks.map_or(false, |ticks| time_ms >= self.chart.ticks_to_ms(ticks));
+		if ended && self.first_unresolved_index >= self.chart.notes.len() && self.state.complete() {
+			effects.push(DrumGameEffect::SongComplete(self.results()));
+		}
+
+		effects
+	}
+}
+
+impl PlaythroughEffect for DrumGameEffect {
+	type Results = DrumResults;
+
+	fn results(self: &Self) -> Option<DrumResults> {
+		match self {
+			DrumGameEffect::SongComplete(results) | DrumGameEffect::Failed(results) => Some(results.clone()),
+			_ => None,
+		}
+	}
+}
+
+impl Playthrough for DrumPlaythrough {
+	type InputAction = DrumInputAction;
+	type Results = DrumResults;
+	type Effect = DrumGameEffect;
+
+	fn apply(self: &mut Self, action: &DrumInputAction, time_ms: f32) -> Vec<DrumGameEffect> {
+		DrumPlaythrough::apply(self, action, time_ms)
+	}
+
+	fn update_time(self: &mut Self, time_ms: f32) -> Vec<DrumGameEffect> {
+		DrumPlaythrough::update_time(self, time_ms)
+	}
+
+	fn time_ms(self: &Self) -> f32 {
+		DrumPlaythrough::time_ms(self)
+	}
+
+	fn start_time_ms(self: &Self) -> f32 {
+		DrumPlaythrough::start_time_ms(self)
+	}
+
+	fn audio_offset_ms(self: &Self) -> f32 {
+		self.chart.audio_offset_ms
+	}
+
+	fn seek(self: &mut Self, time_ms: f32) {
+		DrumPlaythrough::seek(self, time_ms)
+	}
+
+	fn pause(self: &mut Self) {
+		DrumPlaythrough::pause(self)
+	}
+
+	fn resume(self: &mut Self) -> f32 {
+		DrumPlaythrough::resume(self)
+	}
+
+	fn countdown_ms(self: &Self) -> Option<f32> {
+		DrumPlaythrough::countdown_ms(self)
+	}
+
+	fn end_audio(self: &mut Self) {
+		DrumPlaythrough::end_audio(self)
+	}
+
+	fn score(self: &Self) -> u64 {
+		self.state.score
+	}
+
+	fn streak(self: &Self) -> u64 {
+		self.state.streak
+	}
+
+	fn multiplier(self: &Self) -> u64 {
+		self.state.multiplier()
+	}
+
+	fn sp_meter(self: &Self) -> f32 {
+		self.state.sp_meter
+	}
+
+	fn sp_active(self: &Self) -> bool {
+		self.state.sp_active
+	}
+
+	fn rock_meter(self: &Self) -> f32 {
+		self.state.rock_meter
+	}
+
+	fn failed(self: &Self) -> bool {
+		self.state.failed
+	}
+
+	fn results(self: &Self) -> DrumResults {
+		DrumPlaythrough::results(self)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// 120 BPM at 192 ticks per beat, so tick 192 is at 500 ms
+	fn playthrough_with(notes: &str, settings: DrumSettings) -> DrumPlaythrough {
+		let chart_file = format!("[Song]\n{{\n  Resolution = 192\n}}\n[SyncTrack]\n{{\n  0 = B 120000\n}}\n[Events]\n{{\n  0 = E \"section Intro\"\n}}\n[ExpertDrums]\n{{\n{}}}\n", notes);
+		let chart = chart::read(&chart_file).unwrap();
+		DrumPlaythrough::new(chart, chart::Difficulty::Expert, settings).unwrap()
+	}
+
+	fn playthrough(notes: &str) -> DrumPlaythrough {
+		playthrough_with(notes, DrumSettings::for_difficulty(&chart::Difficulty::Expert))
+	}
+
+	fn hit(playthrough: &mut DrumPlaythrough, pad: Pad, time_ms: f32) -> Vec<DrumGameEffect> {
+		playthrough.update_time(time_ms);
+		playthrough.apply(&DrumInputAction::Hit(pad), time_ms)
+	}
+
+	fn hits(effects: &[DrumGameEffect]) -> usize {
+		effects.iter().filter(|effect| match effect { DrumGameEffect::Hit { .. } => true, _ => false }).count()
+	}
+
+	fn has(effects: &[DrumGameEffect], matches: fn(&DrumGameEffect) -> bool) -> bool {
+		effects.iter().any(matches)
+	}
+
+	#[test]
+	fn chord_gems_are_hit_one_pad_at_a_time() {
+		let mut playthrough = playthrough("  192 = N 1 0\n  192 = N 2 0\n");
+		assert_eq!(hits(&hit(&mut playthrough, Pad::YellowTom, 505.0)), 1);
+		assert_eq!(hits(&hit(&mut playthrough, Pad::Red, 510.0)), 1);
+		playthrough.update_time(1000.0);
+
+		assert_eq!(playthrough.state.notes_hit, 2);
+		assert_eq!(playthrough.state.notes_missed, 0);
+		assert_eq!(playthrough.overhits, 0);
+	}
+
+	#[test]
+	fn hitting_one_gem_of_a_chord_misses_only_the_other() {
+		let mut playthrough = playthrough("  192 = N 1 0\n  192 = N 2 0\n");
+		hit(&mut playthrough, Pad::Red, 500.0);
+		playthrough.update_time(1000.0);
+
+		assert_eq!(playthrough.state.notes_hit, 1);
+		assert_eq!(playthrough.state.notes_missed, 1);
+	}
+
+	#[test]
+	fn kick_is_hit_by_the_pedal_only() {
+		let mut playthrough = playthrough("  192 = N 0 0\n");
+		let effects = hit(&mut playthrough, Pad::Red, 500.0);
+		assert!(has(&effects, |effect| match effect { DrumGameEffect::Overhit => true, _ => false }));
+		assert_eq!(hits(&hit(&mut playthrough, Pad::Kick, 505.0)), 1);
+
+		assert_eq!(playthrough.state.notes_hit, 1);
+		assert_eq!(playthrough.overhits, 1);
+		assert_eq!(playthrough.section_overhits, vec![1]);
+		assert_eq!(playthrough.state.section_stats[0].notes_hit, 1);
+	}
+
+	#[test]
+	fn cymbal_counts_as_tom_outside_pro_mode() {
+		let mut playthrough = playthrough("  192 = N 2 0\n  192 = N 66 0\n");
+		assert_eq!(playthrough.chart.notes[0].pad, Pad::YellowCymbal);
+		assert_eq!(hits(&hit(&mut playthrough, Pad::YellowTom, 500.0)), 1);
+	}
+
+	#[test]
+	fn pro_mode_tells_cymbals_and_toms_apart() {
+		let mut settings = DrumSettings::for_difficulty(&chart::Difficulty::Expert);
+		settings.pro = true;
+		let mut playthrough = playthrough_with("  192 = N 2 0\n  192 = N 66 0\n  384 = N 3 0\n", settings);
+
+		assert_eq!(hits(&hit(&mut playthrough, Pad::YellowTom, 500.0)), 0);
+		assert_eq!(hits(&hit(&mut playthrough, Pad::YellowCymbal, 505.0)), 1);
+		assert_eq!(hits(&hit(&mut playthrough, Pad::BlueCymbal, 1000.0)), 0);
+		assert_eq!(hits(&hit(&mut playthrough, Pad::BlueTom, 1005.0)), 1);
+		assert_eq!(playthrough.overhits, 2);
+	}
+
+	#[test]
+	fn every_gem_counts_toward_the_multiplier() {
+		// five two-gem chords make a streak of ten
+		let notes: String = (1..=6).map(|beat| format!("  {} = N 1 0\n  {} = N 2 0\n", beat * 192, beat * 192)).collect();
+		let mut playthrough = playthrough(&notes);
+		for beat in 1..=5 {
+			let time_ms = beat as f32 * 500.0;
+			hit(&mut playthrough, Pad::Red, time_ms);
+			hit(&mut playthrough, Pad::YellowTom, time_ms);
+		}
+		assert_eq!(playthrough.state.streak, 10);
+		assert_eq!(playthrough.state.score, 10 * NOTE_SCORE);
+		assert_eq!(playthrough.state.multiplier(), 2);
+
+		hit(&mut playthrough, Pad::Red, 3000.0);
+		assert_eq!(playthrough.state.score, 10 * NOTE_SCORE + 2 * NOTE_SCORE);
+	}
+
+	#[test]
+	fn fill_finale_activates_star_power() {
+		// two phrases fill the meter halfway, which lets the fill start
+		let mut playthrough = playthrough("  192 = N 1 0\n  192 = S 2 1\n  384 = N 1 0\n  384 = S 2 1\n  768 = N 1 0\n  768 = S 64 192\n  864 = N 2 0\n");
+		hit(&mut playthrough, Pad::Red, 500.0);
+		hit(&mut playthrough, Pad::Red, 1000.0);
+		assert_eq!(playthrough.state.sp_meter, 0.5);
+
+		// star power can only be activated by the fill
+		assert!(playthrough.apply(&DrumInputAction::StarPower, 1500.0).is_empty());
+		let effects = playthrough.update_time(2000.0);
+		assert!(has(&effects, |effect| match effect { DrumGameEffect::FillStarted => true, _ => false }));
+
+		// the fill's first note is played freestyle rather than missed
+		let effects = hit(&mut playthrough, Pad::YellowTom, 2250.0);
+		assert!(has(&effects, |effect| match effect { DrumGameEffect::StarPowerActivated => true, _ => false }));
+		assert!(playthrough.state.sp_active);
+		assert_eq!(playthrough.notes_freestyle, 1);
+		assert_eq!(playthrough.state.notes_missed, 0);
+		assert_eq!(playthrough.results().notes_total, 3);
+	}
+
+	#[test]
+	fn missed_fill_finale_ends_the_fill() {
+		let mut playthrough = playthrough("  192 = N 1 0\n  192 = S 2 1\n  384 = N 1 0\n  384 = S 2 1\n  768 = N 1 0\n  768 = S 64 192\n  864 = N 2 0\n");
+		hit(&mut playthrough, Pad::Red, 500.0);
+		hit(&mut playthrough, Pad::Red, 1000.0);
+		playthrough.update_time(2000.0);
+
+		let effects = playthrough.update_time(2500.0);
+		assert!(has(&effects, |effect| match effect { DrumGameEffect::FillMissed => true, _ => false }));
+		assert!(!playthrough.state.sp_active);
+		assert_eq!(playthrough.active_fill, None);
+		assert_eq!(playthrough.state.notes_missed, 1);
+	}
+}
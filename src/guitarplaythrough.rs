@@ -1,10 +1,71 @@
 use crate::chart;
+use crate::playthrough::{Playthrough, PlaythroughEffect, PlaythroughState};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Fret {
-	G = 0, R, Y, B, O
+	G, R, Y, B, O,
+	/* Six-fret (Guitar Hero Live) guitars have a row of white frets below a row of black frets */
+	W1, W2, W3, B1, B2, B3,
+}
+
+pub const MAX_LANES: usize = 6;
+
+impl Fret {
+	/* Index of the fret's lane in GuitarNote.chord and GuitarPlaythrough.frets */
+	pub fn lane(self: &Self) -> usize {
+		match self {
+			Fret::G | Fret::W1 => 0,
+			Fret::R | Fret::W2 => 1,
+			Fret::Y | Fret::W3 => 2,
+			Fret::B | Fret::B1 => 3,
+			Fret::O | Fret::B2 => 4,
+			Fret::B3 => 5,
+		}
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FretLayout {
+	Five,
+	/* Three columns, each with a white and a black fret */
+	Six,
+}
+
+impl FretLayout {
+	pub fn for_instrument(instrument: &chart::Instrument) -> Option<FretLayout> {
+		match instrument {
+			chart::Instrument::GHLGuitar | chart::Instrument::GHLBass => Some(FretLayout::Six),
+			instrument if instrument.is_five_fret() => Some(FretLayout::Five),
+			_ => None,
+		}
+	}
+
+	/* The layout's frets, in lane order */
+	pub fn frets(self: &Self) -> &'static [Fret] {
+		match self {
+			FretLayout::Five => &[Fret::G, Fret::R, Fret::Y, Fret::B, Fret::O],
+			FretLayout::Six => &[Fret::W1, Fret::W2, Fret::W3, Fret::B1, Fret::B2, Fret::B3],
+		}
+	}
+
+	/* Column of the neck a lane is on, counting from the headstock end */
+	pub fn column(self: &Self, lane: usize) -> usize {
+		match self {
+			FretLayout::Five => lane,
+			FretLayout::Six => lane % 3,
+		}
+	}
+
+	/* The lane a lane plays as when the neck is mirrored, which swaps the highest and lowest columns */
+	pub fn mirror(self: &Self, lane: usize) -> usize {
+		match self {
+			FretLayout::Five => 4 - lane,
+			FretLayout::Six => lane - lane % 3 + (2 - lane % 3),
+		}
+	}
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum GuitarNoteStrumType {
 	Strum,
 	Hopo,
@@ -13,66 +74,496 @@ pub enum GuitarNoteStrumType {
 
 pub struct GuitarNote {
 	pub ticks: u64,
-	pub chord: [bool; 5],
+	pub chord: [bool; MAX_LANES],
 	pub strum_type: GuitarNoteStrumType,
 	pub duration: u64,
+	/* Index into GuitarChart.sp_phrases of the phrase containing this note */
+	pub sp_phrase: Option<usize>,
+	/* Index into GuitarChart.sections of the section containing this note */
+	pub section: Option<usize>,
 }
 
 impl GuitarNote {
 	pub fn is_open(self: &Self) -> bool {
-		!(self.chord[0] || self.chord[1] || self.chord[2] || self.chord[3] || self.chord[4])
+		self.chord.iter().all(|c| !*c)
+	}
+}
+
+pub struct Tempo {
+	pub ticks: u64,
+	pub bpm1000: u64,
+}
+
+pub struct StarPowerPhrase {
+	pub ticks: u64,
+	pub duration: u64,
+}
+
+impl StarPowerPhrase {
+	/* The part's star power phrases sorted by their tick */
+	pub fn from_part(part: &chart::Part) -> Vec<StarPowerPhrase> {
+		let mut sp_phrases: Vec<StarPowerPhrase> = part.special_events.iter().filter_map(|event| {
+			match event {
+				chart::SpecialEvent::StarPower { ticks, duration } => Some(StarPowerPhrase { ticks: *ticks, duration: *duration }),
+				chart::SpecialEvent::DrumFill { .. } => None,
+			}
+		}).collect();
+		sp_phrases.sort_by_key(|phrase| phrase.ticks);
+		sp_phrases
 	}
+
+	pub fn contains(self: &Self, ticks: u64) -> bool {
+		ticks == self.ticks || (ticks > self.ticks && ticks < self.ticks + self.duration)
+	}
+}
+
+pub struct Section {
+	pub ticks: u64,
+	pub name: String,
+}
+
+impl Section {
+	/* The chart's sections sorted by their tick */
+	pub fn from_chart(chart: &chart::Chart) -> Vec<Section> {
+		let mut sections: Vec<Section> = chart.events.iter().filter_map(|event| {
+			match event {
+				chart::Event::Section { ticks, name } => Some(Section { ticks: *ticks, name: name.clone() }),
+				chart::Event::End { .. } => None,
+			}
+		}).collect();
+		sections.sort_by_key(|section| section.ticks);
+		sections
+	}
+}
+
+pub struct TempoMap {
+	pub ticks_per_beat: u64, // aka Resolution
+	/* Vector of tempo changes sorted by their tick. The first starts at tick 0. */
+	pub tempos: std::vec::Vec<Tempo>,
 }
 
 // TODO: refactor
 pub struct GuitarChart {
-	pub ticks_per_beat: u64, // aka Resolution
-	pub beats_per_minute: u64,
+	pub layout: FretLayout,
+	/* Position in the audio of time 0 in the chart */
+	pub audio_offset_ms: f32,
+	pub tempo_map: TempoMap,
 	/* Vector of notes sorted by their tick */
 	pub notes: std::vec::Vec<GuitarNote>,
+	/* Vector of star power phrases sorted by their tick */
+	pub sp_phrases: std::vec::Vec<StarPowerPhrase>,
+	/* Vector of sections sorted by their tick */
+	pub sections: std::vec::Vec<Section>,
+	/* Tick of the chart's end event, if it has one */
+	pub end_ticks: Option<u64>,
+}
+
+fn beats_to_ms(beats: f32, bpm1000: u64) -> f32 {
+	beats / (bpm1000 as f32 / 1000f32) * 60f32 * 1000f32
+}
+
+impl TempoMap {
+	pub fn new(chart: &chart::Chart) -> Result<TempoMap, &'static str> {
+		let mut tempos: Vec<Tempo> = chart.sync_track.iter().filter_map(|st| {
+			match st {
+				chart::SyncTrack::BeatsPerMinute { ticks, bpm1000 } => Some(Tempo { ticks: *ticks, bpm1000: *bpm1000 }),
+				chart::SyncTrack::TimeSignature { .. } => None,
+			}
+		}).collect();
+		tempos.sort_by_key(|tempo| tempo.ticks);
+		tempos.first_mut().ok_or_else(|| "no BPM found")?.ticks = 0; // TODO: handle
+
+		Ok(TempoMap {
+			ticks_per_beat: chart.song.resolution,
+			tempos: tempos,
+		})
+	}
+
+	pub fn ticks_to_ms(self: &Self, ticks: u64) -> f32 {
+		let mut ms = 0f32;
+		let mut tempo = &self.tempos[0];
+
+		for next_tempo in self.tempos.iter().skip(1).take_while(|t| t.ticks < ticks) {
+			ms += beats_to_ms((next_tempo.ticks - tempo.ticks) as f32 / self.ticks_per_beat as f32, tempo.bpm1000);
+			tempo = next_tempo;
+		}
+
+		ms + beats_to_ms((ticks - tempo.ticks) as f32 / self.ticks_per_beat as f32, tempo.bpm1000)
+	}
+
+	/* Fractional beat position at the given time. Times before zero extrapolate the first tempo. */
+	pub fn ms_to_beats(self: &Self, time_ms: f32) -> f32 {
+		let mut ms = 0f32;
+		let mut tempo = &self.tempos[0];
+
+		for next_tempo in self.tempos.iter().skip(1) {
+			let next_ms = ms + beats_to_ms((next_tempo.ticks - tempo.ticks) as f32 / self.ticks_per_beat as f32, tempo.bpm1000);
+			if next_ms > time_ms {
+				break;
+			}
+			ms = next_ms;
+			tempo = next_tempo;
+		}
+
+		(tempo.ticks as f32 / self.ticks_per_beat as f32) + (time_ms - ms) / beats_to_ms(1f32, tempo.bpm1000)
+	}
+
+	/* Tick at the given fractional beat position */
+	pub fn beats_to_ticks(self: &Self, beats: f32) -> u64 {
+		(beats * self.ticks_per_beat as f32) as u64
+	}
 }
 
 impl GuitarChart {
 	pub fn ticks_to_ms(self: &Self, ticks: u64) -> f32 {
-		((ticks as f32) / (self.ticks_per_beat as f32)) / (self.beats_per_minute as f32) * 60f32 * 1000f32
+		self.tempo_map.ticks_to_ms(ticks)
+	}
+
+	pub fn ms_to_beats(self: &Self, time_ms: f32) -> f32 {
+		self.tempo_map.ms_to_beats(time_ms)
+	}
+
+	/* Time at which the chart is over: its end event, or else the end of its last note */
+	pub fn end_ms(self: &Self) -> f32 {
+		match self.end_ticks {
+			Some(ticks) => self.ticks_to_ms(ticks),
+			None => self.notes.iter()
+				.map(|note| self.ticks_to_ms(note.ticks + note.duration))
+				.fold(0f32, f32::max),
+		}
+	}
+
+	/* Index of the last section starting at or before the given tick */
+	pub fn section_at_ticks(self: &Self, ticks: u64) -> Option<usize> {
+		self.sections.iter().rposition(|section| section.ticks <= ticks)
+	}
+
+	pub fn section_at_ms(self: &Self, time_ms: f32) -> Option<usize> {
+		let beats = self.ms_to_beats(time_ms);
+		if beats < 0.0 {
+			return None;
+		}
+		self.section_at_ticks(self.tempo_map.beats_to_ticks(beats))
 	}
 }
 
-pub struct GuitarPlaythrough {
-	pub chart: GuitarChart,
+/* How far from a note's time, in ms, it may still be hit */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct HitWindow {
+	pub early_ms: f32,
+	pub late_ms: f32,
+}
+
+impl HitWindow {
+	pub fn for_difficulty(difficulty: &chart::Difficulty) -> HitWindow {
+		match difficulty {
+			chart::Difficulty::Easy => HitWindow { early_ms: 60.0, late_ms: 50.0 },
+			chart::Difficulty::Medium => HitWindow { early_ms: 55.0, late_ms: 45.0 },
+			chart::Difficulty::Hard => HitWindow { early_ms: 50.0, late_ms: 45.0 },
+			chart::Difficulty::Expert => HitWindow { early_ms: 45.0, late_ms: 40.0 },
+		}
+	}
+
+	/* Grades a hit by how much of the early or late side of the window its offset used */
+	pub fn grade(self: &Self, offset_ms: f32) -> TimingGrade {
+		let side_ms = if offset_ms < 0.0 { self.early_ms } else { self.late_ms };
+		let used = f32::abs(offset_ms) / side_ms;
+
+		if used <= PERFECT_WINDOW_FRACTION {
+			TimingGrade::Perfect
+		} else if used <= GREAT_WINDOW_FRACTION {
+			TimingGrade::Great
+		} else {
+			TimingGrade::Good
+		}
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct GuitarSettings {
+	pub hit_window: HitWindow,
+	/* Ignore a strum shortly after hitting a HOPO or tap with the frets */
+	pub strum_leniency: bool,
+	/* Ignore a second strum shortly after strumming a note, while its frets are still held */
+	pub double_strum_protection: bool,
+	/* A stray fret press stops HOPOs from being hit without a strum until the next hit */
+	pub ghost_protection: bool,
+	/* How far the song goes back when resuming from pause */
+	pub pause_rewind_ms: f32,
+	/* How long before the paused point a countdown is shown when resuming */
+	pub resume_countdown_ms: f32,
+	/* How long the song clock runs before the chart starts, so notes at tick 0 can scroll in */
+	pub lead_in_ms: f32,
+	pub rock_meter: RockMeterSettings,
+	/* The rock meter still moves, but the song can't be failed */
+	pub no_fail: bool,
+	pub modifiers: Modifiers,
+	/* How long the audio takes to be heard once it is played. Inputs are judged this much earlier,
+	 * against the notes the player heard rather than the ones being played. */
+	pub audio_latency_ms: f32,
+}
+
+/* Changes to the chart and judging chosen before play. Runs with different modifiers
+ * don't score alike, so results and replays carry them. */
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Modifiers {
+	/* Every note is played as this type: all strums, all HOPOs or all taps */
+	pub strum_type: Option<GuitarNoteStrumType>,
+	/* The lanes are reversed, so green notes are played on orange and the other way around */
+	pub mirror: bool,
+	/* The highway is drawn flipped for left-handed players. Only the drawing changes. */
+	pub lefty: bool,
+	/* The hit windows shrink to PRECISION_WINDOW_FRACTION of their width */
+	pub precision: bool,
+}
+
+pub const PRECISION_WINDOW_FRACTION: f32 = 0.6;
+
+impl Modifiers {
+	pub fn names(self: &Self) -> Vec<&'static str> {
+		let mut names = Vec::new();
+		match self.strum_type {
+			Some(GuitarNoteStrumType::Strum) => names.push("all_strums"),
+			Some(GuitarNoteStrumType::Hopo) => names.push("all_hopos"),
+			Some(GuitarNoteStrumType::Tap) => names.push("all_taps"),
+			None => (),
+		}
+		if self.mirror {
+			names.push("mirror");
+		}
+		if self.lefty {
+			names.push("lefty");
+		}
+		if self.precision {
+			names.push("precision");
+		}
+		names
+	}
+
+	/* Turns on the modifier with the given name */
+	pub fn enable(self: &mut Self, name: &str) -> Result<(), &'static str> {
+		match name {
+			"all_strums" => self.strum_type = Some(GuitarNoteStrumType::Strum),
+			"all_hopos" => self.strum_type = Some(GuitarNoteStrumType::Hopo),
+			"all_taps" => self.strum_type = Some(GuitarNoteStrumType::Tap),
+			"mirror" => self.mirror = true,
+			"lefty" => self.lefty = true,
+			"precision" => self.precision = true,
+			_ => return Err("unknown modifier"),
+		}
+		Ok(())
+	}
+
+	/* Rewrites the chart's notes before play */
+	pub fn apply(self: &Self, chart: &mut GuitarChart) {
+		let layout = chart.layout;
+		for note in &mut chart.notes {
+			if let Some(strum_type) = self.strum_type {
+				note.strum_type = strum_type;
+			}
+			if self.mirror {
+				let chord = note.chord;
+				for fret in layout.frets() {
+					note.chord[layout.mirror(fret.lane())] = chord[fret.lane()];
+				}
+			}
+		}
+	}
+
+	pub fn hit_window(self: &Self, hit_window: HitWindow) -> HitWindow {
+		if self.precision {
+			HitWindow {
+				early_ms: hit_window.early_ms * PRECISION_WINDOW_FRACTION,
+				late_ms: hit_window.late_ms * PRECISION_WINDOW_FRACTION,
+			}
+		} else {
+			hit_window
+		}
+	}
+}
+
+/* Rock meter (health) tuning. The meter runs from 0 (failed) to 1. */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RockMeterSettings {
+	pub start: f32,
+	pub hit_gain: f32,
+	pub miss_loss: f32, // per note missed
+	pub overstrum_loss: f32,
+	/* Hits gain this many times as much while star power is active */
+	pub sp_hit_multiplier: f32,
+	/* Gained when star power is activated */
+	pub sp_activation_gain: f32,
+}
+
+impl Default for RockMeterSettings {
+	fn default() -> RockMeterSettings {
+		RockMeterSettings {
+			start: 0.5,
+			hit_gain: 0.02,
+			miss_loss: 0.05,
+			overstrum_loss: 0.03,
+			sp_hit_multiplier: 2.0,
+			sp_activation_gain: 0.1,
+		}
+	}
+}
+
+impl GuitarSettings {
+	pub fn for_difficulty(difficulty: &chart::Difficulty) -> GuitarSettings {
+		GuitarSettings {
+			hit_window: HitWindow::for_difficulty(difficulty),
+			strum_leniency: true,
+			double_strum_protection: true,
+			ghost_protection: true,
+			pause_rewind_ms: 3000.0,
+			resume_countdown_ms: 3000.0,
+			lead_in_ms: 2000.0,
+			rock_meter: RockMeterSettings::default(),
+			no_fail: false,
+			modifiers: Modifiers::default(),
+			audio_latency_ms: 0.0,
+		}
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TimingGrade {
+	Perfect,
+	Great,
+	Good,
+}
+
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct TimingStats {
+	pub perfect: u64,
+	pub great: u64,
+	pub good: u64,
+	pub early: u64,
+	pub late: u64,
+	pub total_offset_ms: f32,
+}
+
+impl TimingStats {
+	pub fn record(self: &mut Self, offset_ms: f32, grade: TimingGrade) {
+		match grade {
+			TimingGrade::Perfect => self.perfect += 1,
+			TimingGrade::Great => self.great += 1,
+			TimingGrade::Good => self.good += 1,
+		}
+		if offset_ms < 0.0 {
+			self.early += 1;
+		} else if offset_ms > 0.0 {
+			self.late += 1;
+		}
+		self.total_offset_ms += offset_ms;
+	}
+
+	/* Takes back a hit recorded with the same offset and grade */
+	pub fn forget(self: &mut Self, offset_ms: f32, grade: TimingGrade) {
+		match grade {
+			TimingGrade::Perfect => self.perfect -= 1,
+			TimingGrade::Great => self.great -= 1,
+			TimingGrade::Good => self.good -= 1,
+		}
+		if offset_ms < 0.0 {
+			self.early -= 1;
+		} else if offset_ms > 0.0 {
+			self.late -= 1;
+		}
+		self.total_offset_ms -= offset_ms;
+	}
+
+	pub fn hits(self: &Self) -> u64 {
+		self.perfect + self.great + self.good
+	}
+
+	/* Negative when the player rushes, positive when they drag */
+	pub fn mean_offset_ms(self: &Self) -> f32 {
+		if self.hits() == 0 { 0.0 } else { self.total_offset_ms / self.hits() as f32 }
+	}
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SectionStats {
+	pub name: String,
+	pub notes_hit: u64,
+	pub notes_missed: u64,
+	pub notes_total: u64,
+	pub score: u64,
+}
+
+impl SectionStats {
+	/* Fraction of the section's notes that were hit */
+	pub fn accuracy(self: &Self) -> f32 {
+		if self.notes_total == 0 { 1.0 } else { self.notes_hit as f32 / self.notes_total as f32 }
+	}
+}
+
+/* Final statistics of a playthrough */
+#[derive(Clone, PartialEq, Debug)]
+pub struct GuitarResults {
 	pub score: u64,
 	pub notes_hit: u64,
-	pub streak: u64,
+	pub notes_missed: u64,
+	pub notes_total: u64,
+	pub best_streak: u64,
 	pub sp_phrases: u64,
-	pub avg_multiplier: u64,
-	// TODO: section scores
+	pub failed: bool,
+	pub timing: TimingStats,
+	pub sections: Vec<SectionStats>,
+	/* Strums that hit nothing, one entry per section */
+	pub section_overstrums: Vec<u64>,
+	pub modifiers: Modifiers,
+}
+
+impl GuitarResults {
+	pub fn accuracy(self: &Self) -> f32 {
+		if self.notes_total == 0 { 1.0 } else { self.notes_hit as f32 / self.notes_total as f32 }
+	}
+}
+
+pub struct GuitarPlaythrough {
+	pub instrument: chart::Instrument,
+	pub difficulty: chart::Difficulty,
+	pub chart: GuitarChart,
+	pub settings: GuitarSettings,
+	/* Score, statistics, clock and meters. Inputs before the clock is judging only move the frets. */
+	pub state: PlaythroughState,
+	/* One entry per GuitarChart.sections */
+	pub section_overstrums: Vec<u64>,
 
 	// state
-	pub frets: [bool; 5],
-	time: f32,
-	next_note_index: usize, // TODO: are you only contesting the very next note?
+	pub frets: [bool; MAX_LANES],
+	pub whammy: f32, // 0 (at rest) to 1 (fully pressed)
+	pub tilted: bool,
+	next_note_index: usize, // first note not yet hit or missed; inputs contest every note in the window from here
+	sp_phrase_broken: Option<usize>,
+	sustain_note_index: Option<usize>,
+	sustain_credited_to_beat: f32,
+	whammy_moved_ms: f32,
+	last_fret_hit_ms: Option<f32>,
+	last_strum_hit: Option<(usize, f32)>, // note index and time
+	ghosted: bool,
 }
 
 impl GuitarPlaythrough {
-	pub fn new(chart: chart::Chart) -> Result<GuitarPlaythrough, &'static str> {
-		let guitar_chart = GuitarChart {
-			ticks_per_beat: chart.sync_track.iter().filter_map(|st| {
-				match st {
-					chart::SyncTrack::BeatsPerMinute { bpm1000, .. } => Some(bpm1000 / 1000),
-					chart::SyncTrack::TimeSignature { .. } => None,
-				}
-			}).nth(0).ok_or_else(|| "no BPM found")?, // TODO: handle
-			beats_per_minute: chart.song.resolution,
-			notes: chart.parts
+	/* Plays the chart's part for the given five- or six-fret instrument and difficulty */
+	pub fn new(chart: chart::Chart, instrument: chart::Instrument, difficulty: chart::Difficulty, settings: GuitarSettings) -> Result<GuitarPlaythrough, &'static str> {
+		let layout = FretLayout::for_instrument(&instrument).ok_or_else(|| "not a guitar instrument")?;
+
+		let tempo_map = TempoMap::new(&chart)?;
+
+		let part = chart.parts
 			.iter()
-			.filter(|part| {
-				match (&part.instrument, &part.difficulty) {
-					(chart::Instrument::Guitar, chart::Difficulty::Expert) => true,
-					_ => false
-				}
-			})
+			.filter(|part| part.instrument == instrument && part.difficulty == difficulty)
 			.nth(0)
-			.ok_or_else(|| "no Expert Guitar part found")? // TODO: handle
+			.ok_or_else(|| "part not found in chart")?;
+
+		let mut guitar_chart = GuitarChart {
+			layout: layout,
+			audio_offset_ms: chart.song.offset.unwrap_or(0.0) * 1000f32,
+			tempo_map: tempo_map,
+			notes: part
 			.notes
 			.iter()
 			.fold(Vec::new(), |mut notes, note| {
@@ -86,48 +577,91 @@ impl GuitarPlaythrough {
 					None => {
 						notes.push(GuitarNote {
 							ticks: note.ticks,
-							chord: [false, false, false, false, false],
+							chord: [false; MAX_LANES],
 							strum_type: GuitarNoteStrumType::Strum,
 							duration: note.duration,
+							sp_phrase: None,
+							section: None,
 						});
 						notes.last_mut().unwrap()
 					}
 				};
 
-				match note.note {
-					0 => note_to_modify.chord[Fret::G as usize] = true,
-					1 => note_to_modify.chord[Fret::R as usize] = true,
-					2 => note_to_modify.chord[Fret::Y as usize] = true,
-					3 => note_to_modify.chord[Fret::B as usize] = true,
-					4 => note_to_modify.chord[Fret::O as usize] = true,
-					5 => note_to_modify.strum_type = GuitarNoteStrumType::Hopo, // TODO: this really means FORCE - calculate hopo or not
-					6 => note_to_modify.strum_type = GuitarNoteStrumType::Tap,
-					7 => note_to_modify.chord = [false, false, false, false, false],
+				match (layout, note.note) {
+					(FretLayout::Five, 0) => note_to_modify.chord[Fret::G.lane()] = true,
+					(FretLayout::Five, 1) => note_to_modify.chord[Fret::R.lane()] = true,
+					(FretLayout::Five, 2) => note_to_modify.chord[Fret::Y.lane()] = true,
+					(FretLayout::Five, 3) => note_to_modify.chord[Fret::B.lane()] = true,
+					(FretLayout::Five, 4) => note_to_modify.chord[Fret::O.lane()] = true,
+					(FretLayout::Six, 0) => note_to_modify.chord[Fret::W1.lane()] = true,
+					(FretLayout::Six, 1) => note_to_modify.chord[Fret::W2.lane()] = true,
+					(FretLayout::Six, 2) => note_to_modify.chord[Fret::W3.lane()] = true,
+					(FretLayout::Six, 3) => note_to_modify.chord[Fret::B1.lane()] = true,
+					(FretLayout::Six, 4) => note_to_modify.chord[Fret::B2.lane()] = true,
+					(FretLayout::Six, 8) => note_to_modify.chord[Fret::B3.lane()] = true,
+					(_, 5) => note_to_modify.strum_type = GuitarNoteStrumType::Hopo, // TODO: this really means FORCE - calculate hopo or not
+					(_, 6) => note_to_modify.strum_type = GuitarNoteStrumType::Tap,
+					(_, 7) => note_to_modify.chord = [false; MAX_LANES],
 					_ => (), // TODO: warn or something
 				}
 
 				return notes;
-			})
+			}),
+			sp_phrases: StarPowerPhrase::from_part(part),
+			sections: Section::from_chart(&chart),
+			end_ticks: chart.end_ticks(),
 		};
 
+		for index in 0..guitar_chart.notes.len() {
+			let ticks = guitar_chart.notes[index].ticks;
+			guitar_chart.notes[index].sp_phrase = guitar_chart.sp_phrases.iter().position(|phrase| phrase.contains(ticks));
+			guitar_chart.notes[index].section = guitar_chart.section_at_ticks(ticks);
+		}
+
+		settings.modifiers.apply(&mut guitar_chart);
+
+		let section_stats = guitar_chart.sections.iter().enumerate().map(|(index, section)| SectionStats {
+			name: section.name.clone(),
+			notes_hit: 0,
+			notes_missed: 0,
+			notes_total: guitar_chart.notes.iter().filter(|note| note.section == Some(index)).count() as u64,
+			score: 0,
+		}).collect();
+		let state = PlaythroughState::new(guitar_chart.notes.len(), section_stats, -settings.lead_in_ms, settings.rock_meter.start);
+		let sections_count = guitar_chart.sections.len();
+
 		return Ok(GuitarPlaythrough {
+			instrument: instrument,
+			difficulty: difficulty,
 			chart: guitar_chart,
-			score: 0,
-			notes_hit: 0,
-			streak: 0,
-			sp_phrases: 0,
-			avg_multiplier: 0,
-			frets: [false, false, false, false, false],
-			time: 0.0, // TODO: negative start time?
+			settings: settings,
+			state: state,
+			section_overstrums: vec![0; sections_count],
+			frets: [false; MAX_LANES],
 			next_note_index: 0,
+			whammy: 0.0,
+			tilted: false,
+			sp_phrase_broken: None,
+			sustain_note_index: None,
+			sustain_credited_to_beat: 0.0,
+			whammy_moved_ms: std::f32::NEG_INFINITY,
+			last_fret_hit_ms: None,
+			last_strum_hit: None,
+			ghosted: false,
 		})
 	}
 }
 
+#[derive(Copy, Clone)]
 pub enum GuitarInputAction {
 	FretDown(Fret),
 	FretUp(Fret),
 	Strum,
+	StarPower,
+	/* Whammy bar position, from 0 (at rest) to 1 (fully pressed) */
+	Whammy(f32),
+	/* Tilt of the guitar neck, from 0 (level) to 1 (pointing straight up) */
+	Tilt(f32),
 }
 
 // when a note is hit (strum and earlier fret
@@ -142,88 +676,1195 @@ pub enum GuitarInputAction {
 //    if enabled, play a miss sound
 // when you release a sustain:
 //    mute track
+// when every note of a star power phrase is hit:
+//    fill the star power meter, play the phrase sound
+// when star power is activated / runs out:
+//    light up / restore the highway
+// when every note is resolved and the chart or its audio has ended:
+//    show the results
+// when the rock meter runs out (unless no-fail is on):
+//    stop the song, show the results
+#[derive(Clone, PartialEq, Debug)]
 pub enum GuitarGameEffect {
-	Hit,
+	Hit { offset_ms: f32, grade: TimingGrade }, // offset is negative when early
 	Overstrum,
 	MissStreak,
 	MissNoStreak,
 	ReleaseSustain,
+	StarPowerPhraseComplete { phrase: usize }, // index into the chart's sp_phrases
+	StarPowerActivated,
+	StarPowerDeactivated,
+	SongComplete(GuitarResults),
+	Failed(GuitarResults),
 }
 
-const HALF_HIT_WINDOW_MS: f32 = 40.0;
+const PERFECT_WINDOW_FRACTION: f32 = 0.35;
+const GREAT_WINDOW_FRACTION: f32 = 0.7;
 
-fn frets_match(frets: [bool; 5], chord: [bool; 5]) -> bool {
-	return frets[0] == chord[0]
-		&& frets[1] == chord[1]
-		&& frets[2] == chord[2]
-		&& frets[3] == chord[3]
-		&& frets[4] == chord[4];
+const NOTE_SCORE: u64 = 50;
+
+const SP_WHAMMY_BEATS: f32 = 32.0; // beats of whammied star power sustain that fill the meter
+pub const WHAMMY_HOLD_MS: f32 = 100.0; // the bar counts as whammying for this long after it last moved
+const WHAMMY_MOVE_THRESHOLD: f32 = 0.02;
+const TILT_ACTIVATION_THRESHOLD: f32 = 0.5;
+
+const STRUM_LENIENCY_MS: f32 = 60.0;
+const DOUBLE_STRUM_MS: f32 = 60.0;
+
+// A single note may be anchored: frets in columns below it can be held as well (find_hit prefers an exact match).
+// On six frets that is either fret of a lower column, but not the other fret of the note's own column.
+// A chord (or open note) needs exactly its frets held; on six frets that includes barre chords,
+// which hold both frets of a column.
+fn frets_match(layout: FretLayout, frets: [bool; MAX_LANES], chord: [bool; MAX_LANES]) -> bool {
+	let mut chord_frets = chord.iter().enumerate().filter(|(_i, c)| **c).map(|(i, _c)| i);
+
+	match (chord_frets.next(), chord_frets.next()) {
+		(Some(fret), None) => frets[fret] && frets.iter().enumerate()
+			.filter(|(i, f)| **f && *i != fret)
+			.all(|(i, _f)| layout.column(i) < layout.column(fret)),
+		_ => frets == chord,
+	}
 }
 
 impl GuitarPlaythrough {
-	pub fn apply(self: &mut Self, action: &GuitarInputAction, time_ms: f32) -> Option<GuitarGameEffect> {
+	/* The hit windows judged with, after modifiers */
+	pub fn hit_window(self: &Self) -> HitWindow {
+		self.settings.modifiers.hit_window(self.settings.hit_window)
+	}
+
+	pub fn results(self: &Self) -> GuitarResults {
+		GuitarResults {
+			score: self.state.score,
+			notes_hit: self.state.notes_hit,
+			notes_missed: self.state.notes_missed,
+			notes_total: self.chart.notes.len() as u64,
+			best_streak: self.state.best_streak,
+			sp_phrases: self.state.sp_phrases,
+			failed: self.state.failed,
+			timing: self.state.timing.clone(),
+			sections: self.state.section_stats.clone(),
+			section_overstrums: self.section_overstrums.clone(),
+			modifiers: self.settings.modifiers,
+		}
+	}
+
+	/* Tells the playthrough that the song's audio has finished playing */
+	pub fn end_audio(self: &mut Self) {
+		self.state.end_audio();
+	}
+
+	fn adjust_rock_meter(self: &mut Self, delta: f32) -> Vec<GuitarGameEffect> {
+		if self.state.adjust_rock_meter(delta, self.settings.no_fail) { self.fail() } else { vec![] }
+	}
+
+	/* Ends the song as failed, as the rock meter running out does */
+	pub fn fail(self: &mut Self) -> Vec<GuitarGameEffect> {
+		if self.state.fail() { vec![GuitarGameEffect::Failed(self.results())] } else { vec![] }
+	}
+
+	fn hit_next_note(self: &mut Self, offset_ms: f32) -> Vec<GuitarGameEffect> {
+		let grade = self.hit_window().grade(offset_ms);
+
+		let note = &self.chart.notes[self.next_note_index];
+		let gems = std::cmp::max(note.chord.iter().filter(|c| **c).count() as u64, 1);
+		let phrase = note.sp_phrase;
+		let section = note.section;
+
+		if note.duration > 0 {
+			self.sustain_note_index = Some(self.next_note_index);
+			self.sustain_credited_to_beat = note.ticks as f32 / self.chart.tempo_map.ticks_per_beat as f32;
+		} else {
+			self.sustain_note_index = None;
+		}
+
+		let note_index = self.next_note_index;
+		self.next_note_index += 1;
+
+		let phrase_ended = phrase.is_some()
+			&& self.chart.notes.get(self.next_note_index).map_or(true, |next| next.sp_phrase != phrase);
+		let completed_phrase = phrase_ended && self.sp_phrase_broken != phrase;
+
+		let points = NOTE_SCORE * gems * self.state.multiplier();
+		self.state.record_hit(note_index, section, points, offset_ms, grade, completed_phrase);
+
+		let mut effects = vec![GuitarGameEffect::Hit { offset_ms: offset_ms, grade: grade }];
+
+		let rock_meter_gain = self.state.rock_meter_hit_gain(&self.settings.rock_meter);
+		effects.extend(self.adjust_rock_meter(rock_meter_gain));
+
+		match phrase {
+			Some(phrase) if completed_phrase => effects.push(GuitarGameEffect::StarPowerPhraseComplete { phrase: phrase }),
+			_ => (),
+		}
+
+		effects
+	}
+
+	/* Marks every unresolved note before note_index as missed */
+	fn miss_notes_before(self: &mut Self, note_index: usize) -> Vec<GuitarGameEffect> {
+		if note_index <= self.next_note_index {
+			return vec![];
+		}
+
+		let missed = (note_index - self.next_note_index) as f32;
+
+		for index in self.next_note_index..note_index {
+			let note = &self.chart.notes[index];
+			if note.sp_phrase.is_some() {
+				self.sp_phrase_broken = note.sp_phrase;
+			}
+			self.state.record_miss(index, note.section);
+		}
+		self.next_note_index = note_index;
+
+		let effect = if self.state.break_streak() { GuitarGameEffect::MissStreak } else { GuitarGameEffect::MissNoStreak };
+
+		let mut effects = vec![effect];
+		effects.extend(self.adjust_rock_meter(-self.settings.rock_meter.miss_loss * missed));
+		effects
+	}
+
+	// Hit window search
+	//
+	// A strum contests every unresolved note whose hit window contains the strum time,
+	// not only the next one. Of those, the earliest note whose chord matches the held frets is hit.
+	//  - a chord matches only when exactly its frets are held, so a strum aimed at a chord
+	//    never hits an earlier single note on one of the chord's frets, and vice versa.
+	//  - a single note also matches while frets in lower columns are held (anchoring), but only
+	//    when no note in the window matches the held frets exactly. Holding G+Y for a chord
+	//    after a single Y hits the chord, not the Y anchored under a G.
+	//  - when several notes in the window match (e.g. a repeated chord), the earliest is hit
+	//    so that a late strum doesn't skip a note it could still have hit
+	//  - every unresolved note before the hit note is missed, breaking the streak and any
+	//    star power phrase it belongs to, before the hit is scored
+	// A strum that matches nothing in the window is an overstrum.
+	//
+	// A fret press or release searches the same window, but without strumming:
+	//  - a tap can always be hit this way, and skips earlier notes just like a strum
+	//  - a HOPO can only be hit this way if it is the next unresolved note and the streak is
+	//    unbroken, so a HOPO chain has to be entered by hitting the note before it
+	//  - on six frets, moving between the frets of one column (e.g. W1 to B1, or a barre to
+	//    either of its frets) is a fret change like any other, so it can hit a HOPO or tap.
+	//    Both rows of lower columns may be held while hitting a single-note HOPO.
+	// A fret change that matches nothing is not penalized, but see ghost protection below.
+	//
+	// Notes closer together than the hit window have overlapping windows. One input only
+	// ever resolves one note, and the rules above decide which; the next input contests the rest.
+	fn find_hit(self: &Self, time_ms: f32, strummed: bool) -> Option<(usize, f32)> {
+		let hit_window = self.hit_window();
+
+		let candidates: Vec<(usize, &GuitarNote, f32)> = self.chart.notes.iter()
+			.enumerate()
+			.skip(self.next_note_index)
+			.map(|(index, note)| (index, note, time_ms - self.chart.ticks_to_ms(note.ticks)))
+			.skip_while(|(_index, _note, offset_ms)| *offset_ms > hit_window.late_ms)
+			.take_while(|(_index, _note, offset_ms)| *offset_ms >= -hit_window.early_ms)
+			.filter(|(index, note, _offset_ms)| strummed || match note.strum_type {
+				GuitarNoteStrumType::Strum => false,
+				GuitarNoteStrumType::Hopo => *index == self.next_note_index && self.state.streak > 0 && !self.ghosted,
+				GuitarNoteStrumType::Tap => true,
+			})
+			.filter(|(_index, note, _offset_ms)| frets_match(self.chart.layout, self.frets, note.chord))
+			.collect();
+
+		candidates.iter()
+			.find(|(_index, note, _offset_ms)| note.chord == self.frets)
+			.or_else(|| candidates.first())
+			.map(|(index, _note, offset_ms)| (*index, *offset_ms))
+	}
+
+	// Leniency rules, each toggled in GuitarSettings
+	//  - strum leniency: a strum that hits nothing within STRUM_LENIENCY_MS after a HOPO or tap
+	//    was hit with the frets is ignored once, since players often strum notes they already hit
+	//  - double-strum protection: a strum that hits nothing within DOUBLE_STRUM_MS after strumming
+	//    a note, while that note's frets are still held, is ignored once
+	//  - ghost protection: pressing a fret that is not part of the next note (nor in a lower column,
+	//    for a single note) without hitting anything is a ghost input. HOPOs then need a strum until
+	//    the next hit, so mashing frets can't play through a HOPO chain.
+	fn is_forgiven_strum(self: &mut Self, time_ms: f32) -> bool {
+		if self.settings.strum_leniency {
+			if let Some(hit_ms) = self.last_fret_hit_ms.take() {
+				if time_ms - hit_ms <= STRUM_LENIENCY_MS {
+					return true;
+				}
+			}
+		}
+
+		if self.settings.double_strum_protection {
+			if let Some((note_index, hit_ms)) = self.last_strum_hit.take() {
+				if time_ms - hit_ms <= DOUBLE_STRUM_MS && frets_match(self.chart.layout, self.frets, self.chart.notes[note_index].chord) {
+					return true;
+				}
+			}
+		}
+
+		false
+	}
+
+	fn is_ghost_input(self: &Self, fret: Fret) -> bool {
+		self.settings.ghost_protection && self.chart.notes.get(self.next_note_index).map_or(false, |note| {
+			let layout = self.chart.layout;
+			let highest_fret = note.chord.iter().rposition(|c| *c);
+			let single = note.chord.iter().filter(|c| **c).count() == 1;
+			let anchor = single && highest_fret.map_or(false, |highest| layout.column(fret.lane()) < layout.column(highest));
+			!note.chord[fret.lane()] && !anchor
+		})
+	}
+
+	fn hit(self: &mut Self, note_index: usize, offset_ms: f32) -> Vec<GuitarGameEffect> {
+		self.ghosted = false;
+
+		let mut effects = self.miss_notes_before(note_index);
+		effects.extend(self.hit_next_note(offset_ms));
+		effects
+	}
+
+	fn activate_star_power(self: &mut Self, time_ms: f32) -> Vec<GuitarGameEffect> {
+		if !self.state.activate_sp(self.chart.ms_to_beats(time_ms)) {
+			return vec![];
+		}
+
+		let mut effects = vec![GuitarGameEffect::StarPowerActivated];
+		effects.extend(self.adjust_rock_meter(self.settings.rock_meter.sp_activation_gain));
+		effects
+	}
+
+	/* Updates controller state without judging anything */
+	fn track_input(self: &mut Self, action: &GuitarInputAction) {
+		match action {
+			GuitarInputAction::FretDown(fret) => self.frets[fret.lane()] = true,
+			GuitarInputAction::FretUp(fret) => self.frets[fret.lane()] = false,
+			GuitarInputAction::Whammy(position) => self.whammy = *position,
+			GuitarInputAction::Tilt(tilt) => self.tilted = *tilt >= TILT_ACTIVATION_THRESHOLD,
+			GuitarInputAction::Strum | GuitarInputAction::StarPower => (),
+		}
+	}
+
+	pub fn apply(self: &mut Self, action: &GuitarInputAction, time_ms: f32) -> Vec<GuitarGameEffect> {
+		if self.state.failed || !self.state.judging(time_ms) {
+			self.track_input(action);
+			return vec![];
+		}
+		let time_ms = time_ms - self.settings.audio_latency_ms;
+
 		match action {
 			GuitarInputAction::FretDown(fret) => {
-				// TODO: taps/hopos
-				self.frets[*fret as usize] = true;
-				None
+				self.frets[fret.lane()] = true;
+
+				match self.find_hit(time_ms, false) {
+					Some((note_index, offset_ms)) => {
+						self.last_fret_hit_ms = Some(time_ms);
+						self.hit(note_index, offset_ms)
+					},
+					None => {
+						if self.is_ghost_input(*fret) {
+							self.ghosted = true;
+						}
+						vec![]
+					},
+				}
 			},
 			GuitarInputAction::FretUp(fret) => {
-				self.frets[*fret as usize] = false;
-				None
-			},
-			GuitarInputAction::Strum => {
-				if self.next_note_index >= self.chart.notes.len() {
-					return Some(GuitarGameEffect::Overstrum);
+				self.frets[fret.lane()] = false;
+
+				let released = self.sustain_note_index
+					.map_or(false, |index| self.chart.notes[index].chord[fret.lane()]);
+
+				let mut effects = if released {
+					self.sustain_note_index = None;
+					vec![GuitarGameEffect::ReleaseSustain]
+				} else {
+					vec![]
+				};
+
+				// pull-offs
+				if let Some((note_index, offset_ms)) = self.find_hit(time_ms, false) {
+					self.last_fret_hit_ms = Some(time_ms);
+					effects.extend(self.hit(note_index, offset_ms));
 				}
 
-				let note = &self.chart.notes[self.next_note_index];
+				effects
+			},
+			GuitarInputAction::Strum => {
+				match self.find_hit(time_ms, true) {
+					Some((note_index, offset_ms)) => {
+						self.last_fret_hit_ms = None;
+						self.last_strum_hit = Some((note_index, time_ms));
+						self.hit(note_index, offset_ms)
+					},
+					None if self.is_forgiven_strum(time_ms) => vec![],
+					None => {
+						self.state.break_streak();
+						if let Some(section) = self.chart.section_at_ms(time_ms) {
+							self.section_overstrums[section] += 1;
+						}
 
-				let fretted = frets_match(self.frets, note.chord);
-				let on_time = f32::abs(time_ms - self.chart.ticks_to_ms(note.ticks)) <= HALF_HIT_WINDOW_MS;
+						let mut effects = vec![GuitarGameEffect::Overstrum];
+						effects.extend(self.adjust_rock_meter(-self.settings.rock_meter.overstrum_loss));
+						effects
+					},
+				}
+			},
+			GuitarInputAction::StarPower => self.activate_star_power(time_ms),
+			GuitarInputAction::Whammy(position) => {
+				if f32::abs(position - self.whammy) >= WHAMMY_MOVE_THRESHOLD {
+					self.whammy = *position;
+					self.whammy_moved_ms = time_ms;
+				}
+				vec![]
+			},
+			GuitarInputAction::Tilt(tilt) => {
+				let was_tilted = self.tilted;
+				self.tilted = *tilt >= TILT_ACTIVATION_THRESHOLD;
 
-				if fretted && on_time {
-					self.notes_hit += 1;
-					self.streak += 1;
-					self.next_note_index += 1;
-					None
+				if self.tilted && !was_tilted {
+					self.activate_star_power(time_ms)
 				} else {
-					self.streak = 0;
-					Some(GuitarGameEffect::Overstrum)
+					vec![]
 				}
+			},
+		}
+	}
+
+	/* Moves the playthrough to time_ms, forwards or backwards, without judging the notes in between.
+	 * Notes from time_ms on can be hit again: however they were judged before is taken back out of
+	 * the score and statistics, so no note is ever counted twice, and the star power a phrase among them
+	 * gave is taken back out of the meter. Streaks, active star power and the rock meter are kept as they are. */
+	pub fn seek(self: &mut Self, time_ms: f32) {
+		let judged_ms = time_ms - self.settings.audio_latency_ms;
+		let late_ms = self.hit_window().late_ms;
+		self.next_note_index = self.chart.notes.iter()
+			.position(|note| self.chart.ticks_to_ms(note.ticks) >= judged_ms - late_ms)
+			.unwrap_or(self.chart.notes.len());
+
+		for index in self.next_note_index..self.chart.notes.len() {
+			self.state.take_back_judgement(index, self.chart.notes[index].section);
+		}
+
+		// a phrase can only be completed if the seek landed before its first note
+		let landed_in_phrase = match (self.next_note_index.checked_sub(1).map(|i| &self.chart.notes[i]), self.chart.notes.get(self.next_note_index)) {
+			(Some(previous), Some(next)) => next.sp_phrase.is_some() && previous.sp_phrase == next.sp_phrase,
+			_ => false,
+		};
+		self.sp_phrase_broken = if landed_in_phrase { self.chart.notes[self.next_note_index].sp_phrase } else { None };
+
+		self.sustain_note_index = None;
+		self.last_fret_hit_ms = None;
+		self.last_strum_hit = None;
+		self.ghosted = false;
+		self.whammy_moved_ms = std::f32::NEG_INFINITY;
+
+		self.state.seek(time_ms, self.chart.ms_to_beats(judged_ms));
+	}
+
+	pub fn time_ms(self: &Self) -> f32 {
+		self.state.time_ms()
+	}
+
+	/* The song clock starts here, before the chart does */
+	pub fn start_time_ms(self: &Self) -> f32 {
+		-self.settings.lead_in_ms
+	}
+
+	pub fn pause(self: &mut Self) {
+		// the player lets go of the guitar
+		self.sustain_note_index = None;
+	}
+
+	/* Rewinds from the paused point and returns the time the song clock should restart from.
+	 * The rewound notes are already judged, so nothing is judged again until the paused point. */
+	pub fn resume(self: &mut Self) -> f32 {
+		let start_time_ms = self.start_time_ms();
+		self.state.resume(self.settings.pause_rewind_ms, start_time_ms)
+	}
+
+	/* Time left on the resume countdown, if it is showing */
+	pub fn countdown_ms(self: &Self) -> Option<f32> {
+		self.state.countdown_ms(self.settings.resume_countdown_ms)
+	}
+
+	pub fn update_time(self: &mut Self, time_ms: f32) -> Vec<GuitarGameEffect> {
+		self.state.set_time_ms(time_ms);
+
+		if self.state.failed {
+			return vec![];
+		}
+		let time_ms = time_ms - self.settings.audio_latency_ms;
+
+		let mut effects = Vec::new();
+
+		if let Some(index) = self.sustain_note_index {
+			let note = &self.chart.notes[index];
+			let end_ms = self.chart.ticks_to_ms(note.ticks + note.duration);
+			let beat = f32::max(self.chart.ms_to_beats(f32::min(time_ms, end_ms)), self.sustain_credited_to_beat);
+
+			let whammying = time_ms - self.whammy_moved_ms <= WHAMMY_HOLD_MS;
+			if whammying && note.sp_phrase.is_some() && self.sp_phrase_broken != note.sp_phrase {
+				self.state.sp_meter = f32::min(self.state.sp_meter + (beat - self.sustain_credited_to_beat) / SP_WHAMMY_BEATS, 1.0);
+			}
+			self.sustain_credited_to_beat = beat;
+
+			if time_ms >= end_ms {
+				self.sustain_note_index = None;
 			}
 		}
+
+		if self.state.drain_sp(self.chart.ms_to_beats(time_ms)) {
+			effects.push(GuitarGameEffect::StarPowerDeactivated);
+		}
+
+		let late_ms = self.hit_window().late_ms;
+		let first_hittable_index = self.chart.notes.iter()
+			.enumerate()
+			.skip(self.next_note_index)
+			.find(|(_index, note)| self.chart.ticks_to_ms(note.ticks) >= time_ms - late_ms)
+			.map_or(self.chart.notes.len(), |(index, _note)| index);
+
+		effects.extend(self.miss_notes_before(first_hittable_index));
+
+		// the song is over at the chart's end event, or without one, once the audio has ended
+		let ended = self.state.audio_ended() || self.chart.end_ticks.map_or(false, |ticks| time_ms >= self.chart.ticks_to_ms(ticks));
+		if ended && self.next_note_index >= self.chart.notes.len() && self.sustain_note_index.is_none() && self.state.complete() {
+			effects.push(GuitarGameEffect::SongComplete(self.results()));
+		}
+
+		effects
 	}
+}
 
-	// TODO: must handle pause -> set time back 5 seconds
-	pub fn update_time(self: &mut Self, time_ms: f32) -> Option<GuitarGameEffect> {
-		self.time = time_ms;
+impl PlaythroughEffect for GuitarGameEffect {
+	type Results = GuitarResults;
 
-		if self.next_note_index >= self.chart.notes.len() {
-			return None;
+	fn results(self: &Self) -> Option<GuitarResults> {
+		match self {
+			GuitarGameEffect::SongComplete(results) | GuitarGameEffect::Failed(results) => Some(results.clone()),
+			_ => None,
+		}
+	}
+}
+
+impl Playthrough for GuitarPlaythrough {
+	type InputAction = GuitarInputAction;
+	type Results = GuitarResults;
+	type Effect = GuitarGameEffect;
+
+	fn apply(self: &mut Self, action: &GuitarInputAction, time_ms: f32) -> Vec<GuitarGameEffect> {
+		GuitarPlaythrough::apply(self, action, time_ms)
+	}
+
+	fn update_time(self: &mut Self, time_ms: f32) -> Vec<GuitarGameEffect> {
+		GuitarPlaythrough::update_time(self, time_ms)
+	}
+
+	fn time_ms(self: &Self) -> f32 {
+		GuitarPlaythrough::time_ms(self)
+	}
+
+	fn start_time_ms(self: &Self) -> f32 {
+		GuitarPlaythrough::start_time_ms(self)
+	}
+
+	fn audio_offset_ms(self: &Self) -> f32 {
+		self.chart.audio_offset_ms
+	}
+
+	fn seek(self: &mut Self, time_ms: f32) {
+		GuitarPlaythrough::seek(self, time_ms)
+	}
+
+	fn pause(self: &mut Self) {
+		GuitarPlaythrough::pause(self)
+	}
+
+	fn resume(self: &mut Self) -> f32 {
+		GuitarPlaythrough::resume(self)
+	}
+
+	fn countdown_ms(self: &Self) -> Option<f32> {
+		GuitarPlaythrough::countdown_ms(self)
+	}
+
+	fn end_audio(self: &mut Self) {
+		GuitarPlaythrough::end_audio(self)
+	}
+
+	fn score(self: &Self) -> u64 {
+		self.state.score
+	}
+
+	fn streak(self: &Self) -> u64 {
+		self.state.streak
+	}
+
+	fn multiplier(self: &Self) -> u64 {
+		self.state.multiplier()
+	}
+
+	fn sp_meter(self: &Self) -> f32 {
+		self.state.sp_meter
+	}
+
+	fn sp_active(self: &Self) -> bool {
+		self.state.sp_active
+	}
+
+	fn rock_meter(self: &Self) -> f32 {
+		self.state.rock_meter
+	}
+
+	fn failed(self: &Self) -> bool {
+		self.state.failed
+	}
+
+	fn results(self: &Self) -> GuitarResults {
+		GuitarPlaythrough::results(self)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::playthrough::SP_PHRASE_METER;
+
+	// 120 BPM at 192 ticks per beat, so tick 192 is at 500 ms. Notes 12 ticks (31.25 ms) apart
+	// have overlapping Expert hit windows.
+	fn playthrough(notes: &str) -> GuitarPlaythrough {
+		playthrough_with(notes, GuitarSettings::for_difficulty(&chart::Difficulty::Expert))
+	}
+
+	fn playthrough_with(notes: &str, settings: GuitarSettings) -> GuitarPlaythrough {
+		playthrough_with_events("", notes, settings)
+	}
+
+	fn playthrough_with_events(events: &str, notes: &str, settings: GuitarSettings) -> GuitarPlaythrough {
+		let chart_file = format!("[Song]\n{{\n  Resolution = 192\n}}\n[SyncTrack]\n{{\n  0 = B 120000\n}}\n[Events]\n{{\n{}}}\n[ExpertSingle]\n{{\n{}}}\n", events, notes);
+		let chart = chart::read(&chart_file).unwrap();
+		GuitarPlaythrough::new(chart, chart::Instrument::Guitar, chart::Difficulty::Expert, settings).unwrap()
+	}
+
+	fn six_fret_playthrough(notes: &str) -> GuitarPlaythrough {
+		let chart_file = format!("[Song]\n{{\n  Resolution = 192\n}}\n[SyncTrack]\n{{\n  0 = B 120000\n}}\n[ExpertGHLGuitar]\n{{\n{}}}\n", notes);
+		let chart = chart::read(&chart_file).unwrap();
+		GuitarPlaythrough::new(chart, chart::Instrument::GHLGuitar, chart::Difficulty::Expert, default_settings()).unwrap()
+	}
+
+	fn hold(playthrough: &mut GuitarPlaythrough, frets: &[Fret]) {
+		playthrough.frets = [false; MAX_LANES];
+		for fret in frets {
+			playthrough.frets[fret.lane()] = true;
+		}
+	}
+
+	fn strum(playthrough: &mut GuitarPlaythrough, frets: &[Fret], ticks: u64) -> Vec<GuitarGameEffect> {
+		hold(playthrough, frets);
+		let time_ms = playthrough.chart.ticks_to_ms(ticks);
+		playthrough.update_time(time_ms);
+		playthrough.apply(&GuitarInputAction::Strum, time_ms)
+	}
+
+	fn act(playthrough: &mut GuitarPlaythrough, action: GuitarInputAction, time_ms: f32) -> Vec<GuitarGameEffect> {
+		playthrough.update_time(time_ms);
+		playthrough.apply(&action, time_ms)
+	}
+
+	fn hits(effects: &[GuitarGameEffect]) -> usize {
+		effects.iter().filter(|effect| match effect { GuitarGameEffect::Hit { .. } => true, _ => false }).count()
+	}
+
+	fn overstrums(effects: &[GuitarGameEffect]) -> usize {
+		effects.iter().filter(|effect| match effect { GuitarGameEffect::Overstrum => true, _ => false }).count()
+	}
+
+	// hits a HOPO with the frets, then strums twice just after it
+	fn strums_after_hopo(settings: GuitarSettings) -> (usize, usize) {
+		let mut playthrough = playthrough_with("  192 = N 0 0\n  288 = N 1 0\n  288 = N 5 0\n", settings);
+		strum(&mut playthrough, &[Fret::G], 192);
+		assert_eq!(hits(&act(&mut playthrough, GuitarInputAction::FretDown(Fret::R), 750.0)), 1);
+
+		let first = act(&mut playthrough, GuitarInputAction::Strum, 770.0);
+		let second = act(&mut playthrough, GuitarInputAction::Strum, 780.0);
+		(overstrums(&first), overstrums(&second))
+	}
+
+	// strums a note, then strums twice more while still holding it
+	fn strums_after_strum(settings: GuitarSettings) -> (usize, usize) {
+		let mut playthrough = playthrough_with("  192 = N 0 0\n", settings);
+		assert_eq!(hits(&strum(&mut playthrough, &[Fret::G], 192)), 1);
+
+		let first = act(&mut playthrough, GuitarInputAction::Strum, 520.0);
+		let second = act(&mut playthrough, GuitarInputAction::Strum, 530.0);
+		(overstrums(&first), overstrums(&second))
+	}
+
+	// presses a fret that isn't part of the next HOPO, then lets go and tries to hit the HOPO with the frets
+	fn hopo_hits_after_ghost(settings: GuitarSettings) -> usize {
+		let mut playthrough = playthrough_with("  192 = N 0 0\n  288 = N 1 0\n  288 = N 5 0\n", settings);
+		strum(&mut playthrough, &[Fret::G], 192);
+		act(&mut playthrough, GuitarInputAction::FretDown(Fret::Y), 600.0);
+		act(&mut playthrough, GuitarInputAction::FretUp(Fret::Y), 650.0);
+		hits(&act(&mut playthrough, GuitarInputAction::FretDown(Fret::R), 750.0))
+	}
+
+	fn default_settings() -> GuitarSettings {
+		GuitarSettings::for_difficulty(&chart::Difficulty::Expert)
+	}
+
+	fn assert_close(actual: f32, expected: f32) {
+		assert!(f32::abs(actual - expected) < 1e-5, "{} is not {}", actual, expected);
+	}
+
+	fn completions(effects: &[GuitarGameEffect]) -> usize {
+		effects.iter().filter(|effect| match effect { GuitarGameEffect::SongComplete(_) => true, _ => false }).count()
+	}
+
+	fn failures(effects: &[GuitarGameEffect]) -> usize {
+		effects.iter().filter(|effect| match effect { GuitarGameEffect::Failed(_) => true, _ => false }).count()
+	}
+
+	// misses the first two of four notes, starting with the rock meter just above one miss, then strums the third
+	fn miss_until_failing(no_fail: bool) -> (GuitarPlaythrough, usize) {
+		let settings = GuitarSettings {
+			rock_meter: RockMeterSettings { start: 0.06, ..RockMeterSettings::default() },
+			no_fail: no_fail,
+			..default_settings()
+		};
+		let mut playthrough = playthrough_with("  192 = N 0 0\n  384 = N 0 0\n  576 = N 0 0\n  768 = N 0 0\n", settings);
+
+		let mut effects = playthrough.update_time(800.0);
+		effects.extend(playthrough.update_time(1100.0));
+		effects.extend(strum(&mut playthrough, &[Fret::G], 576));
+		effects.extend(playthrough.update_time(1400.0));
+		(playthrough, failures(&effects))
+	}
+
+	#[test]
+	fn strum_at_second_note_in_window_hits_it_and_misses_the_first() {
+		let mut playthrough = playthrough("  192 = N 0 0\n  204 = N 1 0\n");
+		let effects = strum(&mut playthrough, &[Fret::R], 204);
+
+		assert_eq!(hits(&effects), 1);
+		assert_eq!(playthrough.state.notes_hit, 1);
+		assert_eq!(playthrough.state.notes_missed, 1);
+		assert_eq!(playthrough.next_note_index, 2);
+	}
+
+	#[test]
+	fn chord_strum_skips_earlier_single_note_on_its_fret() {
+		let mut playthrough = playthrough("  192 = N 0 0\n  204 = N 0 0\n  204 = N 2 0\n");
+		strum(&mut playthrough, &[Fret::G, Fret::Y], 204);
+
+		assert_eq!(playthrough.state.notes_hit, 1);
+		assert_eq!(playthrough.state.notes_missed, 1);
+		assert_eq!(playthrough.next_note_index, 2);
+	}
+
+	#[test]
+	fn single_note_strum_skips_later_chord_on_its_fret() {
+		let mut playthrough = playthrough("  192 = N 0 0\n  204 = N 0 0\n  204 = N 2 0\n");
+		strum(&mut playthrough, &[Fret::G], 204);
+
+		assert_eq!(playthrough.state.notes_hit, 1);
+		assert_eq!(playthrough.state.notes_missed, 0);
+		assert_eq!(playthrough.next_note_index, 1);
+	}
+
+	#[test]
+	fn hopo_chain_is_entered_only_by_hitting_the_note_before_it() {
+		let notes = "  192 = N 0 0\n  288 = N 1 0\n  288 = N 5 0\n  384 = N 2 0\n  384 = N 5 0\n";
+
+		// the first note is missed, so the HOPO after it needs a strum
+		let mut missed = playthrough(notes);
+		let time_ms = missed.chart.ticks_to_ms(288);
+		missed.update_time(time_ms);
+		let effects = missed.apply(&GuitarInputAction::FretDown(Fret::R), time_ms);
+		assert_eq!(hits(&effects), 0);
+		assert_eq!(missed.state.notes_missed, 1);
+		assert_eq!(hits(&missed.apply(&GuitarInputAction::Strum, time_ms)), 1);
+
+		// hitting the first note lets the frets alone play the chain
+		let mut entered = playthrough(notes);
+		strum(&mut entered, &[Fret::G], 192);
+		for (fret, ticks) in [(Fret::R, 288), (Fret::Y, 384)].iter() {
+			let time_ms = entered.chart.ticks_to_ms(*ticks);
+			entered.update_time(time_ms);
+			entered.apply(&GuitarInputAction::FretUp(Fret::G), time_ms);
+			entered.apply(&GuitarInputAction::FretUp(Fret::R), time_ms);
+			assert_eq!(hits(&entered.apply(&GuitarInputAction::FretDown(*fret), time_ms)), 1);
+		}
+		assert_eq!(entered.state.notes_hit, 3);
+		assert_eq!(entered.state.notes_missed, 0);
+	}
+
+	#[test]
+	fn chord_strum_skips_earlier_anchored_single_note() {
+		let mut playthrough = playthrough("  192 = N 2 0\n  204 = N 0 0\n  204 = N 2 0\n");
+		strum(&mut playthrough, &[Fret::G, Fret::Y], 204);
+
+		assert_eq!(playthrough.state.notes_hit, 1);
+		assert_eq!(playthrough.state.notes_missed, 1);
+		assert_eq!(playthrough.next_note_index, 2);
+	}
+
+	#[test]
+	fn anchored_single_note_is_hit_when_nothing_matches_exactly() {
+		let mut playthrough = playthrough("  192 = N 2 0\n  204 = N 1 0\n  204 = N 2 0\n");
+		strum(&mut playthrough, &[Fret::G, Fret::Y], 204);
+
+		assert_eq!(playthrough.state.notes_hit, 1);
+		assert_eq!(playthrough.state.notes_missed, 0);
+		assert_eq!(playthrough.next_note_index, 1);
+	}
+
+	#[test]
+	fn hit_windows_narrow_as_difficulty_rises() {
+		let windows: Vec<HitWindow> = [chart::Difficulty::Easy, chart::Difficulty::Medium, chart::Difficulty::Hard, chart::Difficulty::Expert].iter()
+			.map(HitWindow::for_difficulty)
+			.collect();
+		for pair in windows.windows(2) {
+			assert!(pair[1].early_ms <= pair[0].early_ms && pair[1].late_ms <= pair[0].late_ms);
+		}
+		assert_eq!(windows[3], HitWindow { early_ms: 45.0, late_ms: 40.0 });
+	}
+
+	#[test]
+	fn strums_hit_up_to_the_edges_of_the_window() {
+		// the note is at 500 ms, and the Expert window runs from 455 to 540 ms
+		for &(time_ms, hit) in &[(454.0, false), (455.0, true), (540.0, true), (541.0, false)] {
+			let mut playthrough = playthrough("  192 = N 0 0\n");
+			hold(&mut playthrough, &[Fret::G]);
+			let effects = act(&mut playthrough, GuitarInputAction::Strum, time_ms);
+			assert_eq!(hits(&effects), if hit { 1 } else { 0 }, "strum at {} ms", time_ms);
+			assert_eq!(overstrums(&effects), if hit { 0 } else { 1 }, "strum at {} ms", time_ms);
+		}
+	}
+
+	#[test]
+	fn grades_use_each_side_of_the_window() {
+		let hit_window = HitWindow { early_ms: 100.0, late_ms: 50.0 };
+		let grades = [
+			(0.0, TimingGrade::Perfect),
+			(-35.0, TimingGrade::Perfect), (-36.0, TimingGrade::Great),
+			(-70.0, TimingGrade::Great), (-71.0, TimingGrade::Good),
+			(17.5, TimingGrade::Perfect), (18.0, TimingGrade::Great),
+			(35.0, TimingGrade::Great), (36.0, TimingGrade::Good),
+			(50.0, TimingGrade::Good),
+		];
+		for &(offset_ms, grade) in &grades {
+			assert_eq!(hit_window.grade(offset_ms), grade, "offset {} ms", offset_ms);
+		}
+	}
+
+	#[test]
+	fn strum_just_after_a_fret_hit_hopo_is_forgiven_once() {
+		assert_eq!(strums_after_hopo(default_settings()), (0, 1));
+	}
+
+	#[test]
+	fn second_strum_on_a_held_note_is_forgiven_once() {
+		assert_eq!(strums_after_strum(default_settings()), (0, 1));
+	}
+
+	#[test]
+	fn ghosted_fret_makes_the_next_hopo_need_a_strum() {
+		let mut playthrough = playthrough("  192 = N 0 0\n  288 = N 1 0\n  288 = N 5 0\n");
+		assert_eq!(hopo_hits_after_ghost(default_settings()), 0);
+
+		// the strum still hits it, and clears the ghost
+		strum(&mut playthrough, &[Fret::G], 192);
+		act(&mut playthrough, GuitarInputAction::FretDown(Fret::Y), 600.0);
+		act(&mut playthrough, GuitarInputAction::FretUp(Fret::Y), 650.0);
+		act(&mut playthrough, GuitarInputAction::FretDown(Fret::R), 750.0);
+		assert_eq!(hits(&act(&mut playthrough, GuitarInputAction::Strum, 750.0)), 1);
+		assert!(!playthrough.ghosted);
+	}
+
+	#[test]
+	fn each_lenience_setting_turns_its_rule_off() {
+		let strict_strums = GuitarSettings { strum_leniency: false, ..default_settings() };
+		assert_eq!(strums_after_hopo(strict_strums), (1, 1));
+		assert_eq!(strums_after_strum(strict_strums), (0, 1));
+
+		let double_strums = GuitarSettings { double_strum_protection: false, ..default_settings() };
+		assert_eq!(strums_after_strum(double_strums), (1, 1));
+		assert_eq!(strums_after_hopo(double_strums), (0, 1));
+
+		let ghosts = GuitarSettings { ghost_protection: false, ..default_settings() };
+		assert_eq!(hopo_hits_after_ghost(ghosts), 1);
+	}
+
+	#[test]
+	fn missing_the_last_note_completes_the_song() {
+		let mut playthrough = playthrough_with_events("  576 = E \"end\"\n", "  192 = N 0 0\n  384 = N 1 0\n", default_settings());
+		playthrough.update_time(800.0);
+		let effects = playthrough.update_time(2000.0);
+		assert_eq!(playthrough.state.notes_missed, 2);
+		assert_eq!(completions(&effects), 1);
+	}
+
+	#[test]
+	fn song_completes_once_after_the_last_sustain_and_the_end_event() {
+		// a sustain from 500 to 1000 ms, and the end event before or after it ends
+		for &(end_ticks, last_unfinished_ms) in &[(576, 1100.0), (288, 900.0)] {
+			let events = format!("  {} = E \"end\"\n", end_ticks);
+			let mut playthrough = playthrough_with_events(&events, "  192 = N 0 192\n", default_settings());
+			strum(&mut playthrough, &[Fret::G], 192);
+
+			let mut effects = playthrough.update_time(800.0);
+			effects.extend(playthrough.update_time(last_unfinished_ms));
+			assert_eq!(completions(&effects), 0);
+
+			let effects = playthrough.update_time(1600.0);
+			assert_eq!(completions(&effects), 1);
+
+			playthrough.end_audio();
+			assert_eq!(completions(&playthrough.update_time(1700.0)), 0);
+		}
+	}
+
+	#[test]
+	fn song_without_an_end_event_completes_when_the_audio_ends() {
+		let mut playthrough = playthrough("  192 = N 0 0\n");
+		strum(&mut playthrough, &[Fret::G], 192);
+		assert_eq!(completions(&playthrough.update_time(5000.0)), 0);
+
+		playthrough.end_audio();
+		let effects = playthrough.update_time(5010.0);
+		assert_eq!(completions(&effects), 1);
+		match &effects[..] {
+			[GuitarGameEffect::SongComplete(results)] => assert_eq!((results.notes_hit, results.score), (1, NOTE_SCORE)),
+			_ => panic!("expected only SongComplete"),
 		}
+	}
+
+	#[test]
+	fn rock_meter_drains_on_misses_and_overstrums() {
+		let rock_meter = RockMeterSettings::default();
+		let mut playthrough = playthrough("  192 = N 0 0\n");
+		assert_close(playthrough.state.rock_meter, rock_meter.start);
+
+		playthrough.update_time(800.0);
+		assert_close(playthrough.state.rock_meter, rock_meter.start - rock_meter.miss_loss);
+
+		let effects = act(&mut playthrough, GuitarInputAction::Strum, 1000.0);
+		assert_eq!(overstrums(&effects), 1);
+		assert_close(playthrough.state.rock_meter, rock_meter.start - rock_meter.miss_loss - rock_meter.overstrum_loss);
+	}
+
+	#[test]
+	fn empty_rock_meter_fails_the_song_once() {
+		let (playthrough, failures) = miss_until_failing(false);
+		assert_eq!(failures, 1);
+		assert!(playthrough.state.failed);
+		// nothing is judged after failing
+		assert_eq!((playthrough.state.notes_hit, playthrough.state.notes_missed), (0, 2));
+	}
+
+	#[test]
+	fn no_fail_keeps_playing_with_an_empty_rock_meter() {
+		let (playthrough, failures) = miss_until_failing(true);
+		assert_eq!(failures, 0);
+		assert!(!playthrough.state.failed);
+		assert_eq!((playthrough.state.notes_hit, playthrough.state.notes_missed), (1, 2));
+	}
+
+	#[test]
+	fn star_power_boosts_rock_meter_gains() {
+		let rock_meter = RockMeterSettings::default();
+		let mut playthrough = playthrough("  192 = N 0 0\n  384 = N 0 0\n");
+		strum(&mut playthrough, &[Fret::G], 192);
+		let mut expected = rock_meter.start + rock_meter.hit_gain;
+		assert_close(playthrough.state.rock_meter, expected);
 
-		let mut missed = false;
+		playthrough.state.sp_meter = 1.0;
+		act(&mut playthrough, GuitarInputAction::StarPower, 600.0);
+		assert!(playthrough.state.sp_active);
+		expected += rock_meter.sp_activation_gain;
+		assert_close(playthrough.state.rock_meter, expected);
+
+		strum(&mut playthrough, &[Fret::G], 384);
+		expected += rock_meter.hit_gain * rock_meter.sp_hit_multiplier;
+		assert_close(playthrough.state.rock_meter, expected);
+	}
+
+	#[test]
+	fn six_fret_notes_are_read_into_both_rows() {
+		let playthrough = six_fret_playthrough("  192 = N 0 0\n  192 = N 8 0\n  384 = N 3 0\n  384 = N 2 0\n");
+		let lanes = |chord: [bool; MAX_LANES]| (0..MAX_LANES).filter(|lane| chord[*lane]).collect::<Vec<usize>>();
+		assert_eq!(playthrough.chart.layout, FretLayout::Six);
+		assert_eq!(lanes(playthrough.chart.notes[0].chord), vec![Fret::W1.lane(), Fret::B3.lane()]);
+		assert_eq!(lanes(playthrough.chart.notes[1].chord), vec![Fret::W3.lane(), Fret::B1.lane()]);
+	}
 
-		loop {
-			let note = &self.chart.notes[self.next_note_index];
+	#[test]
+	fn six_fret_barre_needs_both_rows_held() {
+		// W1 and B1 are the same column
+		let notes = "  192 = N 0 0\n  192 = N 3 0\n";
+		let mut barre = six_fret_playthrough(notes);
+		assert_eq!(hits(&strum(&mut barre, &[Fret::W1, Fret::B1], 192)), 1);
 
-			if self.chart.ticks_to_ms(note.ticks) < (time_ms - HALF_HIT_WINDOW_MS) {
-				missed = true;
-				self.next_note_index += 1;
+		for frets in &[[Fret::W1], [Fret::B1]] {
+			let mut half = six_fret_playthrough(notes);
+			assert_eq!(overstrums(&strum(&mut half, frets, 192)), 1);
+		}
+	}
+
+	#[test]
+	fn six_fret_single_notes_anchor_on_lower_columns() {
+		// a single W2, with frets in the lower column of either row, in its own column, or above it
+		for &(held, hit) in &[
+			(&[Fret::W2][..], true),
+			(&[Fret::W1, Fret::W2][..], true),
+			(&[Fret::B1, Fret::W2][..], true),
+			(&[Fret::W1, Fret::B1, Fret::W2][..], true),
+			(&[Fret::B2, Fret::W2][..], false),
+			(&[Fret::W3, Fret::W2][..], false),
+			(&[Fret::B3, Fret::W2][..], false),
+		] {
+			let mut playthrough = six_fret_playthrough("  192 = N 1 0\n");
+			assert_eq!(hits(&strum(&mut playthrough, held, 192)), if hit { 1 } else { 0 }, "holding {:?}", held);
+		}
+	}
+
+	#[test]
+	fn mirroring_swaps_the_highest_and_lowest_columns() {
+		let five = FretLayout::Five;
+		let mirrored: Vec<usize> = five.frets().iter().map(|fret| five.mirror(fret.lane())).collect();
+		assert_eq!(mirrored, vec![Fret::O.lane(), Fret::B.lane(), Fret::Y.lane(), Fret::R.lane(), Fret::G.lane()]);
+
+		// six-fret frets stay in their row
+		let six = FretLayout::Six;
+		let mirrored: Vec<usize> = six.frets().iter().map(|fret| six.mirror(fret.lane())).collect();
+		assert_eq!(mirrored, vec![Fret::W3.lane(), Fret::W2.lane(), Fret::W1.lane(), Fret::B3.lane(), Fret::B2.lane(), Fret::B1.lane()]);
+	}
+
+	fn with_modifiers(modifiers: Modifiers) -> GuitarSettings {
+		GuitarSettings { modifiers: modifiers, ..default_settings() }
+	}
+
+	#[test]
+	fn mirror_plays_green_on_orange() {
+		let mut playthrough = playthrough_with("  192 = N 0 0\n  384 = N 1 0\n  384 = N 2 0\n", with_modifiers(Modifiers { mirror: true, ..Modifiers::default() }));
+		assert_eq!(hits(&strum(&mut playthrough, &[Fret::O], 192)), 1);
+		assert_eq!(hits(&strum(&mut playthrough, &[Fret::Y, Fret::B], 384)), 1);
+	}
+
+	#[test]
+	fn strum_type_modifiers_rewrite_every_note() {
+		// a strum, a forced HOPO and a tap
+		let notes = "  192 = N 0 0\n  288 = N 1 0\n  288 = N 5 0\n  384 = N 2 0\n  384 = N 6 0\n";
+		let strum_types = |modifiers: Modifiers| -> Vec<GuitarNoteStrumType> {
+			playthrough_with(notes, with_modifiers(modifiers)).chart.notes.iter().map(|note| note.strum_type).collect()
+		};
+
+		assert_eq!(strum_types(Modifiers::default()), vec![GuitarNoteStrumType::Strum, GuitarNoteStrumType::Hopo, GuitarNoteStrumType::Tap]);
+		for &strum_type in &[GuitarNoteStrumType::Strum, GuitarNoteStrumType::Hopo, GuitarNoteStrumType::Tap] {
+			assert_eq!(strum_types(Modifiers { strum_type: Some(strum_type), ..Modifiers::default() }), vec![strum_type; 3]);
+		}
+	}
+
+	#[test]
+	fn precision_narrows_the_hit_window() {
+		let precise = with_modifiers(Modifiers { precision: true, ..Modifiers::default() });
+		let hit_window = playthrough_with("", precise).hit_window();
+		assert_close(hit_window.early_ms, 45.0 * PRECISION_WINDOW_FRACTION);
+		assert_close(hit_window.late_ms, 40.0 * PRECISION_WINDOW_FRACTION);
+
+		// 30 ms early is inside the Expert window, but not the precise one
+		for &(settings, hit) in &[(default_settings(), true), (precise, false)] {
+			let mut playthrough = playthrough_with("  192 = N 0 0\n", settings);
+			hold(&mut playthrough, &[Fret::G]);
+			assert_eq!(hits(&act(&mut playthrough, GuitarInputAction::Strum, 470.0)), if hit { 1 } else { 0 });
+		}
+	}
+
+	#[test]
+	fn modifiers_are_enabled_by_their_names() {
+		let mut modifiers = Modifiers::default();
+		for name in &["all_taps", "mirror", "lefty", "precision"] {
+			modifiers.enable(name).unwrap();
+		}
+		assert_eq!(modifiers.names(), vec!["all_taps", "mirror", "lefty", "precision"]);
+		assert!(modifiers.enable("all_gold").is_err());
+	}
+
+	// two one-note star power phrases, at 500 and 1000 ms
+	const TWO_PHRASES: &str = "  192 = N 0 0\n  192 = S 2 1\n  384 = N 0 0\n  384 = S 2 1\n  576 = N 0 0\n";
+
+	#[test]
+	fn completing_a_phrase_fills_the_star_power_meter() {
+		let mut playthrough = playthrough(TWO_PHRASES);
+		let effects = strum(&mut playthrough, &[Fret::G], 192);
+		assert!(effects.iter().any(|effect| match effect { GuitarGameEffect::StarPowerPhraseComplete { .. } => true, _ => false }));
+		assert_eq!(playthrough.state.sp_meter, SP_PHRASE_METER);
+
+		// a missed phrase fills nothing
+		playthrough.update_time(1100.0);
+		assert_eq!(playthrough.state.sp_phrases, 1);
+		assert_eq!(playthrough.state.sp_meter, SP_PHRASE_METER);
+	}
+
+	#[test]
+	fn star_power_activates_from_half_a_meter() {
+		let mut playthrough = playthrough(TWO_PHRASES);
+		strum(&mut playthrough, &[Fret::G], 192);
+		assert!(act(&mut playthrough, GuitarInputAction::StarPower, 600.0).is_empty());
+		assert!(!playthrough.state.sp_active);
+
+		strum(&mut playthrough, &[Fret::G], 384);
+		let effects = act(&mut playthrough, GuitarInputAction::StarPower, 1000.0);
+		assert!(effects.iter().any(|effect| match effect { GuitarGameEffect::StarPowerActivated => true, _ => false }));
+		assert!(playthrough.state.sp_active);
+		assert_eq!(playthrough.state.multiplier(), 2);
+	}
+
+	#[test]
+	fn active_star_power_drains_the_meter_until_it_runs_out() {
+		let mut playthrough = playthrough(TWO_PHRASES);
+		strum(&mut playthrough, &[Fret::G], 192);
+		strum(&mut playthrough, &[Fret::G], 384);
+		strum(&mut playthrough, &[Fret::G], 576);
+		act(&mut playthrough, GuitarInputAction::StarPower, 1500.0);
+
+		// half a meter lasts 16 beats, which is 8 seconds at 120 BPM
+		playthrough.update_time(5500.0);
+		assert_close(playthrough.state.sp_meter, 0.25);
+		assert!(playthrough.state.sp_active);
+
+		let effects = playthrough.update_time(9600.0);
+		assert!(effects.iter().any(|effect| match effect { GuitarGameEffect::StarPowerDeactivated => true, _ => false }));
+		assert!(!playthrough.state.sp_active);
+		assert_eq!(playthrough.state.sp_meter, 0.0);
+	}
+
+	// hits a two-beat sustain in a star power phrase, moving the whammy bar every 50 ms through it if whammying
+	fn sustain_meter(phrase: bool, whammying: bool) -> f32 {
+		let notes = if phrase { "  192 = N 0 384\n  192 = S 2 384\n" } else { "  192 = N 0 384\n" };
+		let mut playthrough = playthrough(notes);
+		strum(&mut playthrough, &[Fret::G], 192);
+		for step in 0..=20 {
+			let time_ms = 500.0 + step as f32 * 50.0;
+			if whammying {
+				act(&mut playthrough, GuitarInputAction::Whammy(((step + 1) % 2) as f32 * 0.5), time_ms);
 			} else {
-				break;
+				playthrough.update_time(time_ms);
 			}
 		}
+		playthrough.state.sp_meter
+	}
 
-		if missed {
-			let effect = if self.streak > 0 { GuitarGameEffect::MissStreak } else { GuitarGameEffect::MissNoStreak };
+	#[test]
+	fn whammying_phrase_sustains_fills_the_meter() {
+		assert_close(sustain_meter(true, false), SP_PHRASE_METER);
+		assert_close(sustain_meter(true, true), SP_PHRASE_METER + 2.0 / SP_WHAMMY_BEATS);
+		assert_eq!(sustain_meter(false, true), 0.0);
+	}
 
-			self.streak = 0;
+	#[test]
+	fn tilting_activates_star_power() {
+		let mut playthrough = playthrough(TWO_PHRASES);
+		strum(&mut playthrough, &[Fret::G], 192);
+		strum(&mut playthrough, &[Fret::G], 384);
 
-			Some(effect)
-		} else {
-			None
-		}
+		assert!(act(&mut playthrough, GuitarInputAction::Tilt(0.3), 1100.0).is_empty());
+		assert!(!playthrough.state.sp_active);
+
+		let effects = act(&mut playthrough, GuitarInputAction::Tilt(0.8), 1200.0);
+		assert!(effects.iter().any(|effect| match effect { GuitarGameEffect::StarPowerActivated => true, _ => false }));
+		assert!(playthrough.state.sp_active);
+		// staying tilted doesn't activate it again
+		assert!(act(&mut playthrough, GuitarInputAction::Tilt(0.9), 1300.0).is_empty());
+	}
+
+	#[test]
+	fn hits_misses_and_overstrums_count_for_their_section() {
+		let events = "  0 = E \"section Intro\"\n  384 = E \"section Verse\"\n";
+		let mut playthrough = playthrough_with_events(events, "  192 = N 0 0\n  384 = N 1 0\n  576 = N 2 0\n", default_settings());
+		act(&mut playthrough, GuitarInputAction::Strum, 200.0);
+		strum(&mut playthrough, &[Fret::G], 192);
+		playthrough.update_time(1200.0);
+		strum(&mut playthrough, &[Fret::Y], 576);
+		act(&mut playthrough, GuitarInputAction::Strum, 1700.0);
+		act(&mut playthrough, GuitarInputAction::Strum, 1900.0);
+
+		let results = playthrough.results();
+		let sections: Vec<(&str, u64, u64, u64, u64)> = results.sections.iter()
+			.map(|section| (section.name.as_str(), section.notes_hit, section.notes_missed, section.notes_total, section.score))
+			.collect();
+		assert_eq!(sections, vec![("Intro", 1, 0, 1, NOTE_SCORE), ("Verse", 1, 1, 2, NOTE_SCORE)]);
+		assert_eq!(results.section_overstrums, vec![1, 2]);
+	}
+
+	#[test]
+	fn song_clock_starts_a_lead_in_before_the_first_tick() {
+		let playthrough = playthrough("  0 = N 0 0\n");
+		assert_eq!(playthrough.start_time_ms(), -2000.0);
+		assert_eq!(playthrough.state.time_ms(), -2000.0);
+
+		let short = playthrough_with("  0 = N 0 0\n", GuitarSettings { lead_in_ms: 500.0, ..default_settings() });
+		assert_eq!((short.start_time_ms(), short.state.time_ms()), (-500.0, -500.0));
+	}
+
+	#[test]
+	fn notes_on_the_first_tick_are_judged_after_the_lead_in() {
+		let mut hit = playthrough("  0 = N 0 0\n  192 = N 1 0\n");
+		assert!(hit.update_time(-1000.0).is_empty());
+		assert_eq!(hit.state.notes_missed, 0);
+		assert_eq!(hits(&strum(&mut hit, &[Fret::G], 0)), 1);
+
+		// left alone, it's missed once its window has passed
+		let mut missed = playthrough("  0 = N 0 0\n  192 = N 1 0\n");
+		missed.update_time(30.0);
+		assert_eq!(missed.state.notes_missed, 0);
+		missed.update_time(50.0);
+		assert_eq!(missed.state.notes_missed, 1);
+	}
+
+	#[test]
+	fn seeking_back_takes_back_judgements_of_notes_that_can_be_hit_again() {
+		let mut playthrough = playthrough("  192 = N 0 0\n  384 = N 1 0\n");
+		strum(&mut playthrough, &[Fret::G], 192);
+		playthrough.update_time(2000.0);
+		assert_eq!((playthrough.state.notes_hit, playthrough.state.notes_missed), (1, 1));
+
+		playthrough.seek(0.0);
+		assert_eq!((playthrough.state.notes_hit, playthrough.state.notes_missed, playthrough.state.score), (0, 0, 0));
+		assert_eq!(playthrough.state.timing.hits(), 0);
+
+		strum(&mut playthrough, &[Fret::G], 192);
+		strum(&mut playthrough, &[Fret::R], 384);
+		assert_eq!((playthrough.state.notes_hit, playthrough.state.notes_missed), (2, 0));
+		assert_eq!(playthrough.state.score, 2 * NOTE_SCORE);
+	}
+
+	#[test]
+	fn seeking_back_takes_back_the_star_power_of_phrases_that_can_be_hit_again() {
+		let mut playthrough = playthrough("  192 = N 0 0\n  192 = S 2 192\n  384 = N 1 0\n");
+		strum(&mut playthrough, &[Fret::G], 192);
+		strum(&mut playthrough, &[Fret::R], 384);
+		assert_eq!(playthrough.state.sp_phrases, 1);
+		assert_eq!(playthrough.state.sp_meter, SP_PHRASE_METER);
+
+		playthrough.seek(0.0);
+		assert_eq!(playthrough.state.sp_phrases, 0);
+		assert_eq!(playthrough.state.sp_meter, 0.0);
+
+		// looping over the phrase again doesn't fill the meter any further
+		strum(&mut playthrough, &[Fret::G], 192);
+		strum(&mut playthrough, &[Fret::R], 384);
+		assert_eq!(playthrough.state.sp_phrases, 1);
+		assert_eq!(playthrough.state.sp_meter, SP_PHRASE_METER);
+	}
+
+	#[test]
+	fn repeated_chord_hits_the_earliest_match() {
+		let mut playthrough = playthrough("  192 = N 0 0\n  192 = N 1 0\n  204 = N 0 0\n  204 = N 1 0\n");
+		strum(&mut playthrough, &[Fret::G, Fret::R], 204);
+
+		assert_eq!(playthrough.state.notes_hit, 1);
+		assert_eq!(playthrough.state.notes_missed, 0);
+		assert_eq!(playthrough.next_note_index, 1);
+
+		strum(&mut playthrough, &[Fret::G, Fret::R], 204);
+		assert_eq!(playthrough.state.notes_hit, 2);
 	}
 }
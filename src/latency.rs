@@ -0,0 +1,61 @@
+/// Where the latency settings are kept between runs
+pub const LATENCY_PATH: &str = "Settings/latency.txt";
+
+/// How late this machine's output reaches the player, measured once and kept in LATENCY_PATH.
+///
+/// Audio latency is how long after the music is played it is heard (e.g. over Bluetooth speakers),
+/// and shifts judgement: the engines judge inputs against the notes the player heard.
+/// Video latency is how long after a frame is drawn it is seen (e.g. on a TV's picture processing),
+/// and shifts drawing: notes are drawn that much ahead, so they are seen when they are judged.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Latency {
+    pub audio_ms: f32,
+    pub video_ms: f32,
+}
+
+#[derive(Debug, Clone)]
+pub enum LatencyParseError {
+    ParseFloatError(std::num::ParseFloatError),
+    UnknownSetting(String),
+    MissingField,
+}
+
+impl std::convert::From<std::num::ParseFloatError> for LatencyParseError {
+    fn from(err: std::num::ParseFloatError) -> LatencyParseError {
+        LatencyParseError::ParseFloatError(err)
+    }
+}
+
+impl Latency {
+    /// The song time to draw the highway at, for a song clock at time_ms running at speed.
+    /// The latencies are in real time, so a slowed-down clock goes through less of them.
+    pub fn draw_time_ms(self: &Self, time_ms: f32, speed: f32) -> f32 {
+        time_ms + (self.video_ms - self.audio_ms) * speed
+    }
+
+    pub fn write(self: &Self) -> String {
+        let lines = [
+            format!("audio_latency_ms {}", self.audio_ms),
+            format!("video_latency_ms {}", self.video_ms),
+        ];
+        lines.join("\n") + "\n"
+    }
+}
+
+/// Reads latency settings written by Latency::write. Settings that aren't there are 0.
+pub fn read(contents: &str) -> Result<Latency, LatencyParseError> {
+    let mut latency = Latency::default();
+
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let parts: Vec<&str> = line.split(' ').collect();
+        let value = parts.get(1).ok_or_else(|| LatencyParseError::MissingField)?;
+
+        match parts[0] {
+            "audio_latency_ms" => latency.audio_ms = value.parse()?,
+            "video_latency_ms" => latency.video_ms = value.parse()?,
+            other => return Err(LatencyParseError::UnknownSetting(other.to_string())),
+        }
+    }
+
+    Ok(latency)
+}
@@ -0,0 +1,12 @@
+pub mod audio;
+pub mod autoplay;
+pub mod band;
+pub mod bindings;
+pub mod calibration;
+pub mod chart;
+pub mod drumplaythrough;
+pub mod guitarplaythrough;
+pub mod latency;
+pub mod playthrough;
+pub mod practice;
+pub mod replay;
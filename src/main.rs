@@ -1,20 +1,33 @@
 extern crate sdl2;
-extern crate ears;
+extern crate bumpit;
 
-mod chart;
-mod guitarplaythrough;
-
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired, AudioStatus};
 use sdl2::event::Event;
 use sdl2::pixels;
 use sdl2::keyboard::Keycode;
+use sdl2::rect::Rect;
 
 use sdl2::gfx::primitives::DrawRenderer;
 
-use ears::{AudioController};
-
-use guitarplaythrough::*;
+use bumpit::audio;
+use bumpit::audio::{PcmChunks, SongDecoder, TimeStretch};
+use bumpit::autoplay;
+use bumpit::band::*;
+use bumpit::bindings;
+use bumpit::bindings::*;
+use bumpit::calibration;
+use bumpit::calibration::TapCalibration;
+use bumpit::chart;
+use bumpit::drumplaythrough::*;
+use bumpit::guitarplaythrough::*;
+use bumpit::latency;
+use bumpit::latency::Latency;
+use bumpit::playthrough::Playthrough;
+use bumpit::practice::Practice;
+use bumpit::replay;
+use bumpit::replay::Replay;
 
 const SCREEN_WIDTH: u32 = 800;
 const SCREEN_HEIGHT: u32 = 600;
@@ -25,16 +38,44 @@ enum GameButton {
     Yellow,
     Blue,
     Orange,
+    White1,
+    White2,
+    White3,
+    Black1,
+    Black2,
+    Black3,
 }
 
 enum GameInputAction {
     Quit,
+    Pause,
     ButtonDown(GameButton),
     ButtonUp(GameButton),
     Strum,
+    StarPower,
+    Whammy(f32),
+    Tilt(f32),
+    Pad(Pad),
 }
 
 impl GameButton {
+    fn from_action(action: &Action) -> Option<GameButton> {
+        match action {
+            Action::Green => Some(GameButton::Green),
+            Action::Red => Some(GameButton::Red),
+            Action::Yellow => Some(GameButton::Yellow),
+            Action::Blue => Some(GameButton::Blue),
+            Action::Orange => Some(GameButton::Orange),
+            Action::White1 => Some(GameButton::White1),
+            Action::White2 => Some(GameButton::White2),
+            Action::White3 => Some(GameButton::White3),
+            Action::Black1 => Some(GameButton::Black1),
+            Action::Black2 => Some(GameButton::Black2),
+            Action::Black3 => Some(GameButton::Black3),
+            _ => None,
+        }
+    }
+
     fn to_guitar(self: &Self) -> Fret {
         match self {
             GameButton::Green => Fret::G,
@@ -42,24 +83,58 @@ impl GameButton {
             GameButton::Yellow => Fret::Y,
             GameButton::Blue => Fret::B,
             GameButton::Orange => Fret::O,
+            GameButton::White1 => Fret::W1,
+            GameButton::White2 => Fret::W2,
+            GameButton::White3 => Fret::W3,
+            GameButton::Black1 => Fret::B1,
+            GameButton::Black2 => Fret::B2,
+            GameButton::Black3 => Fret::B3,
         }
     }
 }
 
 impl GameInputAction {
+    // frets are held down and let go; everything else but the whammy bar and tilt only presses
+    fn from_binding(action: Action, press: Press) -> Option<GameInputAction> {
+        match (action, press) {
+            (Action::Strum, Press::Down) => Some(GameInputAction::Strum),
+            (Action::StarPower, Press::Down) => Some(GameInputAction::StarPower),
+            (Action::Pad(pad), Press::Down) => Some(GameInputAction::Pad(pad)),
+            (Action::Whammy, Press::Position(position)) => Some(GameInputAction::Whammy(position)),
+            (Action::Tilt, Press::Position(tilt)) => Some(GameInputAction::Tilt(tilt)),
+            (action, Press::Down) => GameButton::from_action(&action).map(GameInputAction::ButtonDown),
+            (action, Press::Up) => GameButton::from_action(&action).map(GameInputAction::ButtonUp),
+            _ => None,
+        }
+    }
+
     fn to_guitar_action(self: &Self) -> Option<GuitarInputAction> {
         match self {
             GameInputAction::Quit => None,
+            GameInputAction::Pause => None,
             GameInputAction::ButtonDown(button) => Some(GuitarInputAction::FretDown(button.to_guitar())),
             GameInputAction::ButtonUp(button) => Some(GuitarInputAction::FretUp(button.to_guitar())),
             GameInputAction::Strum => Some(GuitarInputAction::Strum),
+            GameInputAction::StarPower => Some(GuitarInputAction::StarPower),
+            GameInputAction::Whammy(position) => Some(GuitarInputAction::Whammy(*position)),
+            GameInputAction::Tilt(tilt) => Some(GuitarInputAction::Tilt(*tilt)),
+            GameInputAction::Pad(_) => None,
+        }
+    }
+
+    fn to_drum_action(self: &Self) -> Option<DrumInputAction> {
+        match self {
+            GameInputAction::Pad(pad) => Some(DrumInputAction::Hit(*pad)),
+            GameInputAction::StarPower => Some(DrumInputAction::StarPower),
+            _ => None,
         }
     }
 }
 
 enum GameInputEffect {
     Quit,
-    GuitarEffect(GuitarGameEffect),
+    TogglePause,
+    BandEffect(BandEffect),
 }
 
 fn draw_fret<T: sdl2::render::RenderTarget>(canvas: &sdl2::render::Canvas<T>, enabled: bool, x: i16, y: i16, radius: i16, color: pixels::Color) -> Result<(), String> {
@@ -70,11 +145,412 @@ fn draw_fret<T: sdl2::render::RenderTarget>(canvas: &sdl2::render::Canvas<T>, en
     }
 }
 
+// drum lanes: red, yellow, blue and green left to right. the kick is drawn across every lane
+fn drum_lane(pad: Pad) -> Option<(i16, pixels::Color)> {
+    match pad.tom() {
+        Pad::Red => Some((50, pixels::Color::RGB(128, 0, 0))),
+        Pad::YellowTom => Some((150, pixels::Color::RGB(128, 128, 0))),
+        Pad::BlueTom => Some((250, pixels::Color::RGB(0, 0, 128))),
+        Pad::GreenTom => Some((350, pixels::Color::RGB(0, 128, 0))),
+        _ => None,
+    }
+}
+
+// every event from a joystick carries the instance id of the joystick it came from
+fn event_joystick(event: &Event) -> Option<i32> {
+    match event {
+        Event::JoyAxisMotion { which, .. } => Some(*which),
+        Event::JoyButtonDown { which, .. } => Some(*which),
+        Event::JoyButtonUp { which, .. } => Some(*which),
+        Event::JoyHatMotion { which, .. } => Some(*which),
+        _ => None,
+    }
+}
+
+// the part of an event bindings can bind. Held keys repeat, which doesn't count as pressing them again
+fn binding_input(event: &Event) -> Option<Input> {
+    match event {
+        Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => Some(Input::KeyDown(keycode.name())),
+        Event::KeyUp { keycode: Some(keycode), .. } => Some(Input::KeyUp(keycode.name())),
+        Event::JoyButtonDown { button_idx, .. } => Some(Input::ButtonDown(*button_idx)),
+        Event::JoyButtonUp { button_idx, .. } => Some(Input::ButtonUp(*button_idx)),
+        Event::JoyHatMotion { hat_idx, state, .. } => hat_direction(*state).map(|direction| Input::Hat(*hat_idx, direction)),
+        Event::JoyAxisMotion { axis_idx, value, .. } => Some(Input::Axis(*axis_idx, *value)),
+        _ => None,
+    }
+}
+
+fn hat_direction(state: sdl2::joystick::HatState) -> Option<HatDirection> {
+    match state {
+        sdl2::joystick::HatState::Up => Some(HatDirection::Up),
+        sdl2::joystick::HatState::Down => Some(HatDirection::Down),
+        sdl2::joystick::HatState::Left => Some(HatDirection::Left),
+        sdl2::joystick::HatState::Right => Some(HatDirection::Right),
+        _ => None,
+    }
+}
+
+// the devices whose bindings a joystick uses, most specific first
+fn joystick_devices(joystick: &sdl2::joystick::Joystick) -> Vec<Device> {
+    vec![Device::Guid(joystick.guid().string()), Device::Name(joystick.name()), Device::Joystick]
+}
+
+// six-fret lanes: each column's white fret is drawn left of its black fret
+fn six_fret_lane(lane: usize) -> (i16, pixels::Color) {
+    let column = FretLayout::Six.column(lane) as i16;
+    if lane < 3 {
+        (50 + column * 150, pixels::Color::RGB(220, 220, 220))
+    } else {
+        (100 + column * 150, pixels::Color::RGB(90, 90, 90))
+    }
+}
+
+// the song's audio goes through the time-stretch stage, so it can follow a slowed-down song clock
+struct MusicCallback {
+    stretch: TimeStretch,
+}
+
+impl AudioCallback for MusicCallback {
+    type Channel = f32;
+
+    fn callback(self: &mut Self, out: &mut [f32]) {
+        self.stretch.fill(out);
+    }
+}
+
+struct Music {
+    device: AudioDevice<MusicCallback>,
+    // hands over the song as it's decoded, if it still is
+    decoder: Option<SongDecoder>,
+}
+
+impl Music {
+    fn new(audio_subsystem: &sdl2::AudioSubsystem, pcm: PcmChunks, decoder: Option<SongDecoder>, rate: f32) -> Result<Music, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(pcm.sample_rate as i32),
+            channels: Some(pcm.channels as u8),
+            samples: Some(1024),
+        };
+        let mut stretch = TimeStretch::new(pcm);
+        stretch.set_rate(rate);
+
+        let device = audio_subsystem.open_playback(None, &desired, |_spec| MusicCallback { stretch: stretch })?;
+        Ok(Music { device: device, decoder: decoder })
+    }
+
+    // hands the audio decoded since the last call to the device
+    fn receive_decoded(self: &mut Self) -> Result<(), String> {
+        let result = match &self.decoder {
+            Some(decoder) => decoder.receive(&mut self.device.lock().stretch.source),
+            None => return Ok(()),
+        };
+        if self.device.lock().stretch.source.complete {
+            self.decoder = None;
+        }
+        result
+    }
+
+    fn play(self: &mut Self) {
+        self.device.resume();
+    }
+
+    fn pause(self: &mut Self) {
+        self.device.pause();
+    }
+
+    fn is_playing(self: &mut Self) -> bool {
+        self.device.status() == AudioStatus::Playing && !self.device.lock().stretch.is_finished()
+    }
+
+    // position in the song of the audio last handed to the device
+    fn offset_ms(self: &mut Self) -> f32 {
+        self.device.lock().stretch.position_ms()
+    }
+
+    fn set_offset_ms(self: &mut Self, time_ms: f32) {
+        self.device.lock().stretch.seek_ms(time_ms);
+    }
+}
+
 enum FrameLimit {
-    Vsync,
     Cap(u32),
 }
 
+fn save_latency(latency: &Latency) -> Result<(), String> {
+    std::fs::create_dir_all("Settings").map_err(|e| e.to_string())?;
+    std::fs::write(latency::LATENCY_PATH, latency.write()).map_err(|e| e.to_string())?;
+    println!("Saved latency to {}", latency::LATENCY_PATH);
+    Ok(())
+}
+
+enum CalibrationPhase {
+    Audio,
+    Video,
+    Done,
+}
+
+// any key or button, or the strum bar, taps along
+fn is_tap(event: &Event) -> bool {
+    match event {
+        Event::KeyDown { repeat: false, .. } => true,
+        Event::JoyButtonDown { .. } => true,
+        Event::JoyHatMotion { state, .. } => *state != sdl2::joystick::HatState::Centered,
+        _ => false,
+    }
+}
+
+fn tap_stats_line(name: &str, taps: &TapCalibration) -> String {
+    match (taps.mean_ms(), taps.deviation_ms()) {
+        (Some(mean_ms), Some(deviation_ms)) => format!("{}: {:+.1} ms, deviation {:.1} ms ({} taps)", name, mean_ms, deviation_ms, taps.offsets_ms.len()),
+        _ => format!("{}: no taps", name),
+    }
+}
+
+// The calibration screen. The player taps along to a metronome click, then to a flashing box
+// with no sound, and the mean offsets of their taps become the audio and video latency.
+// Returns None if the player backed out.
+fn calibrate<T: sdl2::render::RenderTarget>(canvas: &mut sdl2::render::Canvas<T>, events: &mut sdl2::EventPump, audio_subsystem: &sdl2::AudioSubsystem, previous: Latency) -> Result<Option<Latency>, String> {
+    let mut music = Music::new(audio_subsystem, PcmChunks::from(calibration::click_track(44100, 2)), None, 1f32)?;
+    let mut audio_taps = TapCalibration::new();
+    let mut video_taps = TapCalibration::new();
+    let mut phase = CalibrationPhase::Audio;
+    let mut last_offset_ms: Option<f32> = None;
+
+    // each phase's clock starts a beat before its first beat
+    let mut previous_frame_time = Instant::now();
+    let mut time_ms = -calibration::beat_ms();
+    let mut music_started = false;
+    let mut last_playhead_pos_ms = time_ms;
+
+    loop {
+        let this_frame_time = Instant::now();
+        time_ms += this_frame_time.duration_since(previous_frame_time).as_millis() as f32;
+        previous_frame_time = this_frame_time;
+
+        // the clicks keep the clock the same way the music keeps the song clock
+        if let CalibrationPhase::Audio = phase {
+            if !music_started && time_ms >= 0f32 {
+                music.set_offset_ms(time_ms);
+                music.play();
+                music_started = true;
+                last_playhead_pos_ms = music.offset_ms();
+            }
+            let playhead_pos_ms = music.offset_ms();
+            if music_started && playhead_pos_ms != last_playhead_pos_ms {
+                time_ms = (time_ms + playhead_pos_ms) / 2f32;
+                last_playhead_pos_ms = playhead_pos_ms;
+            }
+        }
+
+        for event in events.poll_iter() {
+            match event {
+                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return Ok(None),
+                _ if !is_tap(&event) => continue,
+                _ => (),
+            }
+            match phase {
+                CalibrationPhase::Audio => last_offset_ms = audio_taps.tap(time_ms).or(last_offset_ms),
+                CalibrationPhase::Video => last_offset_ms = video_taps.tap(time_ms).or(last_offset_ms),
+                CalibrationPhase::Done => return Ok(Some(calibration::measured_latency(&audio_taps, &video_taps, previous))),
+            }
+        }
+
+        if time_ms >= calibration::end_ms() {
+            match phase {
+                CalibrationPhase::Audio => {
+                    music.pause();
+                    phase = CalibrationPhase::Video;
+                    time_ms = -calibration::beat_ms();
+                    last_offset_ms = None;
+                },
+                CalibrationPhase::Video => phase = CalibrationPhase::Done,
+                CalibrationPhase::Done => (),
+            }
+        }
+
+        canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        canvas.clear();
+        let white = pixels::Color::RGB(255, 255, 255);
+        let beat = (time_ms / calibration::beat_ms()).floor();
+
+        let lines = match phase {
+            CalibrationPhase::Audio | CalibrationPhase::Video => {
+                let (instruction, taps) = match phase {
+                    CalibrationPhase::Audio => ("Tap along to the clicks with any key or button", &audio_taps),
+                    _ => ("Tap along to the flashes with any key or button", &video_taps),
+                };
+                vec![
+                    String::from(instruction),
+                    if beat < calibration::COUNT_IN_BEATS as f32 { String::from("Get ready...") } else { String::new() },
+                    format!("Taps: {} / {}", taps.offsets_ms.len(), calibration::MEASURED_BEATS),
+                    last_offset_ms.map_or_else(String::new, |offset_ms| format!("Last tap: {:+.1} ms", offset_ms)),
+                ]
+            },
+            CalibrationPhase::Done => {
+                let calibrated = calibration::measured_latency(&audio_taps, &video_taps, previous);
+                vec![
+                    tap_stats_line("Audio", &audio_taps),
+                    tap_stats_line("Video", &video_taps),
+                    String::new(),
+                    format!("Audio latency: {:.1} ms (was {:.1} ms)", calibrated.audio_ms, previous.audio_ms),
+                    format!("Video latency: {:.1} ms (was {:.1} ms)", calibrated.video_ms, previous.video_ms),
+                    String::new(),
+                    String::from("Press any key or button to save, or Escape to cancel"),
+                ]
+            },
+        };
+        for (i, line) in lines.iter().enumerate() {
+            let _ = canvas.string(50, 50 + (i as i16) * 12, line, white);
+        }
+
+        // the flash is on for the start of every beat
+        if let CalibrationPhase::Video = phase {
+            let into_beat_ms = time_ms - beat * calibration::beat_ms();
+            if time_ms >= 0f32 && time_ms < calibration::end_ms() && into_beat_ms < 80f32 {
+                let _ = canvas.box_(300, 200, 500, 400, white);
+            }
+        }
+
+        canvas.present();
+        ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 120));
+    }
+}
+
+// how far an axis has to move from where it rested to be the one being bound
+const REBIND_AXIS_TRAVEL: i32 = 16384;
+
+// each joystick's axis positions
+fn axis_positions(joysticks: &[sdl2::joystick::Joystick]) -> Vec<(i32, Vec<i16>)> {
+    joysticks.iter()
+        .map(|joystick| (joystick.instance_id(), (0..joystick.num_axes()).map(|axis| joystick.axis(axis).unwrap_or(0)).collect()))
+        .collect()
+}
+
+fn control_name(control: &Control) -> String {
+    match control {
+        Control::Key(name) => name.clone(),
+        Control::Button(button_idx) => format!("button {}", button_idx),
+        Control::Hat(hat_idx, direction) => format!("hat {} {}", hat_idx, direction.name()),
+        Control::Axis(calibration) => format!("axis {}", calibration.axis_idx),
+    }
+}
+
+// keys and buttons the game keeps for itself: Escape quits, and P and button 7 (Start on Xbox 360 controllers)
+// pause, so binding them would do both
+const RESERVED_KEYS: [&str; 2] = ["Escape", "P"];
+const RESERVED_BUTTONS: [u8; 1] = [7];
+
+// The rebinding screen. It asks for each of the part's actions in turn ("Press the button for Green")
+// and binds whatever the first device to answer presses, or for the whammy bar and tilt, the axis it moves.
+// Backspace leaves an action unbound, and RESERVED_KEYS and RESERVED_BUTTONS can't be bound. Returns None if the player backed out.
+fn rebind<T: sdl2::render::RenderTarget>(canvas: &mut sdl2::render::Canvas<T>, events: &mut sdl2::EventPump, joysticks: &[sdl2::joystick::Joystick], kind: BindingKind) -> Result<Option<DeviceBindings>, String> {
+    let actions = kind.actions();
+    // the device being bound, and its joystick's instance id
+    let mut device: Option<(Device, Option<i32>)> = None;
+    let mut bound: Vec<Binding> = Vec::new();
+    let mut index = 0;
+    let mut rests = axis_positions(joysticks);
+
+    while index < actions.len() {
+        let action = actions[index];
+        // the keyboard has no axes
+        if action.is_analog() && device.as_ref().map_or(false, |(_device, which)| which.is_none()) {
+            index += 1;
+            continue;
+        }
+
+        for event in events.poll_iter() {
+            match event {
+                Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return Ok(None),
+                Event::KeyDown { keycode: Some(Keycode::Backspace), repeat: false, .. } => {
+                    index += 1;
+                    break;
+                },
+                _ => (),
+            }
+            let which = event_joystick(&event);
+            if device.as_ref().map_or(false, |(_device, bound_which)| *bound_which != which) {
+                continue;
+            }
+
+            let control = match (&event, action.is_analog()) {
+                (Event::JoyAxisMotion { which, axis_idx, value, .. }, true) => {
+                    let rest = rests.iter()
+                        .find(|(instance_id, _axes)| instance_id == which)
+                        .and_then(|(_instance_id, axes)| axes.get(*axis_idx as usize))
+                        .map_or(0, |rest| *rest);
+                    if (*value as i32 - rest as i32).abs() < REBIND_AXIS_TRAVEL {
+                        continue;
+                    }
+                    let full = if *value > rest { std::i16::MAX } else { std::i16::MIN };
+                    Control::Axis(AxisCalibration::new(action, *axis_idx, rest, full))
+                },
+                (_, true) => continue,
+                (_, false) => match binding_input(&event) {
+                    Some(Input::KeyDown(name)) if RESERVED_KEYS.contains(&name.as_str()) => continue,
+                    Some(Input::KeyDown(name)) => Control::Key(name),
+                    Some(Input::ButtonDown(button_idx)) if RESERVED_BUTTONS.contains(&button_idx) => continue,
+                    Some(Input::ButtonDown(button_idx)) => Control::Button(button_idx),
+                    Some(Input::Hat(hat_idx, direction)) => Control::Hat(hat_idx, direction),
+                    _ => continue,
+                },
+            };
+
+            // joysticks are bound by GUID, so every controller of the same model shares them
+            if device.is_none() {
+                device = Some(match which {
+                    Some(which) => (joysticks.iter()
+                        .find(|joystick| joystick.instance_id() == which)
+                        .map_or(Device::Joystick, |joystick| Device::Guid(joystick.guid().string())), Some(which)),
+                    None => (Device::Keyboard, None),
+                });
+            }
+            bound.push(Binding { control: control, action: action });
+            index += 1;
+            rests = axis_positions(joysticks);
+            break;
+        }
+
+        if index >= actions.len() {
+            break;
+        }
+
+        canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        canvas.clear();
+        let white = pixels::Color::RGB(255, 255, 255);
+
+        let device_name = match &device {
+            Some((Device::Keyboard, _)) => String::from("the keyboard"),
+            Some((_, Some(which))) => joysticks.iter()
+                .find(|joystick| joystick.instance_id() == *which)
+                .map_or_else(|| String::from("the joystick"), |joystick| format!("\"{}\"", joystick.name())),
+            _ => String::from("the first device pressed"),
+        };
+        let action = actions[index];
+        let prompt = if action.is_analog() {
+            format!("Move {} all the way", action.label())
+        } else {
+            format!("Press the button for {}", action.label())
+        };
+        let mut lines = vec![
+            format!("Binding {} controls for {}", kind.name(), device_name),
+            String::new(),
+            prompt,
+            String::from("Backspace skips, Escape cancels, P and Start can't be bound"),
+            String::new(),
+        ];
+        lines.extend(bound.iter().map(|binding| format!("{}: {}", binding.action.label(), control_name(&binding.control))));
+        for (i, line) in lines.iter().enumerate() {
+            let _ = canvas.string(50, 50 + (i as i16) * 12, line, white);
+        }
+
+        canvas.present();
+        ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 120));
+    }
+
+    Ok(device.map(|(device, _which)| DeviceBindings { kind: kind, device: device, bindings: bound }))
+}
+
 fn main() -> Result<(), String> {
     let sdl_context = sdl2::init()?;
 
@@ -87,8 +563,9 @@ fn main() -> Result<(), String> {
 
     println!("{} joysticks available", available);
 
-    // Iterate over all available joysticks and stop once we manage to open one.
-    let mut joystick = (0..available).find_map(|id| match joystick_subsystem.open(id) {
+    // Open every available joystick. Each player gets one, in the order they were opened,
+    // and joysticks only send events while they stay open.
+    let joysticks: Vec<sdl2::joystick::Joystick> = (0..available).filter_map(|id| match joystick_subsystem.open(id) {
         Ok(c) => {
             println!("Success: opened \"{}\"", c.name());
             Some(c)
@@ -97,11 +574,13 @@ fn main() -> Result<(), String> {
             println!("failed: {:?}", e);
             None
         },
-    }).expect("Couldn't open any joystick");
+    }).collect();
 
-    // Print the joystick's power level
-    println!("\"{}\" power level: {:?}", joystick.name(), joystick.power_level()
-        .map_err(|e| e.to_string())?);
+    // Print the joysticks' power levels
+    for joystick in &joysticks {
+        println!("\"{}\" power level: {:?}", joystick.name(), joystick.power_level()
+            .map_err(|e| e.to_string())?);
+    }
 
     /* window initialization */
 
@@ -116,27 +595,319 @@ fn main() -> Result<(), String> {
 
     let mut events = sdl_context.event_pump()?;
 
-    let mut playthrough: GuitarPlaythrough = std::fs::read_to_string("Songs/notes.chart")
-        .map_err(|e| e.to_string())
-        .and_then(|file| chart::read(file.as_ref())
-            .map_err(|e| { println!("Error: {:?}", e); return String::from("couldn't parse chart") })) // TODO: error to string
-        .and_then(|chart| GuitarPlaythrough::new(chart)
-            .map_err(|s| String::from(s)))?;
+    // `bumpit` plays the song and records a replay, `bumpit <replay file>` watches a recorded replay,
+    // and `bumpit --autoplay` watches the autoplay bot play the song.
+    // `--part <instrument> <difficulty>` (e.g. `--part bass hard`) picks the part, Expert Guitar by default.
+    // Repeating `--part` adds a player for each part, up to four, who play together on one song.
+    // `--coop <difficulty>` is two players: lead guitar, and the song's bass or rhythm part (or co-op guitar).
+    // `--sections` lists the chart's sections, and `--practice <first> <last> <speed %>` (e.g. `--practice 2 3 75`)
+    // loops from the first listed section through the last at 50-100% speed.
+    // `--modifier <name>` turns on a modifier for every guitar player: all_strums, all_hopos, all_taps, mirror, lefty or precision.
+    // `--audio-latency <ms>` and `--video-latency <ms>` set how late this machine's sound and picture are, and are kept for later runs.
+    // `--calibrate` measures them instead, by tapping along to a metronome and a flashing box.
+    // `--bind <guitar|six_fret|drums>` rebinds a device's controls for the part, and saves them to the bindings file.
+    let mut replay_path: Option<String> = None;
+    let mut autoplaying = false;
+    let mut parts: Vec<(chart::Instrument, chart::Difficulty)> = Vec::new();
+    let mut coop: Option<chart::Difficulty> = None;
+    let mut listing_sections = false;
+    let mut practice_range: Option<(usize, usize, f32)> = None;
+    let mut modifiers = Modifiers::default();
+
+    // a machine without a latency file hasn't been calibrated
+    let mut latency = match std::fs::read_to_string(latency::LATENCY_PATH) {
+        Ok(file) => latency::read(file.as_ref())
+            .map_err(|e| { println!("Error: {:?}", e); return String::from("couldn't parse latency settings") })?,
+        Err(_) => Latency::default(),
+    };
+    let mut latency_changed = false;
+    let mut calibrating = false;
+
+    // devices without bindings of their own in the bindings file use the default bindings
+    let mut bindings = match std::fs::read_to_string(bindings::BINDINGS_PATH) {
+        Ok(file) => bindings::read(file.as_ref())
+            .map_err(|e| { println!("Error: {:?}", e); return String::from("couldn't parse bindings") })?,
+        Err(_) => Bindings::default(),
+    };
+    let mut rebinding: Option<BindingKind> = None;
 
-    fn draw<T: sdl2::render::RenderTarget>(canvas: &mut sdl2::render::Canvas<T>, playthrough: &GuitarPlaythrough, time: f32) {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--autoplay" => autoplaying = true,
+            "--part" => parts.push((
+                args.next().and_then(|name| chart::Instrument::from_name(&name))
+                    .ok_or_else(|| String::from("--part needs an instrument"))?,
+                args.next().and_then(|name| chart::Difficulty::from_name(&name))
+                    .ok_or_else(|| String::from("--part needs a difficulty"))?,
+            )),
+            "--coop" => coop = Some(args.next().and_then(|name| chart::Difficulty::from_name(&name))
+                .ok_or_else(|| String::from("--coop needs a difficulty"))?),
+            "--sections" => listing_sections = true,
+            "--practice" => practice_range = Some((
+                args.next().and_then(|number| number.parse::<usize>().ok()).filter(|number| *number > 0)
+                    .ok_or_else(|| String::from("--practice needs a first section"))? - 1,
+                args.next().and_then(|number| number.parse::<usize>().ok()).filter(|number| *number > 0)
+                    .ok_or_else(|| String::from("--practice needs a last section"))? - 1,
+                args.next().and_then(|percent| percent.parse::<f32>().ok())
+                    .ok_or_else(|| String::from("--practice needs a speed"))? / 100f32,
+            )),
+            "--modifier" => args.next().ok_or_else(|| "--modifier needs a name")
+                .and_then(|name| modifiers.enable(&name))
+                .map_err(|s| String::from(s))?,
+            "--calibrate" => calibrating = true,
+            "--bind" => rebinding = Some(args.next().and_then(|name| BindingKind::from_name(&name))
+                .ok_or_else(|| String::from("--bind needs guitar, six_fret or drums"))?),
+            "--audio-latency" => {
+                latency.audio_ms = args.next().and_then(|ms| ms.parse::<f32>().ok())
+                    .ok_or_else(|| String::from("--audio-latency needs a time in ms"))?;
+                latency_changed = true;
+            },
+            "--video-latency" => {
+                latency.video_ms = args.next().and_then(|ms| ms.parse::<f32>().ok())
+                    .ok_or_else(|| String::from("--video-latency needs a time in ms"))?;
+                latency_changed = true;
+            },
+            _ => replay_path = Some(arg),
+        }
+    }
+
+    if calibrating {
+        let audio_subsystem = sdl_context.audio()?;
+        if let Some(calibrated) = calibrate(&mut canvas, &mut events, &audio_subsystem, latency)? {
+            save_latency(&calibrated)?;
+        }
+        return Ok(());
+    }
+    if latency_changed {
+        save_latency(&latency)?;
+    }
+    if let Some(kind) = rebinding {
+        if let Some(device_bindings) = rebind(&mut canvas, &mut events, &joysticks, kind)? {
+            bindings.set(device_bindings);
+            std::fs::create_dir_all("Settings").map_err(|e| e.to_string())?;
+            std::fs::write(bindings::BINDINGS_PATH, bindings.write()).map_err(|e| e.to_string())?;
+            println!("Saved bindings to {}", bindings::BINDINGS_PATH);
+        }
+        return Ok(());
+    }
+
+    let mut watching: Option<Replay> = match replay_path {
+        Some(ref path) if !autoplaying => Some(std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| replay::read(file.as_ref())
+                .map_err(|e| { println!("Error: {:?}", e); return String::from("couldn't parse replay") }))?),
+        _ => None,
+    };
+
+    let chart_file = std::fs::read_to_string("Songs/notes.chart").map_err(|e| e.to_string())?;
+    let chart_hash = replay::chart_hash(&chart_file);
+    let chart = chart::read(chart_file.as_ref())
+        .map_err(|e| { println!("Error: {:?}", e); return String::from("couldn't parse chart") })?; // TODO: error to string
+
+    if watching.as_ref().map_or(false, |replay| replay.chart_hash != chart_hash) {
+        println!("Warning: the replay was recorded on a different chart");
+    }
+
+    if listing_sections {
+        for (i, section) in Section::from_chart(&chart).iter().enumerate() {
+            println!("{}: {}", i + 1, section.name);
+        }
+        return Ok(());
+    }
+
+    if let Some(difficulty) = coop {
+        if !parts.is_empty() {
+            return Err(String::from("--coop picks both players' parts"));
+        }
+        let coop_instrument = chart.coop_instrument(difficulty)
+            .ok_or_else(|| format!("the chart has no bass, rhythm or co-op guitar part on {}", difficulty.name()))?;
+        parts = vec![(chart::Instrument::Guitar, difficulty), (coop_instrument, difficulty)];
+    }
+    if parts.is_empty() {
+        parts.push((chart::Instrument::Guitar, chart::Difficulty::Expert));
+    }
+    if let Some(replay) = &watching {
+        parts = vec![(replay.instrument, replay.difficulty)];
+    }
+
+    // replays, autoplay and practice are of a single guitar player
+    let single_guitar = parts.len() == 1 && parts[0].0 != chart::Instrument::Drums;
+    if autoplaying && !single_guitar {
+        return Err(String::from("autoplay plays a single guitar part"));
+    }
+    if practice_range.is_some() && (!single_guitar || watching.is_some() || autoplaying) {
+        return Err(String::from("practice is for a single guitar part"));
+    }
+
+    let playthroughs = parts.iter().map(|&(instrument, difficulty)| {
+        let chart = chart.clone();
+        match instrument {
+            chart::Instrument::Drums => {
+                let settings = DrumSettings { audio_latency_ms: latency.audio_ms, ..DrumSettings::for_difficulty(&difficulty) };
+                DrumPlaythrough::new(chart, difficulty, settings)
+                    .map(PlayerPlaythrough::Drums)
+            },
+            _ => {
+                // a replay plays with the modifiers it was recorded with
+                let settings = watching.as_ref().map_or_else(
+                    || GuitarSettings { modifiers: modifiers, audio_latency_ms: latency.audio_ms, ..GuitarSettings::for_difficulty(&difficulty) },
+                    |replay| replay.settings);
+                GuitarPlaythrough::new(chart, instrument, difficulty, settings)
+                    .map(PlayerPlaythrough::Guitar)
+            },
+        }.map_err(|s| String::from(s))
+    }).collect::<Result<Vec<PlayerPlaythrough>, String>>()?;
+
+    let mut band = Band::new(playthroughs).map_err(|s| String::from(s))?;
+
+    // each player's joystick only plays for them, and the keyboard plays for the first player without one
+    let player_joysticks: Vec<Option<i32>> = (0..band.players.len())
+        .map(|player| joysticks.get(player).map(|joystick| joystick.instance_id()))
+        .collect();
+    let player_devices: Vec<Vec<Device>> = (0..band.players.len())
+        .map(|player| joysticks.get(player).map_or_else(Vec::new, joystick_devices))
+        .collect();
+    let keyboard_devices = vec![Device::Keyboard];
+    let without_joystick: Vec<usize> = (0..band.players.len())
+        .filter(|&player| player_joysticks[player].is_none())
+        .collect();
+    let keyboard_player = without_joystick.first().copied().unwrap_or(0);
+    if without_joystick.len() > 1 && watching.is_none() && !autoplaying {
+        let names: Vec<String> = without_joystick[1..].iter().map(|player| format!("player {}", player + 1)).collect();
+        return Err(format!("{} {} no device to play on: player {} plays on the keyboard, so connect a joystick for each of the others",
+            names.join(", "), if names.len() == 1 { "has" } else { "have" }, keyboard_player + 1));
+    }
+    for (player, joystick) in joysticks.iter().take(band.players.len()).enumerate() {
+        println!("Player {} plays on \"{}\"", player + 1, joystick.name());
+    }
+    println!("Player {} plays on the keyboard", keyboard_player + 1);
+
+    // practice seeks around the song, which replays can't record
+    let mut practice: Option<Practice> = None;
+    if let (Some((first, last, speed)), PlayerPlaythrough::Guitar(playthrough)) = (practice_range, &mut band.players[0].playthrough) {
+        let practicing = Practice::new(&playthrough.chart, first, last, speed).map_err(|s| String::from(s))?;
+        playthrough.settings = practicing.settings(playthrough.settings);
+        practice = Some(practicing);
+    }
+
+    let mut recording: Option<Replay> = None;
+    if let PlayerPlaythrough::Guitar(playthrough) = &band.players[0].playthrough {
+        if autoplaying {
+            watching = Some(autoplay::replay(playthrough, chart_hash));
+        } else if single_guitar && watching.is_none() && practice.is_none() {
+            recording = Some(Replay::new(chart_hash, playthrough.instrument, playthrough.difficulty, playthrough.settings));
+        }
+    }
+    let mut replay_position = 0;
+
+    // each player's results get a column, with the band's score above them when there's more than one player
+    fn draw_results<T: sdl2::render::RenderTarget>(canvas: &mut sdl2::render::Canvas<T>, band: &Band) {
         canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
         canvas.clear();
 
-        for i in 0..playthrough.notes_hit {
+        let white = pixels::Color::RGB(255, 255, 255);
+        let column_width = (SCREEN_WIDTH as i16) / (band.players.len() as i16);
+        let top = if band.players.len() > 1 {
+            let _ = canvas.string(50, 30, &format!("Band score: {}", band.score), white);
+            70
+        } else {
+            50
+        };
+
+        for (player, results) in band.players.iter().filter_map(|player| player.results.as_ref()).enumerate() {
+            let left = 50 / (band.players.len() as i16) + (player as i16) * column_width;
+            for (i, line) in results_lines(results).into_iter().enumerate() {
+                let _ = canvas.string(left, top + (i as i16) * 12, &line, white);
+            }
+        }
+
+        canvas.present();
+    }
+
+    fn results_lines(results: &PlayerResults) -> Vec<String> {
+        let (failed, score, notes_hit, notes_total, accuracy, best_streak, sp_phrases, timing, sections) = match results {
+            PlayerResults::Guitar(results) => (results.failed, results.score, results.notes_hit, results.notes_total, results.accuracy(),
+                results.best_streak, results.sp_phrases, &results.timing, &results.sections),
+            PlayerResults::Drums(results) => (results.failed, results.score, results.notes_hit, results.notes_total, results.accuracy(),
+                results.best_streak, results.sp_phrases, &results.timing, &results.sections),
+        };
+        let instrument_line = match results {
+            PlayerResults::Guitar(results) => {
+                let modifier_names = results.modifiers.names();
+                format!("Modifiers: {}", if modifier_names.is_empty() { String::from("none") } else { modifier_names.join(", ") })
+            },
+            PlayerResults::Drums(results) => format!("Fill notes played freestyle: {} / Overhits: {}", results.notes_freestyle, results.overhits),
+        };
+        let lines = vec![
+            String::from(if failed { "FAILED" } else { "SONG COMPLETE" }),
+            format!("Score: {}", score),
+            instrument_line,
+            format!("Notes: {} / {} ({:.1}%)", notes_hit, notes_total, accuracy * 100f32),
+            format!("Best streak: {}", best_streak),
+            format!("Star power phrases: {}", sp_phrases),
+            format!("Perfect {} / Great {} / Good {}", timing.perfect, timing.great, timing.good),
+            format!("Average offset: {:+.1} ms", timing.mean_offset_ms()),
+        ];
+        let section_lines = sections.iter().map(|section| {
+            format!("{}: {} / {} ({:.1}%)", section.name, section.notes_hit, section.notes_total, section.accuracy() * 100f32)
+        });
+
+        lines.into_iter().chain(std::iter::once(String::new())).chain(section_lines).collect()
+    }
+
+    // the parts of the screen every instrument shares: meters, pause and resume countdown
+    fn draw_hud<T: sdl2::render::RenderTarget, P: Playthrough>(canvas: &mut sdl2::render::Canvas<T>, playthrough: &P, paused: bool) {
+        // rock meter and star power meter
+        let meter_bottom = (SCREEN_HEIGHT as i16) - 50;
+        let meter_height = 400f32;
+        let rock_color = if playthrough.rock_meter() < 0.25 { pixels::Color::RGB(200, 0, 0) } else { pixels::Color::RGB(0, 200, 0) };
+        let _ = canvas.box_(700, meter_bottom - (playthrough.rock_meter() * meter_height) as i16, 720, meter_bottom, rock_color);
+        let _ = canvas.rectangle(700, meter_bottom - meter_height as i16, 720, meter_bottom, pixels::Color::RGB(255, 255, 255));
+        let _ = canvas.box_(740, meter_bottom - (playthrough.sp_meter() * meter_height) as i16, 760, meter_bottom, pixels::Color::RGB(0, 160, 255));
+        let _ = canvas.rectangle(740, meter_bottom - meter_height as i16, 760, meter_bottom, pixels::Color::RGB(255, 255, 255));
+
+        if paused {
+            let _ = canvas.string((SCREEN_WIDTH as i16) / 2 - 24, (SCREEN_HEIGHT as i16) / 2, "PAUSED", pixels::Color::RGB(255, 255, 255));
+        } else if let Some(countdown_ms) = playthrough.countdown_ms() {
+            let count = (countdown_ms / 1000f32).ceil().to_string();
+            let _ = canvas.string((SCREEN_WIDTH as i16) / 2 - 4, (SCREEN_HEIGHT as i16) / 2, &count, pixels::Color::RGB(255, 255, 255));
+        }
+    }
+
+    // the loop's speed and the accuracy of its last passes, newest first
+    fn draw_practice<T: sdl2::render::RenderTarget>(canvas: &mut sdl2::render::Canvas<T>, practice: &Practice) {
+        let white = pixels::Color::RGB(255, 255, 255);
+        let _ = canvas.string(500, 30, &format!("Practice {:.0}%", practice.speed * 100f32), white);
+        for (i, (number, stats)) in practice.loops.iter().enumerate().rev().take(10).enumerate() {
+            let line = format!("Loop {}: {:.1}%", number + 1, stats.accuracy() * 100f32);
+            let _ = canvas.string(500, 45 + (i as i16) * 12, &line, white);
+        }
+    }
+
+    fn draw_guitar<T: sdl2::render::RenderTarget>(canvas: &mut sdl2::render::Canvas<T>, playthrough: &GuitarPlaythrough, time: f32) {
+        for i in 0..playthrough.state.notes_hit {
             let _ = draw_fret(&canvas, true, (i as i16) * 10, 10, 5, pixels::Color::RGB(255, 255, 255));
         }
 
+        let layout = playthrough.chart.layout;
         let frets = playthrough.frets;
-        let _ = draw_fret(&canvas, frets[Fret::G as usize], 50, (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(0, 128, 0));
-        let _ = draw_fret(&canvas, frets[Fret::R as usize], 150, (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(128, 0, 0));
-        let _ = draw_fret(&canvas, frets[Fret::Y as usize], 250, (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(128, 128, 0));
-        let _ = draw_fret(&canvas, frets[Fret::B as usize], 350, (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(0, 0, 128));
-        let _ = draw_fret(&canvas, frets[Fret::O as usize], 450, (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(192, 128, 00));
+        // lefty flips the highway around its middle
+        let lefty = playthrough.settings.modifiers.lefty;
+        let flip = |x: i16| if !lefty { x } else { match layout { FretLayout::Five => 500 - x, FretLayout::Six => 450 - x } };
+        match layout {
+            FretLayout::Five => {
+                let _ = draw_fret(&canvas, frets[Fret::G.lane()], flip(50), (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(0, 128, 0));
+                let _ = draw_fret(&canvas, frets[Fret::R.lane()], flip(150), (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(128, 0, 0));
+                let _ = draw_fret(&canvas, frets[Fret::Y.lane()], flip(250), (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(128, 128, 0));
+                let _ = draw_fret(&canvas, frets[Fret::B.lane()], flip(350), (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(0, 0, 128));
+                let _ = draw_fret(&canvas, frets[Fret::O.lane()], flip(450), (SCREEN_HEIGHT as i16) - 75, 25, pixels::Color::RGB(192, 128, 00));
+            },
+            FretLayout::Six => for fret in layout.frets() {
+                let (x, color) = six_fret_lane(fret.lane());
+                let _ = draw_fret(&canvas, frets[fret.lane()], flip(x), (SCREEN_HEIGHT as i16) - 75, 22, color);
+            },
+        }
 
         for note in &playthrough.chart.notes {
             let position_past_time = playthrough.chart.ticks_to_ms(note.ticks) - time;
@@ -146,64 +917,66 @@ fn main() -> Result<(), String> {
             }
             let y = ((1f32 - progress_on_screen) * (SCREEN_HEIGHT as f32)) as i16 - 75;
 
+            let open_right = match layout { FretLayout::Five => 462, FretLayout::Six => 412 };
             if note.is_open() {
-                let _ = canvas.rectangle(50, y - 2, 462, y + 2, pixels::Color::RGB(200, 60, 200));
+                let _ = canvas.rectangle(std::cmp::min(flip(50), flip(open_right)), y - 2, std::cmp::max(flip(50), flip(open_right)), y + 2, pixels::Color::RGB(200, 60, 200));
             } else {
                 note.chord.iter()
                     .enumerate()
                     .filter(|(_i, chord_note)| **chord_note)
                     .for_each(|(note_index, _chord_note)| {
-                        let _ = draw_fret(&canvas, true, 50 + (note_index as i16) * 100, y, 17, pixels::Color::RGB(60, 80, 100));
+                        let (x, color) = match layout {
+                            FretLayout::Five => (50 + (note_index as i16) * 100, pixels::Color::RGB(60, 80, 100)),
+                            FretLayout::Six => six_fret_lane(note_index),
+                        };
+                        let _ = draw_fret(&canvas, true, flip(x), y, 17, color);
                     });
             }
         }
+    }
 
-        canvas.present();
-    };
-
-    fn input<'a>(events: &'a mut sdl2::EventPump) -> impl Iterator<Item = Option<GameInputAction>> + 'a {
-        events.poll_iter()
-            .map(|event| match event {
-                Event::Quit {..} => Some(GameInputAction::Quit),
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => Some(GameInputAction::Quit),
-
-                Event::KeyDown { keycode : Some(Keycode::Z), .. } => Some(GameInputAction::ButtonDown(GameButton::Green)),
-                Event::KeyDown { keycode : Some(Keycode::X), .. } => Some(GameInputAction::ButtonDown(GameButton::Red)),
-                Event::KeyDown { keycode : Some(Keycode::C), .. } => Some(GameInputAction::ButtonDown(GameButton::Yellow)),
-                Event::KeyDown { keycode : Some(Keycode::V), .. } => Some(GameInputAction::ButtonDown(GameButton::Blue)),
-                Event::KeyDown { keycode : Some(Keycode::B), .. } => Some(GameInputAction::ButtonDown(GameButton::Orange)),
-
-                Event::KeyUp { keycode : Some(Keycode::Z), .. } => Some(GameInputAction::ButtonUp(GameButton::Green)),
-                Event::KeyUp { keycode : Some(Keycode::X), .. } => Some(GameInputAction::ButtonUp(GameButton::Red)),
-                Event::KeyUp { keycode : Some(Keycode::C), .. } => Some(GameInputAction::ButtonUp(GameButton::Yellow)),
-                Event::KeyUp { keycode : Some(Keycode::V), .. } => Some(GameInputAction::ButtonUp(GameButton::Blue)),
-                Event::KeyUp { keycode : Some(Keycode::B), .. } => Some(GameInputAction::ButtonUp(GameButton::Orange)),
-
-                Event::KeyDown { keycode : Some(Keycode::Space), .. } => Some(GameInputAction::Strum),
+    fn draw_drums<T: sdl2::render::RenderTarget>(canvas: &mut sdl2::render::Canvas<T>, playthrough: &DrumPlaythrough, time: f32) {
+        for i in 0..playthrough.state.notes_hit {
+            let _ = draw_fret(&canvas, true, (i as i16) * 10, 10, 5, pixels::Color::RGB(255, 255, 255));
+        }
 
-                Event::JoyButtonDown { button_idx : 0, .. } => Some(GameInputAction::ButtonDown(GameButton::Green)),
-                Event::JoyButtonDown { button_idx : 1, .. } => Some(GameInputAction::ButtonDown(GameButton::Red)),
-                Event::JoyButtonDown { button_idx : 3, .. } => Some(GameInputAction::ButtonDown(GameButton::Yellow)),
-                Event::JoyButtonDown { button_idx : 2, .. } => Some(GameInputAction::ButtonDown(GameButton::Blue)),
-                Event::JoyButtonDown { button_idx : 4, .. } => Some(GameInputAction::ButtonDown(GameButton::Orange)),
+        for &pad in &[Pad::Red, Pad::YellowTom, Pad::BlueTom, Pad::GreenTom] {
+            if let Some((x, color)) = drum_lane(pad) {
+                let _ = draw_fret(&canvas, false, x, (SCREEN_HEIGHT as i16) - 75, 25, color);
+            }
+        }
 
-                Event::JoyButtonUp { button_idx : 0, .. } => Some(GameInputAction::ButtonUp(GameButton::Green)),
-                Event::JoyButtonUp { button_idx : 1, .. } => Some(GameInputAction::ButtonUp(GameButton::Red)),
-                Event::JoyButtonUp { button_idx : 3, .. } => Some(GameInputAction::ButtonUp(GameButton::Yellow)),
-                Event::JoyButtonUp { button_idx : 2, .. } => Some(GameInputAction::ButtonUp(GameButton::Blue)),
-                Event::JoyButtonUp { button_idx : 4, .. } => Some(GameInputAction::ButtonUp(GameButton::Orange)),
+        for note in &playthrough.chart.notes {
+            let position_past_time = playthrough.chart.ticks_to_ms(note.ticks) - time;
+            let progress_on_screen = position_past_time / 1000f32;
+            if progress_on_screen > 1f32 || progress_on_screen < 0f32 {
+                continue;
+            }
+            let y = ((1f32 - progress_on_screen) * (SCREEN_HEIGHT as f32)) as i16 - 75;
 
-                Event::JoyHatMotion { hat_idx : 0, state : sdl2::joystick::HatState::Up, .. } => Some(GameInputAction::Strum),
-                Event::JoyHatMotion { hat_idx : 0, state : sdl2::joystick::HatState::Down, .. } => Some(GameInputAction::Strum),
+            // toms are drawn filled and cymbals hollow
+            match drum_lane(note.pad) {
+                Some((x, color)) => { let _ = draw_fret(&canvas, !note.pad.is_cymbal(), x, y, 17, color); },
+                None => { let _ = canvas.rectangle(50, y - 2, 362, y + 2, pixels::Color::RGB(192, 128, 0)); },
+            }
+        }
+    }
 
-                _ => None
-            })
+    // quitting and pausing work from every player's device
+    fn global_input(event: &Event) -> Option<GameInputAction> {
+        match *event {
+            Event::Quit {..} => Some(GameInputAction::Quit),
+            Event::KeyDown { keycode: Some(Keycode::Escape), .. } => Some(GameInputAction::Quit),
+            Event::KeyDown { keycode: Some(Keycode::P), .. } => Some(GameInputAction::Pause),
+            Event::JoyButtonDown { button_idx : 7, .. } => Some(GameInputAction::Pause),
+            _ => None
+        }
     }
 
     // for power-saving. if Some, the game will sleep for
     const FRAME_LIMIT: Option<FrameLimit> = Option::Some(FrameLimit::Cap(120));
 
-    // TODO: enable vsync based on frame_limit
+    // TODO: add a vsync frame limit
     // https://wiki.libsdl.org/SDL_GL_SetSwapInterval
 
     // TODO: process inputs more frequently than once per frame?
@@ -213,74 +986,245 @@ fn main() -> Result<(), String> {
     // that results in that frequency (at runtime)
     // and ensure game loop handles huge outliers in sleep wakeup time
 
-    let mut music = ears::Sound::new("Songs/song.ogg")?;
-    music.play();
+    let audio_subsystem = sdl_context.audio()?;
+    let song = chart.song;
+    let speed = practice.as_ref().map_or(1f32, |practice| practice.speed);
+    let decoder = audio::decode_song(std::path::Path::new("Songs"), &song.streams)?;
+    let mut music = Music::new(&audio_subsystem, decoder.pcm(), Some(decoder), speed)?;
+
+    // the song clock starts during the lead-in, and the music once the clock reaches its start.
+    // every player shares the one clock
+    let audio_offset_ms = band.audio_offset_ms();
+    let mut music_started = false;
 
     let mut previous_frame_time = Instant::now();
-    let mut last_playhead_pos_ms = 0f32;
-    let mut song_time_ms = 0f32;
+    let mut song_time_ms = band.start_time_ms();
+    if let (Some(practice), PlayerPlaythrough::Guitar(playthrough)) = (practice.as_mut(), &mut band.players[0].playthrough) {
+        song_time_ms = practice.start_loop(playthrough);
+    }
+    // the song clock runs slower while practicing slowed down, as does the music
+    let mut last_playhead_pos_ms = song_time_ms;
+    let mut paused = false;
 
     let mut run = true;
     while run {
         // https://www.reddit.com/r/gamedev/comments/13y26t/how_do_rhythm_games_stay_in_sync_with_the_music/c78aawd/
         let this_frame_time = Instant::now();
-        song_time_ms += this_frame_time.duration_since(previous_frame_time).as_millis() as f32;
+        if !paused {
+            song_time_ms += this_frame_time.duration_since(previous_frame_time).as_millis() as f32 * speed;
+        }
         previous_frame_time = this_frame_time;
 
-        let playhead_pos_ms = music.get_offset() * 1000f32;
-        if playhead_pos_ms != last_playhead_pos_ms {
+        if let Err(e) = music.receive_decoded() {
+            println!("Error decoding song: {}", e);
+        }
+
+        if !paused && !music_started && song_time_ms + audio_offset_ms >= 0f32 {
+            music.set_offset_ms(song_time_ms + audio_offset_ms);
+            music.play();
+            music_started = true;
+            last_playhead_pos_ms = music.offset_ms() - audio_offset_ms;
+        }
+
+        if !paused && music_started && !music.is_playing() {
+            band.end_audio();
+        }
+
+        let playhead_pos_ms = music.offset_ms() - audio_offset_ms;
+        if !paused && music_started && playhead_pos_ms != last_playhead_pos_ms {
             song_time_ms = (song_time_ms + playhead_pos_ms) / 2f32;
             last_playhead_pos_ms = playhead_pos_ms;
         }
 
-        let effects = input(&mut events)
-            .filter_map(|action| match action {
-                Some(GameInputAction::Quit) => Some(GameInputEffect::Quit),
-                Some(action) => match action.to_guitar_action() {
-                    Some(guitar_action) => {
-                        // sdl's event timestamps are always later than the OS timestamp
-                        // so just assume that events are happening at this instant
-                        // TODO: can we do better?
-                        // TODO: track inputs for replays?
-                        playthrough.apply(&guitar_action, song_time_ms).map(|e| GameInputEffect::GuitarEffect(e))
-                    },
-                    None => None,
-                },
-                None => None,
-            });
+        let mut effects: Vec<GameInputEffect> = Vec::new();
+        for event in events.poll_iter() {
+            match global_input(&event) {
+                Some(GameInputAction::Quit) => { effects.push(GameInputEffect::Quit); continue; },
+                Some(GameInputAction::Pause) => { effects.push(GameInputEffect::TogglePause); continue; },
+                _ => (),
+            }
+            if paused || watching.is_some() {
+                continue;
+            }
+
+            let player = match event_joystick(&event) {
+                Some(which) => player_joysticks.iter().position(|joystick| *joystick == Some(which)),
+                None => Some(keyboard_player),
+            };
+            let player = match player {
+                Some(player) => player,
+                None => continue,
+            };
+
+            let kind = match &band.players[player].playthrough {
+                PlayerPlaythrough::Guitar(playthrough) if playthrough.chart.layout == FretLayout::Six => BindingKind::SixFret,
+                PlayerPlaythrough::Guitar(_) => BindingKind::Guitar,
+                PlayerPlaythrough::Drums(_) => BindingKind::Drums,
+            };
+            let devices = match event_joystick(&event) {
+                Some(_) => &player_devices[player],
+                None => &keyboard_devices,
+            };
+            let game_action = binding_input(&event)
+                .and_then(|input| bindings.lookup(kind, devices, &input))
+                .and_then(|(action, press)| GameInputAction::from_binding(action, press));
+
+            let action = match &band.players[player].playthrough {
+                PlayerPlaythrough::Guitar(_) => game_action
+                    .and_then(|action| action.to_guitar_action())
+                    .map(PlayerInputAction::Guitar),
+                PlayerPlaythrough::Drums(_) => game_action
+                    .and_then(|action| action.to_drum_action())
+                    .map(PlayerInputAction::Drums),
+            };
+
+            if let Some(action) = action {
+                // sdl's event timestamps are always later than the OS timestamp
+                // so just assume that events are happening at this instant
+                // TODO: can we do better?
+                if let (Some(recording), PlayerInputAction::Guitar(guitar_action)) = (recording.as_mut(), action) {
+                    recording.record_input(guitar_action, song_time_ms);
+                }
+                effects.extend(band.apply(player, &action, song_time_ms)
+                    .into_iter()
+                    .map(|e| GameInputEffect::BandEffect(e)));
+            }
+        }
+
+        match &watching {
+            // replay everything the recording game loop did up to now, including its pauses
+            Some(replay) if !paused => {
+                while let Some(event) = replay.events.get(replay_position).filter(|event| event.time_ms() <= song_time_ms) {
+                    let player_effects: Vec<PlayerEffect> = match &mut band.players[0].playthrough {
+                        PlayerPlaythrough::Guitar(playthrough) => event.play(playthrough).into_iter().map(PlayerEffect::Guitar).collect(),
+                        PlayerPlaythrough::Drums(_) => vec![],
+                    };
+                    effects.extend(band.handle_effects(0, player_effects)
+                        .into_iter()
+                        .map(|e| GameInputEffect::BandEffect(e)));
 
-        effects.for_each(|effect: GameInputEffect| {
+                    if let replay::ReplayEvent::Resume { .. } = event {
+                        song_time_ms = band.players[0].playthrough.time_ms();
+                        music.pause();
+                        music_started = false;
+                    }
+
+                    replay_position += 1;
+                }
+            },
+            Some(_) => (),
+            None if !paused => {
+                if let Some(recording) = recording.as_mut() {
+                    recording.record_time(song_time_ms);
+                }
+                effects.extend(band.update_time(song_time_ms)
+                    .into_iter()
+                    .map(|e| GameInputEffect::BandEffect(e)));
+
+                // start the next pass once the loop is over, even if the loop ended the song
+                if let (Some(practice), PlayerPlaythrough::Guitar(playthrough)) = (practice.as_mut(), &mut band.players[0].playthrough) {
+                    if let Some(stats) = practice.update_time(playthrough, song_time_ms) {
+                        println!("Loop {}: {} / {} ({:.1}%)", practice.loops.len(), stats.notes_hit, stats.notes_total, stats.accuracy() * 100f32);
+                        song_time_ms = practice.start_loop(playthrough);
+                        band.players[0].results = None;
+                        music.pause();
+                        music_started = false;
+                    }
+                }
+            },
+            None => (),
+        }
+
+        let mut toggle_pause = false;
+
+        effects.into_iter().for_each(|effect: GameInputEffect| {
             match effect {
                 GameInputEffect::Quit => run = false,
-                GameInputEffect::GuitarEffect(effect) => match effect {
-                    Hit => (),
-                    Overstrum => (),
-                    MissStreak => (),
-                    MissNoStreak => (),
-                    ReleaseSustain => (),
-                }
+                GameInputEffect::TogglePause => toggle_pause = !toggle_pause,
+                // each player's results are kept by the band
+                GameInputEffect::BandEffect(BandEffect::Player(_player, PlayerEffect::Guitar(effect))) => match effect {
+                    GuitarGameEffect::Hit { .. } => (),
+                    GuitarGameEffect::Overstrum => (),
+                    GuitarGameEffect::MissStreak => (),
+                    GuitarGameEffect::MissNoStreak => (),
+                    GuitarGameEffect::ReleaseSustain => (),
+                    GuitarGameEffect::StarPowerPhraseComplete { .. } => (),
+                    GuitarGameEffect::StarPowerActivated => (),
+                    GuitarGameEffect::StarPowerDeactivated => (),
+                    GuitarGameEffect::SongComplete(_) => (),
+                    GuitarGameEffect::Failed(_) => (),
+                },
+                GameInputEffect::BandEffect(BandEffect::Player(_player, PlayerEffect::Drums(_))) => (),
+                GameInputEffect::BandEffect(BandEffect::UnisonBonus) => (),
+                GameInputEffect::BandEffect(BandEffect::PlayerOut(_player)) => (),
+                GameInputEffect::BandEffect(BandEffect::PlayerSaved(_player)) => (),
+                GameInputEffect::BandEffect(BandEffect::Failed) => (),
             }
         });
 
-        playthrough.update_time(song_time_ms)
-            .map(|e| GameInputEffect::GuitarEffect(e))
-            .map(|effect: GameInputEffect| {
-                match effect {
-                    GameInputEffect::Quit => run = false,
-                    GameInputEffect::GuitarEffect(effect) => match effect {
-                        Hit => (),
-                        Overstrum => (),
-                        MissStreak => (),
-                        MissNoStreak => (),
-                        ReleaseSustain => (),
+        if toggle_pause {
+            // a replay pauses wherever it was recorded pausing, so watching one only freezes the clock
+            if paused {
+                if watching.is_none() {
+                    if let Some(recording) = recording.as_mut() {
+                        recording.record_resume(song_time_ms);
+                    }
+                    song_time_ms = band.resume();
+                }
+                music_started = false;
+            } else {
+                if watching.is_none() {
+                    if let Some(recording) = recording.as_mut() {
+                        recording.record_pause(song_time_ms);
                     }
+                    band.pause();
                 }
-            });
+                music.pause();
+            }
+            paused = !paused;
+        }
+
+        if band.failed() && music.is_playing() {
+            music.pause();
+        }
 
-        draw(&mut canvas, &playthrough, song_time_ms);
+        if band.is_over() {
+            draw_results(&mut canvas, &band);
+        } else {
+            canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+            canvas.clear();
+
+            // each player's highway is drawn full size into their own slice of the screen,
+            // at the time it will be seen rather than the time it is played
+            let draw_time_ms = latency.draw_time_ms(song_time_ms, speed);
+            let players = band.players.len() as u32;
+            for (i, player) in band.players.iter().enumerate() {
+                let _ = canvas.set_scale(1f32 / players as f32, 1f32);
+                canvas.set_viewport(Rect::new((i as u32 * SCREEN_WIDTH) as i32, 0, SCREEN_WIDTH, SCREEN_HEIGHT));
+                match &player.playthrough {
+                    PlayerPlaythrough::Guitar(playthrough) => draw_guitar(&mut canvas, playthrough, draw_time_ms),
+                    PlayerPlaythrough::Drums(playthrough) => draw_drums(&mut canvas, playthrough, draw_time_ms),
+                }
+                draw_hud(&mut canvas, &player.playthrough, paused);
+                if player.out_since_ms.is_some() {
+                    let _ = canvas.string((SCREEN_WIDTH as i16) / 2 - 28, (SCREEN_HEIGHT as i16) / 2 - 24, "SAVE ME!", pixels::Color::RGB(200, 0, 0));
+                }
+            }
+            let _ = canvas.set_scale(1f32, 1f32);
+            canvas.set_viewport(None);
+
+            if let Some(practice) = &practice {
+                draw_practice(&mut canvas, practice);
+            }
+
+            if players > 1 {
+                let _ = canvas.string((SCREEN_WIDTH as i16) / 2 - 60, 25, &format!("Band score: {} x{}", band.score, band.multiplier()), pixels::Color::RGB(255, 255, 255));
+            }
+
+            canvas.present();
+        }
 
         match FRAME_LIMIT {
-            Some(FrameLimit::Vsync) => (), // present() waits for vsync if on
             Some(FrameLimit::Cap(cap)) => {
                 ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / cap));
             },
@@ -288,5 +1232,15 @@ fn main() -> Result<(), String> {
         }
     }
 
+    if let Some(mut recording) = recording {
+        // only the first player is recorded, so the replay claims their own score rather than the band's
+        recording.claimed_score = Some(band.players[0].playthrough.score());
+        std::fs::create_dir_all("Replays").map_err(|e| e.to_string())?;
+        let recorded_at = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?.as_secs();
+        let path = format!("Replays/{}.replay", recorded_at);
+        std::fs::write(&path, recording.write()).map_err(|e| e.to_string())?;
+        println!("Saved replay to {}", path);
+    }
+
     Ok(())
 }
@@ -0,0 +1,328 @@
+use crate::guitarplaythrough::{RockMeterSettings, SectionStats, TimingGrade, TimingStats};
+
+pub const SP_PHRASE_METER: f32 = 0.25;
+pub const SP_ACTIVATION_METER: f32 = 0.5;
+pub const SP_METER_BEATS: f32 = 32.0; // a full meter lasts eight 4/4 measures
+const STREAK_PER_MULTIPLIER: u64 = 10;
+const MAX_STREAK_MULTIPLIER: u64 = 4;
+
+/// What the game loop needs from an instrument's gameplay engine.
+///
+/// Every engine runs on the song clock the game loop keeps: inputs are fed in with `apply`,
+/// the passing of time with `update_time`, and the loop reacts to the effects both return.
+/// Each engine reports its final statistics in its own Results type, since instruments count different things.
+pub trait Playthrough {
+    type InputAction;
+    type Results;
+    type Effect: PlaythroughEffect<Results = Self::Results>;
+
+    fn apply(self: &mut Self, action: &Self::InputAction, time_ms: f32) -> Vec<Self::Effect>;
+    fn update_time(self: &mut Self, time_ms: f32) -> Vec<Self::Effect>;
+
+    // timeline
+    fn time_ms(self: &Self) -> f32;
+    /// The song clock starts here, before the chart does
+    fn start_time_ms(self: &Self) -> f32;
+    /// Position in the audio of time 0 in the chart
+    fn audio_offset_ms(self: &Self) -> f32;
+    fn seek(self: &mut Self, time_ms: f32);
+    fn pause(self: &mut Self);
+    /// Returns the time the song clock should restart from
+    fn resume(self: &mut Self) -> f32;
+    fn countdown_ms(self: &Self) -> Option<f32>;
+    fn end_audio(self: &mut Self);
+
+    // state
+    fn score(self: &Self) -> u64;
+    fn streak(self: &Self) -> u64;
+    fn multiplier(self: &Self) -> u64;
+    fn sp_meter(self: &Self) -> f32; // 0 to 1
+    fn sp_active(self: &Self) -> bool;
+    fn rock_meter(self: &Self) -> f32; // 0 to 1
+    fn failed(self: &Self) -> bool;
+    fn results(self: &Self) -> Self::Results;
+}
+
+/// What the game loop needs to know about any engine's effects
+pub trait PlaythroughEffect {
+    type Results;
+
+    /// The final results, if this effect ends the song (by completing or failing it)
+    fn results(self: &Self) -> Option<Self::Results>;
+}
+
+/// How a note was resolved, kept so that seeking back before the note can take it back out of the statistics
+#[derive(Copy, Clone)]
+pub enum NoteJudgement {
+    Hit { points: u64, base_points: u64, offset_ms: f32, grade: TimingGrade, completed_phrase: bool },
+    Missed,
+    /// A drum fill note that was played freestyle
+    Freestyle,
+}
+
+/// The state every engine keeps the same way: the song clock, the score and statistics,
+/// the star power meter and the rock meter.
+///
+/// Engines decide which notes are hit or missed, and when star power and the rock meter move;
+/// this keeps the books. Methods that can end the song return whether it should fail,
+/// and the engine then fails it with its own effect.
+pub struct PlaythroughState {
+    pub score: u64,
+    /// The score without star power's doubling, which a band multiplies by its own multiplier instead
+    pub base_score: u64,
+    pub notes_hit: u64,
+    pub notes_missed: u64,
+    pub timing: TimingStats,
+    pub streak: u64,
+    pub best_streak: u64,
+    pub sp_phrases: u64,
+    /// One entry per section of the chart
+    pub section_stats: Vec<SectionStats>,
+    pub sp_meter: f32, // 0 to 1
+    pub sp_active: bool,
+    pub rock_meter: f32, // 0 to 1
+    pub failed: bool,
+    time: f32,
+    judging_from_ms: f32, // inputs before this time aren't judged
+    judgements: Vec<Option<NoteJudgement>>, // one per note, for the notes that were judged
+    sp_drained_to_beat: f32,
+    audio_ended: bool,
+    complete: bool,
+}
+
+impl PlaythroughState {
+    pub fn new(notes_count: usize, section_stats: Vec<SectionStats>, start_time_ms: f32, rock_meter: f32) -> PlaythroughState {
+        PlaythroughState {
+            score: 0,
+            base_score: 0,
+            notes_hit: 0,
+            notes_missed: 0,
+            timing: TimingStats::default(),
+            streak: 0,
+            best_streak: 0,
+            sp_phrases: 0,
+            section_stats: section_stats,
+            sp_meter: 0.0,
+            sp_active: false,
+            rock_meter: rock_meter,
+            failed: false,
+            time: start_time_ms,
+            judging_from_ms: std::f32::NEG_INFINITY,
+            judgements: vec![None; notes_count],
+            sp_drained_to_beat: 0.0,
+            audio_ended: false,
+            complete: false,
+        }
+    }
+
+    // song clock
+
+    pub fn time_ms(self: &Self) -> f32 {
+        self.time
+    }
+
+    pub fn set_time_ms(self: &mut Self, time_ms: f32) {
+        self.time = time_ms;
+    }
+
+    /// Whether an input at time_ms is judged, rather than being before the point a resume rewound from
+    pub fn judging(self: &Self, time_ms: f32) -> bool {
+        time_ms >= self.judging_from_ms
+    }
+
+    /// Rewinds from the paused point and returns the time the song clock should restart from.
+    /// The rewound notes are already judged, so nothing is judged again until the paused point.
+    pub fn resume(self: &mut Self, rewind_ms: f32, start_time_ms: f32) -> f32 {
+        let paused_ms = self.time;
+        self.judging_from_ms = paused_ms;
+        self.time = f32::max(paused_ms - rewind_ms, start_time_ms);
+        self.time
+    }
+
+    /// Time left on the resume countdown, if it is showing
+    pub fn countdown_ms(self: &Self, countdown_ms: f32) -> Option<f32> {
+        let remaining_ms = self.judging_from_ms - self.time;
+        if remaining_ms > 0.0 && remaining_ms <= countdown_ms {
+            Some(remaining_ms)
+        } else {
+            None
+        }
+    }
+
+    /// Moves the clock to time_ms, judging inputs from there on.
+    /// Star power drains from judged_beat on, and the song has to end again.
+    pub fn seek(self: &mut Self, time_ms: f32, judged_beat: f32) {
+        self.sp_drained_to_beat = judged_beat;
+        self.audio_ended = false;
+        self.complete = false;
+        self.time = time_ms;
+        self.judging_from_ms = time_ms;
+    }
+
+    pub fn end_audio(self: &mut Self) {
+        self.audio_ended = true;
+    }
+
+    pub fn audio_ended(self: &Self) -> bool {
+        self.audio_ended
+    }
+
+    /// Marks the song complete, returning true only the first time
+    pub fn complete(self: &mut Self) -> bool {
+        let was_complete = self.complete;
+        self.complete = true;
+        !was_complete
+    }
+
+    // score and statistics
+
+    pub fn multiplier(self: &Self) -> u64 {
+        let streak_multiplier = std::cmp::min(1 + self.streak / STREAK_PER_MULTIPLIER, MAX_STREAK_MULTIPLIER);
+        if self.sp_active { streak_multiplier * 2 } else { streak_multiplier }
+    }
+
+    /// Fraction of resolved notes that were hit
+    pub fn accuracy(self: &Self) -> f32 {
+        let resolved = self.notes_hit + self.notes_missed;
+        if resolved == 0 { 1.0 } else { self.notes_hit as f32 / resolved as f32 }
+    }
+
+    /// Scores a hit note, adding to the streak. A note that completes a star power phrase fills the meter.
+    pub fn record_hit(self: &mut Self, note_index: usize, section: Option<usize>, points: u64, offset_ms: f32, grade: TimingGrade, completed_phrase: bool) {
+        let base_points = if self.sp_active { points / 2 } else { points };
+        self.timing.record(offset_ms, grade);
+        self.score += points;
+        self.base_score += base_points;
+        self.notes_hit += 1;
+        if let Some(section) = section {
+            self.section_stats[section].score += points;
+            self.section_stats[section].notes_hit += 1;
+        }
+        self.streak += 1;
+        self.best_streak = std::cmp::max(self.best_streak, self.streak);
+        if completed_phrase {
+            self.sp_phrases += 1;
+            self.sp_meter = f32::min(self.sp_meter + SP_PHRASE_METER, 1.0);
+        }
+        self.judgements[note_index] = Some(NoteJudgement::Hit {
+            points: points,
+            base_points: base_points,
+            offset_ms: offset_ms,
+            grade: grade,
+            completed_phrase: completed_phrase,
+        });
+    }
+
+    /// Counts a missed note. The engine breaks the streak, once for however many notes it misses at once.
+    pub fn record_miss(self: &mut Self, note_index: usize, section: Option<usize>) {
+        self.notes_missed += 1;
+        if let Some(section) = section {
+            self.section_stats[section].notes_missed += 1;
+        }
+        self.judgements[note_index] = Some(NoteJudgement::Missed);
+    }
+
+    /// Counts a note played freestyle, which is neither hit nor missed, so it leaves its section's total
+    pub fn record_freestyle(self: &mut Self, note_index: usize, section: Option<usize>) {
+        if let Some(section) = section {
+            self.section_stats[section].notes_total -= 1;
+        }
+        self.judgements[note_index] = Some(NoteJudgement::Freestyle);
+    }
+
+    /// Takes however the note was judged back out of the score and statistics, so it can be judged again
+    pub fn take_back_judgement(self: &mut Self, note_index: usize, section: Option<usize>) -> Option<NoteJudgement> {
+        let judgement = self.judgements[note_index].take();
+        match judgement {
+            Some(NoteJudgement::Hit { points, base_points, offset_ms, grade, completed_phrase }) => {
+                self.score -= points;
+                self.base_score -= base_points;
+                self.notes_hit -= 1;
+                self.timing.forget(offset_ms, grade);
+                if completed_phrase {
+                    self.sp_phrases -= 1;
+                    self.sp_meter = f32::max(self.sp_meter - SP_PHRASE_METER, 0.0);
+                }
+                if let Some(section) = section {
+                    self.section_stats[section].score -= points;
+                    self.section_stats[section].notes_hit -= 1;
+                }
+            },
+            Some(NoteJudgement::Missed) => {
+                self.notes_missed -= 1;
+                if let Some(section) = section {
+                    self.section_stats[section].notes_missed -= 1;
+                }
+            },
+            Some(NoteJudgement::Freestyle) => {
+                if let Some(section) = section {
+                    self.section_stats[section].notes_total += 1;
+                }
+            },
+            None => (),
+        }
+        judgement
+    }
+
+    /// Breaks the streak, returning whether there was one to break
+    pub fn break_streak(self: &mut Self) -> bool {
+        let had_streak = self.streak > 0;
+        self.streak = 0;
+        had_streak
+    }
+
+    // star power
+
+    pub fn can_activate_sp(self: &Self) -> bool {
+        !self.sp_active && self.sp_meter >= SP_ACTIVATION_METER
+    }
+
+    /// Activates star power at the given beat if the meter allows it, returning whether it did
+    pub fn activate_sp(self: &mut Self, beat: f32) -> bool {
+        if !self.can_activate_sp() {
+            return false;
+        }
+        self.sp_active = true;
+        self.sp_drained_to_beat = beat;
+        true
+    }
+
+    /// Drains active star power up to the given beat, returning whether it ran out.
+    /// After a rewind the meter stays put until the song is back where it was drained to.
+    pub fn drain_sp(self: &mut Self, beat: f32) -> bool {
+        if !self.sp_active {
+            return false;
+        }
+
+        let beat = f32::max(beat, self.sp_drained_to_beat);
+        self.sp_meter -= (beat - self.sp_drained_to_beat) / SP_METER_BEATS;
+        self.sp_drained_to_beat = beat;
+
+        if self.sp_meter > 0.0 {
+            return false;
+        }
+        self.sp_meter = 0.0;
+        self.sp_active = false;
+        true
+    }
+
+    // rock meter
+
+    /// What a hit gains, more while star power is active
+    pub fn rock_meter_hit_gain(self: &Self, settings: &RockMeterSettings) -> f32 {
+        if self.sp_active { settings.hit_gain * settings.sp_hit_multiplier } else { settings.hit_gain }
+    }
+
+    /// Moves the rock meter, returning whether it ran out and the song should fail
+    pub fn adjust_rock_meter(self: &mut Self, delta: f32, no_fail: bool) -> bool {
+        self.rock_meter = f32::max(f32::min(self.rock_meter + delta, 1.0), 0.0);
+        self.rock_meter <= 0.0 && !no_fail
+    }
+
+    /// Marks the song failed, returning false if it already was
+    pub fn fail(self: &mut Self) -> bool {
+        let was_failed = self.failed;
+        self.failed = true;
+        !was_failed
+    }
+}
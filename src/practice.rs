@@ -0,0 +1,164 @@
+use crate::guitarplaythrough::*;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 1.0;
+/// How long the song clock runs before the loop's first section, so its first notes can scroll in
+pub const LOOP_LEAD_IN_MS: f32 = 2000.0;
+
+/// How one pass through the loop went
+#[derive(Clone)]
+pub struct LoopStats {
+    pub notes_hit: u64,
+    pub notes_missed: u64,
+    pub notes_total: u64,
+}
+
+impl LoopStats {
+    /// Fraction of the loop's notes that were hit
+    pub fn accuracy(self: &Self) -> f32 {
+        if self.notes_total == 0 { 1.0 } else { self.notes_hit as f32 / self.notes_total as f32 }
+    }
+}
+
+/// Loops a range of a chart's sections, from the start of the first to the end of the last,
+/// at a reduced speed.
+///
+/// Speed is applied by the game loop, which runs the song clock at `speed` times real time.
+/// The engine judges in song time, so its hit windows shrink by the same factor to stay as wide in real time.
+pub struct Practice {
+    pub start_section: usize,
+    pub end_section: usize,
+    pub start_ms: f32,
+    pub end_ms: f32,
+    pub speed: f32,
+    /// Every completed pass through the loop, oldest first
+    pub loops: Vec<LoopStats>,
+    notes_total: u64,
+}
+
+impl Practice {
+    pub fn new(chart: &GuitarChart, start_section: usize, end_section: usize, speed: f32) -> Result<Practice, &'static str> {
+        if chart.sections.is_empty() {
+            return Err("the chart has no sections to practice");
+        }
+        if start_section >= chart.sections.len() || end_section >= chart.sections.len() {
+            return Err("no such section");
+        }
+        if end_section < start_section {
+            return Err("the end section is before the start section");
+        }
+        if speed < MIN_SPEED || speed > MAX_SPEED {
+            return Err("practice speed is 50% to 100%");
+        }
+
+        let start_ticks = chart.sections[start_section].ticks;
+        let end_ticks = chart.sections.get(end_section + 1).map(|section| section.ticks);
+
+        Ok(Practice {
+            start_section: start_section,
+            end_section: end_section,
+            start_ms: chart.ticks_to_ms(start_ticks),
+            end_ms: end_ticks.map_or_else(|| chart.end_ms(), |ticks| chart.ticks_to_ms(ticks)),
+            speed: speed,
+            loops: Vec::new(),
+            notes_total: chart.notes.iter()
+                .filter(|note| note.ticks >= start_ticks && end_ticks.map_or(true, |end_ticks| note.ticks < end_ticks))
+                .count() as u64,
+        })
+    }
+
+    /// The settings to practice with: hit windows and audio latency scaled to the speed, and no failing.
+    /// Both are in real time, which the slowed-down song clock goes through less of.
+    pub fn settings(self: &Self, settings: GuitarSettings) -> GuitarSettings {
+        GuitarSettings {
+            hit_window: HitWindow {
+                early_ms: settings.hit_window.early_ms * self.speed,
+                late_ms: settings.hit_window.late_ms * self.speed,
+            },
+            audio_latency_ms: settings.audio_latency_ms * self.speed,
+            no_fail: true,
+            ..settings
+        }
+    }
+
+    /// Seeks the playthrough to the start of the loop, returning the time the song clock should restart from.
+    /// The seek takes the last pass's judgements of the loop's notes back, so each pass is counted on its own.
+    pub fn start_loop(self: &mut Self, playthrough: &mut GuitarPlaythrough) -> f32 {
+        playthrough.seek(self.start_ms);
+        f32::max(self.start_ms - LOOP_LEAD_IN_MS, playthrough.start_time_ms())
+    }
+
+    /// Once the playthrough has judged the whole loop, records and returns how the pass went.
+    /// The game loop then starts the next pass.
+    pub fn update_time(self: &mut Self, playthrough: &GuitarPlaythrough, time_ms: f32) -> Option<LoopStats> {
+        if time_ms < self.end_ms + playthrough.settings.audio_latency_ms + playthrough.hit_window().late_ms {
+            return None;
+        }
+
+        // only the loop's own sections count: a note just past the loop can be hit early, before the loop is over
+        let sections = &playthrough.state.section_stats[self.start_section..=self.end_section];
+        let stats = LoopStats {
+            notes_hit: sections.iter().map(|section| section.notes_hit).sum(),
+            notes_missed: sections.iter().map(|section| section.notes_missed).sum(),
+            notes_total: self.notes_total,
+        };
+        self.loops.push(stats.clone());
+        Some(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart;
+
+    // 120 BPM at 192 ticks per beat: section B is the loop, from 2000 ms to 4000 ms
+    const CHART: &str = "[Song]\n{\n  Resolution = 192\n}\n[SyncTrack]\n{\n  0 = B 120000\n}\n[Events]\n{\n  0 = E \"section A\"\n  768 = E \"section B\"\n  1536 = E \"section C\"\n}\n[ExpertSingle]\n{\n  960 = N 0 0\n  1540 = N 1 0\n}\n";
+
+    fn playthrough() -> GuitarPlaythrough {
+        let difficulty = chart::Difficulty::Expert;
+        GuitarPlaythrough::new(chart::read(CHART).unwrap(), chart::Instrument::Guitar, difficulty, GuitarSettings::for_difficulty(&difficulty)).unwrap()
+    }
+
+    #[test]
+    fn note_past_the_loop_hit_early_does_not_count() {
+        let mut playthrough = playthrough();
+        let mut practice = Practice::new(&playthrough.chart, 1, 1, 1.0).unwrap();
+        playthrough.settings = practice.settings(playthrough.settings);
+
+        for _pass in 0..2 {
+            practice.start_loop(&mut playthrough);
+            // the loop's note is missed, and the note just after the loop is hit early
+            playthrough.update_time(3975.0);
+            playthrough.apply(&GuitarInputAction::FretDown(Fret::R), 3975.0);
+            playthrough.apply(&GuitarInputAction::Strum, 3975.0);
+            playthrough.apply(&GuitarInputAction::FretUp(Fret::R), 3975.0);
+            playthrough.update_time(4100.0);
+
+            let stats = practice.update_time(&playthrough, 4100.0).unwrap();
+            assert_eq!((stats.notes_hit, stats.notes_missed, stats.notes_total), (0, 1, 1));
+        }
+
+        // the seeks took every pass but the last back
+        assert_eq!((playthrough.state.notes_hit, playthrough.state.notes_missed), (1, 1));
+    }
+
+    #[test]
+    fn audio_latency_is_scaled_to_the_speed() {
+        let mut playthrough = playthrough();
+        playthrough.settings.audio_latency_ms = 100.0;
+        let mut practice = Practice::new(&playthrough.chart, 1, 1, 0.5).unwrap();
+        playthrough.settings = practice.settings(playthrough.settings);
+        practice.start_loop(&mut playthrough);
+
+        // at half speed, the note at 2500 ms is heard 100 ms later in real time, which is 50 ms of song time
+        playthrough.update_time(2550.0);
+        playthrough.apply(&GuitarInputAction::FretDown(Fret::G), 2550.0);
+        let effects = playthrough.apply(&GuitarInputAction::Strum, 2550.0);
+        let offsets: Vec<f32> = effects.iter().filter_map(|effect| match effect {
+            GuitarGameEffect::Hit { offset_ms, .. } => Some(*offset_ms),
+            _ => None,
+        }).collect();
+        assert_eq!(offsets, vec![0.0]);
+    }
+}
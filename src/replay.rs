@@ -0,0 +1,413 @@
+use crate::chart;
+use crate::guitarplaythrough::*;
+
+/// Bumped whenever the replay format or the engine's judgement changes
+/// in a way that would make older replays play back differently.
+pub const REPLAY_VERSION: u64 = 1;
+
+pub enum ReplayEvent {
+    /// The game loop called GuitarPlaythrough::update_time
+    Time { time_ms: f32 },
+    /// The game loop called GuitarPlaythrough::apply
+    Input { time_ms: f32, action: GuitarInputAction },
+    /// The game loop called GuitarPlaythrough::pause
+    Pause { time_ms: f32 },
+    /// The game loop called GuitarPlaythrough::resume, which rewinds the song clock
+    Resume { time_ms: f32 },
+}
+
+impl ReplayEvent {
+    pub fn time_ms(self: &Self) -> f32 {
+        match self {
+            ReplayEvent::Time { time_ms } => *time_ms,
+            ReplayEvent::Input { time_ms, .. } => *time_ms,
+            ReplayEvent::Pause { time_ms } => *time_ms,
+            ReplayEvent::Resume { time_ms } => *time_ms,
+        }
+    }
+
+    /// Feeds this event to the playthrough exactly as the game loop did when recording
+    pub fn play(self: &Self, playthrough: &mut GuitarPlaythrough) -> Vec<GuitarGameEffect> {
+        match self {
+            ReplayEvent::Time { time_ms } => playthrough.update_time(*time_ms),
+            ReplayEvent::Input { time_ms, action } => playthrough.apply(action, *time_ms),
+            ReplayEvent::Pause { .. } => {
+                playthrough.pause();
+                vec![]
+            },
+            ReplayEvent::Resume { .. } => {
+                playthrough.resume();
+                vec![]
+            },
+        }
+    }
+}
+
+pub struct Replay {
+    pub chart_hash: u64,
+    /// The part that was played. Replays without a part line played Expert Guitar.
+    pub instrument: chart::Instrument,
+    pub difficulty: chart::Difficulty,
+    pub settings: GuitarSettings,
+    pub events: Vec<ReplayEvent>,
+    /// The score the recording game loop ended with
+    pub claimed_score: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ReplayParseError {
+    MissingHeader,
+    UnsupportedVersion(u64),
+    MissingChartHash,
+    ParseIntError(std::num::ParseIntError),
+    ParseFloatError(std::num::ParseFloatError),
+    ParseBoolError(std::str::ParseBoolError),
+    UnknownPart(String),
+    UnknownSetting(String),
+    UnknownEvent(String),
+    MissingField,
+}
+
+impl std::convert::From<std::num::ParseIntError> for ReplayParseError {
+    fn from(err: std::num::ParseIntError) -> ReplayParseError {
+        ReplayParseError::ParseIntError(err)
+    }
+}
+
+impl std::convert::From<std::num::ParseFloatError> for ReplayParseError {
+    fn from(err: std::num::ParseFloatError) -> ReplayParseError {
+        ReplayParseError::ParseFloatError(err)
+    }
+}
+
+impl std::convert::From<std::str::ParseBoolError> for ReplayParseError {
+    fn from(err: std::str::ParseBoolError) -> ReplayParseError {
+        ReplayParseError::ParseBoolError(err)
+    }
+}
+
+/// Identifies a chart by its file contents (64-bit FNV-1a).
+pub fn chart_hash(contents: &str) -> u64 {
+    contents.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn fret_name(fret: &Fret) -> &'static str {
+    match fret {
+        Fret::G => "G",
+        Fret::R => "R",
+        Fret::Y => "Y",
+        Fret::B => "B",
+        Fret::O => "O",
+        Fret::W1 => "W1",
+        Fret::W2 => "W2",
+        Fret::W3 => "W3",
+        Fret::B1 => "B1",
+        Fret::B2 => "B2",
+        Fret::B3 => "B3",
+    }
+}
+
+fn parse_fret(name: &str) -> Result<Fret, ReplayParseError> {
+    match name {
+        "G" => Ok(Fret::G),
+        "R" => Ok(Fret::R),
+        "Y" => Ok(Fret::Y),
+        "B" => Ok(Fret::B),
+        "O" => Ok(Fret::O),
+        "W1" => Ok(Fret::W1),
+        "W2" => Ok(Fret::W2),
+        "W3" => Ok(Fret::W3),
+        "B1" => Ok(Fret::B1),
+        "B2" => Ok(Fret::B2),
+        "B3" => Ok(Fret::B3),
+        _ => Err(ReplayParseError::UnknownEvent(name.to_string())),
+    }
+}
+
+// f32 values are written with Display, which prints the shortest string that parses back
+// to the same f32, so a replay read back feeds the engine bit-identical times.
+fn settings_entries(settings: &GuitarSettings) -> Vec<(&'static str, String)> {
+    vec![
+        ("hit_window_early_ms", settings.hit_window.early_ms.to_string()),
+        ("hit_window_late_ms", settings.hit_window.late_ms.to_string()),
+        ("strum_leniency", settings.strum_leniency.to_string()),
+        ("double_strum_protection", settings.double_strum_protection.to_string()),
+        ("ghost_protection", settings.ghost_protection.to_string()),
+        ("pause_rewind_ms", settings.pause_rewind_ms.to_string()),
+        ("resume_countdown_ms", settings.resume_countdown_ms.to_string()),
+        ("lead_in_ms", settings.lead_in_ms.to_string()),
+        ("rock_meter_start", settings.rock_meter.start.to_string()),
+        ("rock_meter_hit_gain", settings.rock_meter.hit_gain.to_string()),
+        ("rock_meter_miss_loss", settings.rock_meter.miss_loss.to_string()),
+        ("rock_meter_overstrum_loss", settings.rock_meter.overstrum_loss.to_string()),
+        ("rock_meter_sp_hit_multiplier", settings.rock_meter.sp_hit_multiplier.to_string()),
+        ("rock_meter_sp_activation_gain", settings.rock_meter.sp_activation_gain.to_string()),
+        ("no_fail", settings.no_fail.to_string()),
+        ("modifier_notes", strum_type_name(settings.modifiers.strum_type).to_string()),
+        ("modifier_mirror", settings.modifiers.mirror.to_string()),
+        ("modifier_lefty", settings.modifiers.lefty.to_string()),
+        ("modifier_precision", settings.modifiers.precision.to_string()),
+        ("audio_latency_ms", settings.audio_latency_ms.to_string()),
+    ]
+}
+
+fn strum_type_name(strum_type: Option<GuitarNoteStrumType>) -> &'static str {
+    match strum_type {
+        None => "chart",
+        Some(GuitarNoteStrumType::Strum) => "strum",
+        Some(GuitarNoteStrumType::Hopo) => "hopo",
+        Some(GuitarNoteStrumType::Tap) => "tap",
+    }
+}
+
+fn parse_strum_type(name: &str) -> Result<Option<GuitarNoteStrumType>, ReplayParseError> {
+    match name {
+        "chart" => Ok(None),
+        "strum" => Ok(Some(GuitarNoteStrumType::Strum)),
+        "hopo" => Ok(Some(GuitarNoteStrumType::Hopo)),
+        "tap" => Ok(Some(GuitarNoteStrumType::Tap)),
+        _ => Err(ReplayParseError::UnknownSetting(name.to_string())),
+    }
+}
+
+fn apply_setting(settings: &mut GuitarSettings, name: &str, value: &str) -> Result<(), ReplayParseError> {
+    match name {
+        "hit_window_early_ms" => settings.hit_window.early_ms = value.parse()?,
+        "hit_window_late_ms" => settings.hit_window.late_ms = value.parse()?,
+        "strum_leniency" => settings.strum_leniency = value.parse()?,
+        "double_strum_protection" => settings.double_strum_protection = value.parse()?,
+        "ghost_protection" => settings.ghost_protection = value.parse()?,
+        "pause_rewind_ms" => settings.pause_rewind_ms = value.parse()?,
+        "resume_countdown_ms" => settings.resume_countdown_ms = value.parse()?,
+        "lead_in_ms" => settings.lead_in_ms = value.parse()?,
+        "rock_meter_start" => settings.rock_meter.start = value.parse()?,
+        "rock_meter_hit_gain" => settings.rock_meter.hit_gain = value.parse()?,
+        "rock_meter_miss_loss" => settings.rock_meter.miss_loss = value.parse()?,
+        "rock_meter_overstrum_loss" => settings.rock_meter.overstrum_loss = value.parse()?,
+        "rock_meter_sp_hit_multiplier" => settings.rock_meter.sp_hit_multiplier = value.parse()?,
+        "rock_meter_sp_activation_gain" => settings.rock_meter.sp_activation_gain = value.parse()?,
+        "no_fail" => settings.no_fail = value.parse()?,
+        "modifier_notes" => settings.modifiers.strum_type = parse_strum_type(value)?,
+        "modifier_mirror" => settings.modifiers.mirror = value.parse()?,
+        "modifier_lefty" => settings.modifiers.lefty = value.parse()?,
+        "modifier_precision" => settings.modifiers.precision = value.parse()?,
+        "audio_latency_ms" => settings.audio_latency_ms = value.parse()?,
+        _ => return Err(ReplayParseError::UnknownSetting(name.to_string())),
+    }
+    Ok(())
+}
+
+impl Replay {
+    pub fn new(chart_hash: u64, instrument: chart::Instrument, difficulty: chart::Difficulty, settings: GuitarSettings) -> Replay {
+        Replay {
+            chart_hash: chart_hash,
+            instrument: instrument,
+            difficulty: difficulty,
+            settings: settings,
+            events: Vec::new(),
+            claimed_score: None,
+        }
+    }
+
+    pub fn record_time(self: &mut Self, time_ms: f32) {
+        self.events.push(ReplayEvent::Time { time_ms: time_ms });
+    }
+
+    pub fn record_input(self: &mut Self, action: GuitarInputAction, time_ms: f32) {
+        self.events.push(ReplayEvent::Input { time_ms: time_ms, action: action });
+    }
+
+    pub fn record_pause(self: &mut Self, time_ms: f32) {
+        self.events.push(ReplayEvent::Pause { time_ms: time_ms });
+    }
+
+    pub fn record_resume(self: &mut Self, time_ms: f32) {
+        self.events.push(ReplayEvent::Resume { time_ms: time_ms });
+    }
+
+    /// Plays every event into a fresh playthrough, returning all effects in order.
+    pub fn play_all(self: &Self, playthrough: &mut GuitarPlaythrough) -> Vec<GuitarGameEffect> {
+        self.events.iter().flat_map(|event| event.play(playthrough)).collect()
+    }
+
+    pub fn write(self: &Self) -> String {
+        let mut lines = vec![
+            format!("bumpit-replay {}", REPLAY_VERSION),
+            format!("chart {:016x}", self.chart_hash),
+            format!("part {} {}", self.instrument.name(), self.difficulty.name()),
+        ];
+
+        lines.extend(settings_entries(&self.settings).into_iter()
+            .map(|(name, value)| format!("setting {} {}", name, value)));
+
+        lines.extend(self.events.iter().map(|event| match event {
+            ReplayEvent::Time { time_ms } => format!("t {}", time_ms),
+            ReplayEvent::Pause { time_ms } => format!("p {}", time_ms),
+            ReplayEvent::Resume { time_ms } => format!("r {}", time_ms),
+            ReplayEvent::Input { time_ms, action } => match action {
+                GuitarInputAction::FretDown(fret) => format!("i {} fret_down {}", time_ms, fret_name(fret)),
+                GuitarInputAction::FretUp(fret) => format!("i {} fret_up {}", time_ms, fret_name(fret)),
+                GuitarInputAction::Strum => format!("i {} strum", time_ms),
+                GuitarInputAction::StarPower => format!("i {} star_power", time_ms),
+                GuitarInputAction::Whammy(position) => format!("i {} whammy {}", time_ms, position),
+                GuitarInputAction::Tilt(tilt) => format!("i {} tilt {}", time_ms, tilt),
+            },
+        }));
+
+        if let Some(score) = self.claimed_score {
+            lines.push(format!("score {}", score));
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
+
+/// A parser for replays written by Replay::write.
+pub fn read(contents: &str) -> Result<Replay, ReplayParseError> {
+    let mut lines = contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty());
+
+    let version = match lines.next().map(|line| line.split(' ').collect::<Vec<&str>>()) {
+        Some(ref parts) if parts.len() == 2 && parts[0] == "bumpit-replay" => parts[1].parse::<u64>()?,
+        _ => return Err(ReplayParseError::MissingHeader),
+    };
+    if version != REPLAY_VERSION {
+        return Err(ReplayParseError::UnsupportedVersion(version));
+    }
+
+    let chart_hash = match lines.next().map(|line| line.split(' ').collect::<Vec<&str>>()) {
+        Some(ref parts) if parts.len() == 2 && parts[0] == "chart" => u64::from_str_radix(parts[1], 16)?,
+        _ => return Err(ReplayParseError::MissingChartHash),
+    };
+
+    let mut replay = Replay::new(chart_hash, chart::Instrument::Guitar, chart::Difficulty::Expert,
+        GuitarSettings::for_difficulty(&chart::Difficulty::Expert));
+
+    for line in lines {
+        let parts: Vec<&str> = line.split(' ').collect();
+        let field = |i: usize| parts.get(i).map(|s| *s).ok_or_else(|| ReplayParseError::MissingField);
+
+        match parts[0] {
+            "part" => {
+                let (instrument, difficulty) = (field(1)?, field(2)?);
+                replay.instrument = chart::Instrument::from_name(instrument)
+                    .ok_or_else(|| ReplayParseError::UnknownPart(instrument.to_string()))?;
+                replay.difficulty = chart::Difficulty::from_name(difficulty)
+                    .ok_or_else(|| ReplayParseError::UnknownPart(difficulty.to_string()))?;
+            },
+            "setting" => apply_setting(&mut replay.settings, field(1)?, field(2)?)?,
+            "t" => replay.record_time(field(1)?.parse()?),
+            "score" => replay.claimed_score = Some(field(1)?.parse()?),
+            "p" => replay.record_pause(field(1)?.parse()?),
+            "r" => replay.record_resume(field(1)?.parse()?),
+            "i" => {
+                let time_ms: f32 = field(1)?.parse()?;
+                let action = match field(2)? {
+                    "fret_down" => GuitarInputAction::FretDown(parse_fret(field(3)?)?),
+                    "fret_up" => GuitarInputAction::FretUp(parse_fret(field(3)?)?),
+                    "strum" => GuitarInputAction::Strum,
+                    "star_power" => GuitarInputAction::StarPower,
+                    "whammy" => GuitarInputAction::Whammy(field(3)?.parse()?),
+                    "tilt" => GuitarInputAction::Tilt(field(3)?.parse()?),
+                    other => return Err(ReplayParseError::UnknownEvent(other.to_string())),
+                };
+                replay.record_input(action, time_ms);
+            },
+            other => return Err(ReplayParseError::UnknownEvent(other.to_string())),
+        }
+    }
+
+    Ok(replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 120 BPM at 192 ticks per beat: a note every 500 ms from 500 ms, the second and third in a phrase
+    // and the sixth in another
+    const CHART: &str = "[Song]\n{\n  Resolution = 192\n}\n[SyncTrack]\n{\n  0 = B 120000\n}\n[ExpertSingle]\n{\n  192 = N 0 0\n  384 = N 1 96\n  384 = S 2 200\n  576 = N 2 0\n  768 = N 3 0\n  960 = N 4 0\n  1152 = N 0 0\n  1152 = S 2 10\n}\n";
+
+    fn playthrough(settings: GuitarSettings) -> GuitarPlaythrough {
+        GuitarPlaythrough::new(chart::read(CHART).unwrap(), chart::Instrument::Guitar, chart::Difficulty::Expert, settings).unwrap()
+    }
+
+    /// Plays the chart like a game loop with a 7 ms frame, recording everything it feeds the engine
+    fn record(playthrough: &mut GuitarPlaythrough, replay: &mut Replay) -> Vec<GuitarGameEffect> {
+        let frets = [Fret::G, Fret::R, Fret::Y, Fret::B, Fret::O, Fret::G];
+        let mut effects = Vec::new();
+        let mut time_ms = playthrough.start_time_ms();
+        let mut paused = false;
+
+        while time_ms < 5000.0 {
+            time_ms += 7.0;
+            replay.record_time(time_ms);
+            effects.extend(playthrough.update_time(time_ms));
+
+            let mut inputs = Vec::new();
+            for (index, fret) in frets.iter().enumerate() {
+                // the fourth note is missed, and the fifth is strummed late
+                let note_ms = (index as f32 + 1.0) * 500.0 + if index == 4 { 30.0 } else { 3.0 };
+                if index != 3 && time_ms >= note_ms && time_ms < note_ms + 7.0 {
+                    inputs.extend(vec![GuitarInputAction::FretDown(*fret), GuitarInputAction::Strum]);
+                }
+                if time_ms >= note_ms + 100.0 && time_ms < note_ms + 107.0 {
+                    inputs.push(GuitarInputAction::FretUp(*fret));
+                }
+            }
+            if time_ms >= 1100.0 && time_ms < 1107.0 {
+                inputs.push(GuitarInputAction::Whammy(0.5));
+            }
+            if time_ms >= 3300.0 && time_ms < 3307.0 {
+                inputs.extend(vec![GuitarInputAction::Strum, GuitarInputAction::StarPower]);
+            }
+            // after the pause the song is rewound, and the player doesn't play it again
+            if paused {
+                inputs.clear();
+            }
+            for action in inputs {
+                replay.record_input(action, time_ms);
+                effects.extend(playthrough.apply(&action, time_ms));
+            }
+
+            if !paused && time_ms >= 3500.0 {
+                paused = true;
+                replay.record_pause(time_ms);
+                playthrough.pause();
+                replay.record_resume(time_ms);
+                time_ms = playthrough.resume();
+            }
+        }
+
+        effects
+    }
+
+    #[test]
+    fn replay_read_back_plays_the_same_game() {
+        let settings = GuitarSettings::for_difficulty(&chart::Difficulty::Expert);
+        let mut recorded = playthrough(settings);
+        let mut replay = Replay::new(chart_hash(CHART), chart::Instrument::Guitar, chart::Difficulty::Expert, settings);
+        let recorded_effects = record(&mut recorded, &mut replay);
+        replay.claimed_score = Some(recorded.state.score);
+
+        let read_back = read(&replay.write()).unwrap();
+        assert_eq!(read_back.chart_hash, replay.chart_hash);
+        assert_eq!(read_back.claimed_score, Some(recorded.state.score));
+        assert_eq!(read_back.events.len(), replay.events.len());
+
+        let mut replayed = playthrough(read_back.settings);
+        let replayed_effects = read_back.play_all(&mut replayed);
+
+        assert!(recorded_effects.contains(&GuitarGameEffect::StarPowerActivated));
+        assert!(recorded_effects.contains(&GuitarGameEffect::Overstrum));
+        assert_eq!(replayed_effects, recorded_effects);
+        assert_eq!(replayed.results(), recorded.results());
+    }
+
+    #[test]
+    fn unknown_lines_are_errors() {
+        assert!(match read("bumpit-replay 1\n") { Err(ReplayParseError::MissingChartHash) => true, _ => false });
+        assert!(match read("bumpit-replay 0\nchart 0\n") { Err(ReplayParseError::UnsupportedVersion(0)) => true, _ => false });
+        assert!(match read("bumpit-replay 1\nchart 0\nx 1\n") { Err(ReplayParseError::UnknownEvent(_)) => true, _ => false });
+    }
+}